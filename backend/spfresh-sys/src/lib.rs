@@ -5,6 +5,8 @@ pub type SPFreshIndex = c_void;
 
 extern "C" {
     pub fn spfresh_index_create(path: *const c_char) -> *mut SPFreshIndex;
+
+    pub fn spfresh_index_create_with_dim(path: *const c_char, dim: usize) -> *mut SPFreshIndex;
     
     pub fn spfresh_index_append(index: *mut SPFreshIndex, vector: *const f32, dim: usize) -> i32;
     
@@ -18,6 +20,7 @@ extern "C" {
     ) -> i32;
     
     pub fn spfresh_index_size(index: *mut SPFreshIndex) -> usize;
+    pub fn spfresh_index_flush(index: *mut SPFreshIndex) -> i32;
     pub fn spfresh_index_destroy(index: *mut SPFreshIndex);
 }
 
@@ -31,11 +34,16 @@ unsafe impl Sync for Index {}
 
 impl Index {
     pub fn new<P: AsRef<str>>(path: P) -> Result<Self, String> {
+        Self::with_dim(path, 128)
+    }
+
+    /// Opens or creates an index holding `dim`-dimensional vectors.
+    pub fn with_dim<P: AsRef<str>>(path: P, dim: usize) -> Result<Self, String> {
         let path_str = path.as_ref();
         let c_path = CString::new(path_str)
             .map_err(|e| format!("Invalid path: {}", e))?;
         
-        let ptr = unsafe { spfresh_index_create(c_path.as_ptr()) };
+        let ptr = unsafe { spfresh_index_create_with_dim(c_path.as_ptr(), dim) };
         if ptr.is_null() {
             return Err("Failed to create index".to_string());
         }
@@ -55,6 +63,16 @@ impl Index {
         }
     }
     
+    /// Routes buffered appends into their postings and persists the centroids.
+    pub fn flush(&mut self) -> Result<(), String> {
+        let result = unsafe { spfresh_index_flush(self.ptr) };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(format!("Failed to flush index: error code {}", result))
+        }
+    }
+
     pub fn len(&self) -> usize {
         unsafe { spfresh_index_size(self.ptr) }
     }
@@ -80,8 +98,10 @@ impl Index {
             )
         };
         
-        if result == 0 {
-            Ok(result_indices.into_iter().zip(result_scores.into_iter()).collect())
+        if result >= 0 {
+            // The index reports how many of the `k` slots it filled.
+            let found = std::cmp::min(result as usize, k);
+            Ok(result_indices.into_iter().zip(result_scores).take(found).collect())
         } else if result == -1 {
             Ok(Vec::new())
         } else {
@@ -104,7 +124,7 @@ mod tests {
     
     #[test]
     fn test_index_lifecycle() {
-        let mut index = Index::new("test.index").expect("Failed to create index");
+        let mut index = Index::with_dim("test.index", 768).expect("Failed to create index");
         
         let vector = vec![1.0f32; 768];
         index.append(&vector).expect("Failed to append vector");
@@ -16,10 +16,10 @@ unsafe impl Sync for Index {}
 
 #[cfg(feature = "spfresh")]
 impl Index {
-    pub fn open_or_create<P: AsRef<Path>>(path: P) -> Result<Self> {
+    pub fn open_or_create<P: AsRef<Path>>(path: P, dim: usize) -> Result<Self> {
         let path_str = path.as_ref().to_string_lossy();
         println!("Creating SPFresh index with path: {}", path_str);
-        let inner = sys::Index::new(&path_str)
+        let inner = sys::Index::with_dim(&path_str, dim)
             .map_err(|e| anyhow::anyhow!("Failed to create SPFresh index: {}", e))?;
         println!("SPFresh index created successfully");
         
@@ -42,90 +42,185 @@ impl Index {
         self.inner.search(query, top_k)
             .map_err(|e| anyhow::anyhow!("Search failed: {}", e))
     }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+            .map_err(|e| anyhow::anyhow!("Failed to flush SPFresh index: {}", e))
+    }
 }
 
 #[cfg(not(feature = "spfresh"))]
 mod fallback {
     use anyhow::Result;
+    use memmap2::Mmap;
     use ordered_float::NotNan;
     use std::fs::{File, OpenOptions};
-    use std::io::{Seek, SeekFrom, Write};
+    use std::io::Write;
     use std::path::PathBuf;
     use bytemuck;
     use std::collections::BinaryHeap;
     use std::cmp::Reverse;
 
+    /// Same self-describing header the backend writes (see
+    /// `backend::storage::header`): magic, version, header length, dim,
+    /// element type, metric, quantization scale/offset and model id.
+    const MAGIC: &[u8; 4] = b"RVEC";
+    const VERSION: u16 = 1;
+    const HEADER_LEN: usize = 64;
+    const ELEMENT_F32: u8 = 0;
+    const METRIC_DOT: u8 = 1;
+
+    fn encode_header(dim: usize) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&(HEADER_LEN as u16).to_le_bytes());
+        out.extend_from_slice(&(dim as u32).to_le_bytes());
+        out.push(ELEMENT_F32);
+        out.push(METRIC_DOT);
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(&1.0f32.to_le_bytes());
+        out.extend_from_slice(&0.0f32.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.resize(HEADER_LEN, 0);
+        out
+    }
+
+    /// Validates the header of `path`, writing one to a new file and adding
+    /// one to a legacy headerless file. Returns the offset of the vectors.
+    fn prepare_header(path: &std::path::Path, dim: usize) -> Result<usize> {
+        let data = if path.exists() { std::fs::read(path)? } else { Vec::new() };
+        if data.len() >= 26 && &data[..4] == MAGIC {
+            let version = u16::from_le_bytes([data[4], data[5]]);
+            let len = u16::from_le_bytes([data[6], data[7]]) as usize;
+            let file_dim = u32::from_le_bytes(data[8..12].try_into().expect("4 bytes")) as usize;
+            if version > VERSION {
+                anyhow::bail!("{:?} uses vector file format version {}, this build reads up to {}", path, version, VERSION);
+            }
+            if file_dim != dim {
+                anyhow::bail!("{:?} holds {}-d vectors, but this index expects {}", path, file_dim, dim);
+            }
+            if data[12] != ELEMENT_F32 || data[13] != METRIC_DOT {
+                anyhow::bail!("{:?} does not hold raw f32 vectors scored by dot product", path);
+            }
+            return Ok(len);
+        }
+        let row = dim * std::mem::size_of::<f32>();
+        let mut rewritten = encode_header(dim);
+        rewritten.extend_from_slice(&data[..data.len() - data.len() % row]);
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, &rewritten)
+            .map_err(|e| anyhow::anyhow!("Failed to write vector store header: {}", e))?;
+        File::open(&tmp)?.sync_all()?;
+        std::fs::rename(&tmp, path)
+            .map_err(|e| anyhow::anyhow!("Failed to replace vector store file: {}", e))?;
+        Ok(HEADER_LEN)
+    }
+
     #[derive(Debug)]
     pub struct Index {
         path: PathBuf,
         dim: usize,
+        data_offset: usize,
+        file: File,
+        map: Option<Mmap>,
     }
 
     impl Index {
-        pub fn open_or_create<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        pub fn open_or_create<P: AsRef<std::path::Path>>(path: P, dim: usize) -> Result<Self> {
             let p = path.as_ref();
-            if !p.exists() {
-                File::create(p)?;
-            }
-            Ok(Self {
+            let data_offset = prepare_header(p, dim)?;
+            let file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(p)
+                .map_err(|e| anyhow::anyhow!("Failed to open vector store file: {}", e))?;
+            let mut index = Self {
                 path: p.to_path_buf(),
-                dim: 128,
-            })
+                dim,
+                data_offset,
+                file,
+                map: None,
+            };
+            index.remap()?;
+            Ok(index)
+        }
+
+        fn remap(&mut self) -> Result<()> {
+            let size = self.file.metadata()?.len();
+            if size == 0 {
+                self.map = None;
+                return Ok(());
+            }
+            // SAFETY: the file is append-only and only written through this index.
+            let map = unsafe { Mmap::map(&self.file) }
+                .map_err(|e| anyhow::anyhow!("Failed to map vector store file {:?}: {}", self.path, e))?;
+            self.map = Some(map);
+            Ok(())
+        }
+
+        /// The mapped vectors. Mappings are page aligned and the header is a
+        /// multiple of 64 bytes, so the cast to `f32` is valid.
+        fn vectors(&self) -> &[f32] {
+            match &self.map {
+                Some(map) if map.len() > self.data_offset => {
+                    let data = &map[self.data_offset..];
+                    let row = self.dim * std::mem::size_of::<f32>();
+                    bytemuck::cast_slice(&data[..data.len() - data.len() % row])
+                }
+                _ => &[],
+            }
         }
 
         pub fn append(&mut self, vector: &[f32]) -> Result<()> {
-            assert_eq!(vector.len(), self.dim, "vector dim mismatch");
-            let mut file = OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(&self.path)
-                .map_err(|e| anyhow::anyhow!("Failed to open vector store file: {}", e))?;
-            
+            if vector.len() != self.dim {
+                anyhow::bail!("vector has {} dimensions, but this index stores {}-d vectors", vector.len(), self.dim);
+            }
             let bytes = bytemuck::cast_slice(vector);
-            file.write_all(bytes)
+            self.file.write_all(bytes)
                 .map_err(|e| anyhow::anyhow!("Failed to write vector to file: {}", e))?;
-            
-            file.sync_all()
+
+            self.file.sync_data()
                 .map_err(|e| anyhow::anyhow!("Failed to sync vector store to disk: {}", e))?;
-                
-            Ok(())
+
+            self.remap()
         }
 
         pub fn len(&self) -> Result<usize> {
-            let mut file = File::open(&self.path)
-                .map_err(|e| anyhow::anyhow!("Failed to open vector store file: {}", e))?;
-            let size = file.seek(SeekFrom::End(0))?
-                as usize;
-            Ok(size / (self.dim * std::mem::size_of::<f32>()))
+            Ok(self.vectors().len() / self.dim)
+        }
+
+        pub fn flush(&mut self) -> Result<()> {
+            Ok(())
         }
 
         pub fn search(&self, query: &[f32], top_k: usize) -> Result<Vec<(usize, f32)>> {
             assert!(top_k > 0, "top_k must be > 0");
-            assert_eq!(query.len(), self.dim, "query dim mismatch");
-            
-            let data = std::fs::read(&self.path)
-                .map_err(|e| anyhow::anyhow!("Failed to read vector store file: {}", e))?;
-                
-            if data.is_empty() {
+            if query.len() != self.dim {
+                anyhow::bail!("query has {} dimensions, but this index stores {}-d vectors", query.len(), self.dim);
+            }
+
+            let total_f32 = self.vectors();
+            if total_f32.is_empty() {
                 return Ok(Vec::new());
             }
-            
-            let total_f32: &[f32] = bytemuck::cast_slice(&data);
+
             let num_vecs = total_f32.len() / self.dim;
-            
+
             let mut heap: BinaryHeap<Reverse<(NotNan<f32>, usize)>> =
                 BinaryHeap::with_capacity(top_k + 1);
-                
+
             for i in 0..num_vecs {
                 let start = i * self.dim;
                 let end = start + self.dim;
                 let vec_slice = &total_f32[start..end];
-                
+
                 let mut score = 0.0f32;
                 for (a, b) in query.iter().zip(vec_slice.iter()) {
                     score += a * b;
                 }
-                
+
                 if let Ok(not_nan) = NotNan::new(score) {
                     heap.push(Reverse((not_nan, i)));
                     if heap.len() > top_k {
@@ -133,12 +228,12 @@ mod fallback {
                     }
                 }
             }
-            
+
             let mut results: Vec<(usize, f32)> = heap.into_iter()
                 .map(|Reverse((s, i))| (i, s.into_inner()))
                 .collect();
             results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
-            
+
             Ok(results)
         }
     }
@@ -6,88 +6,135 @@
 #include <cmath>
 #include <algorithm>
 #include <cstring>
+#include <cstdint>
+#include <cstdlib>
+#include <cstdio>
+#include <deque>
+#include <queue>
 #include <unordered_map>
+#include <unordered_set>
 #include <mutex>
-#include <random>
+#include <stdexcept>
+#include <dirent.h>
+#include <sys/stat.h>
+#include <unistd.h>
+
+// SPANN/SPFresh-style partitioned index.
+//
+// Vectors are grouped into posting lists around centroids. Posting lists live
+// on disk (one file per posting under `<path>.postings/`), only the centroids
+// and a small neighbour graph over them are kept in memory. Appends are
+// buffered in memory, then routed to their nearest posting(s); postings that
+// grow past the limit are split with 2-means and the vectors around the split
+// are reassigned (LIRE), while postings that shrink too far are merged into
+// their neighbours.
+//
+// `<path>.vectors` / `<path>.metadata` remain the append-only log of every
+// quantized vector and its norm, so postings can always be rebuilt from it.
+// The vector log may start with the backend's `RVEC` format header, which is
+// written and validated on the Rust side; here it is only skipped.
 
 const float MIN_VAL = -1.0f;
 const float MAX_VAL = 1.0f;
 const uint8_t QUANTIZATION_LEVELS = 255;
 
+const char VECTOR_FILE_MAGIC[4] = {'R', 'V', 'E', 'C'};
+const uint32_t CENTROIDS_MAGIC = 0x53504652; // "SPFR"
+const uint32_t CENTROIDS_VERSION = 1;
+
+static size_t env_size(const char* name, size_t fallback) {
+    const char* v = std::getenv(name);
+    if (!v) return fallback;
+    char* end = nullptr;
+    unsigned long long parsed = std::strtoull(v, &end, 10);
+    if (end == v || parsed == 0) return fallback;
+    return static_cast<size_t>(parsed);
+}
+
+struct Posting {
+    std::vector<float> centroid; // unit length
+    uint32_t size = 0;
+    bool alive = true;
+};
+
+struct Record {
+    uint32_t id;
+    float norm;
+    std::vector<uint8_t> data;
+};
+
 struct SPFreshIndex {
     std::string path;
-    std::vector<std::vector<uint8_t>> quantized_vectors;
-    std::vector<float> norms;
     size_t dimension;
     std::mutex mtx;
-    
+
+    // Tunables, overridable through the environment.
+    size_t posting_limit;   // split above this many records
+    size_t posting_min;     // merge below this many records
+    size_t nprobe;          // postings scanned per query
+    size_t replicas;        // postings a new vector may be written to
+    size_t graph_degree;    // neighbours per centroid in the routing graph
+    size_t buffer_limit;    // appends buffered before they are routed
+
+    std::vector<Posting> postings;
+    std::vector<std::vector<uint32_t>> graph;
+    size_t persisted_count = 0;
+    size_t data_offset = 0;     // bytes of format header before the first vector
+
     std::vector<std::vector<uint8_t>> write_buffer_vectors;
     std::vector<float> write_buffer_norms;
-    static const size_t WRITE_BUFFER_SIZE = 1000;
-    
-    SPFreshIndex(const std::string& p) : path(p), dimension(128) {
+
+    SPFreshIndex(const std::string& p, size_t dim) : path(p), dimension(dim) {
         if (path.size() > 6 && path.substr(path.size() - 6) == ".index") {
             path = path.substr(0, path.size() - 6);
         }
-        load_index();
+        posting_limit = env_size("SPFRESH_POSTING_LIMIT", 256);
+        posting_min = std::max<size_t>(1, posting_limit / 8);
+        nprobe = env_size("SPFRESH_NPROBE", 32);
+        replicas = env_size("SPFRESH_REPLICAS", 2);
+        graph_degree = env_size("SPFRESH_GRAPH_DEGREE", 16);
+        buffer_limit = env_size("SPFRESH_BUFFER", 1000);
+        if (!load_index()) {
+            throw std::runtime_error("unreadable vector log: " + vec_path());
+        }
     }
-    
+
     ~SPFreshIndex() {
+        std::lock_guard<std::mutex> lock(mtx);
         flush_write_buffer();
-        save_index();
     }
-    
-    void flush_write_buffer() {
-        if (write_buffer_vectors.empty()) return;
-        
-        std::string vec_path = path + ".vectors";
-        std::string meta_path = path + ".metadata";
-        
-        {
-            std::ofstream vec_file(vec_path, std::ios::binary | std::ios::app);
-            if (!vec_file) {
-                std::cerr << "Failed to open vector file for writing: " << vec_path << std::endl;
-                return;
-            }
-            for (const auto& qv : write_buffer_vectors) {
-                vec_file.write(reinterpret_cast<const char*>(qv.data()), dimension);
-            }
-        }
-        
-        {
-            std::ofstream meta_file(meta_path, std::ios::binary | std::ios::app);
-            if (!meta_file) {
-                std::cerr << "Failed to open metadata file for writing: " << meta_path << std::endl;
-                return;
-            }
-            meta_file.write(reinterpret_cast<const char*>(write_buffer_norms.data()),
-                           write_buffer_norms.size() * sizeof(float));
-        }
-        
-        write_buffer_vectors.clear();
-        write_buffer_norms.clear();
+
+    std::string vec_path() const { return path + ".vectors"; }
+    std::string meta_path() const { return path + ".metadata"; }
+    std::string centroids_path() const { return path + ".centroids"; }
+    std::string postings_dir() const { return path + ".postings"; }
+    std::string posting_path(uint32_t pid) const {
+        return postings_dir() + "/" + std::to_string(pid) + ".bin";
     }
-    
+    size_t record_size() const { return sizeof(uint32_t) + sizeof(float) + dimension; }
+
+    // ---------------------------------------------------------------- quantization
+
     uint8_t quantize(float val) {
         float clamped = std::max(MIN_VAL, std::min(MAX_VAL, val));
         float normalized = (clamped - MIN_VAL) / (MAX_VAL - MIN_VAL);
         return static_cast<uint8_t>(normalized * QUANTIZATION_LEVELS);
     }
-    
+
     float dequantize(uint8_t val) {
         float normalized = static_cast<float>(val) / QUANTIZATION_LEVELS;
         return normalized * (MAX_VAL - MIN_VAL) + MIN_VAL;
     }
-    
-    std::vector<uint8_t> quantize_vector(const std::vector<float>& vec) {
+
+    std::vector<uint8_t> quantize_vector(const float* vec) {
         std::vector<uint8_t> quantized;
-        quantized.reserve(vec.size());
-        for (float val : vec) {
-            quantized.push_back(quantize(val));
+        quantized.reserve(dimension);
+        for (size_t i = 0; i < dimension; i++) {
+            quantized.push_back(quantize(vec[i]));
         }
         return quantized;
     }
-    
+
     std::vector<float> dequantize_vector(const std::vector<uint8_t>& quantized) {
         std::vector<float> vec;
         vec.reserve(quantized.size());
@@ -96,88 +143,653 @@ struct SPFreshIndex {
         }
         return vec;
     }
-    
-    float calculate_norm(const std::vector<float>& vec) {
+
+    static float calculate_norm(const float* vec, size_t dim) {
         float sum_sq = 0.0f;
-        for (float val : vec) {
-            sum_sq += val * val;
+        for (size_t i = 0; i < dim; i++) {
+            sum_sq += vec[i] * vec[i];
         }
-        float norm = std::sqrt(sum_sq);
-        std::cout << "Vector norm: " << norm << " (sum_sq: " << sum_sq << ")" << std::endl;
-        return norm;
+        return std::sqrt(sum_sq);
     }
-    
-    bool load_index() {
-        std::lock_guard<std::mutex> lock(mtx);
-        quantized_vectors.clear();
-        norms.clear();
 
-        std::string vec_path = path + ".vectors";
-        std::string meta_path = path + ".metadata";
+    static float dot(const float* a, const float* b, size_t dim) {
+        float s = 0.0f;
+        for (size_t i = 0; i < dim; i++) s += a[i] * b[i];
+        return s;
+    }
 
-        std::ifstream vec_file(vec_path, std::ios::binary);
-        if (!vec_file) {
-            return true;
+    static void normalize(std::vector<float>& v) {
+        float n = calculate_norm(v.data(), v.size());
+        if (n > 0) {
+            for (float& x : v) x /= n;
         }
+    }
 
-        vec_file.seekg(0, std::ios::end);
-        std::streampos vec_size = vec_file.tellg();
-        if (vec_size <= 0 || (vec_size % static_cast<std::streampos>(dimension)) != 0) {
-            return false;
+    // Cosine similarity between a float query and a quantized record.
+    // dequantize(v) = v * 2/255 - 1, so the dot product splits into an
+    // integer-weighted sum minus the query's element sum.
+    float score(const float* query, float query_norm, float query_sum,
+                const uint8_t* data, float doc_norm) const {
+        float acc = 0.0f;
+        for (size_t j = 0; j < dimension; j++) {
+            acc += query[j] * static_cast<float>(data[j]);
+        }
+        float dot_product = acc * ((MAX_VAL - MIN_VAL) / QUANTIZATION_LEVELS) + MIN_VAL * query_sum;
+        if (query_norm > 0 && doc_norm > 0) {
+            return dot_product / (query_norm * doc_norm);
         }
-        size_t num_vectors = static_cast<size_t>(vec_size) / dimension;
-        vec_file.seekg(0, std::ios::beg);
+        return 0.0f;
+    }
+
+    // ---------------------------------------------------------------- postings on disk
 
-        quantized_vectors.resize(num_vectors, std::vector<uint8_t>(dimension));
-        for (size_t i = 0; i < num_vectors; i++) {
-            vec_file.read(reinterpret_cast<char*>(quantized_vectors[i].data()), dimension);
+    bool read_posting(uint32_t pid, std::vector<Record>& out) {
+        out.clear();
+        std::ifstream f(posting_path(pid), std::ios::binary);
+        if (!f) return false;
+        f.seekg(0, std::ios::end);
+        size_t bytes = static_cast<size_t>(f.tellg());
+        f.seekg(0, std::ios::beg);
+        size_t count = bytes / record_size();
+        std::vector<char> buf(count * record_size());
+        f.read(buf.data(), buf.size());
+        out.reserve(count);
+        for (size_t i = 0; i < count; i++) {
+            const char* p = buf.data() + i * record_size();
+            Record r;
+            std::memcpy(&r.id, p, sizeof(uint32_t));
+            std::memcpy(&r.norm, p + sizeof(uint32_t), sizeof(float));
+            const uint8_t* d = reinterpret_cast<const uint8_t*>(p + sizeof(uint32_t) + sizeof(float));
+            r.data.assign(d, d + dimension);
+            out.push_back(std::move(r));
         }
+        return true;
+    }
+
+    static void write_records(std::ofstream& f, const std::vector<Record>& records) {
+        for (const auto& r : records) {
+            f.write(reinterpret_cast<const char*>(&r.id), sizeof(uint32_t));
+            f.write(reinterpret_cast<const char*>(&r.norm), sizeof(float));
+            f.write(reinterpret_cast<const char*>(r.data.data()), r.data.size());
+        }
+    }
 
-        std::ifstream meta_file(meta_path, std::ios::binary);
-        if (meta_file) {
-            norms.resize(num_vectors);
-            meta_file.read(reinterpret_cast<char*>(norms.data()), num_vectors * sizeof(float));
-            if (meta_file.gcount() != static_cast<std::streamsize>(num_vectors * sizeof(float))) {
-                norms.clear();
+    bool write_posting(uint32_t pid, const std::vector<Record>& records) {
+        std::string tmp = posting_path(pid) + ".tmp";
+        {
+            std::ofstream f(tmp, std::ios::binary | std::ios::trunc);
+            if (!f) return false;
+            write_records(f, records);
+        }
+        if (std::rename(tmp.c_str(), posting_path(pid).c_str()) != 0) return false;
+        postings[pid].size = static_cast<uint32_t>(records.size());
+        return true;
+    }
+
+    bool append_posting(uint32_t pid, const std::vector<Record>& records) {
+        std::ofstream f(posting_path(pid), std::ios::binary | std::ios::app);
+        if (!f) return false;
+        write_records(f, records);
+        postings[pid].size += static_cast<uint32_t>(records.size());
+        return true;
+    }
+
+    // ---------------------------------------------------------------- routing
+
+    std::vector<float> record_vector(const Record& r) {
+        std::vector<float> v = dequantize_vector(r.data);
+        normalize(v);
+        return v;
+    }
+
+    std::vector<uint32_t> alive_postings() const {
+        std::vector<uint32_t> out;
+        for (uint32_t i = 0; i < postings.size(); i++) {
+            if (postings[i].alive) out.push_back(i);
+        }
+        return out;
+    }
+
+    // Returns up to `count` postings closest to the unit vector `q`, best first.
+    // Small indexes are scanned exhaustively; larger ones walk the centroid graph.
+    std::vector<std::pair<float, uint32_t>> route(const float* q, size_t count) {
+        std::vector<std::pair<float, uint32_t>> found;
+        std::vector<uint32_t> alive = alive_postings();
+        if (alive.empty()) return found;
+        if (alive.size() <= std::max<size_t>(64, count * 2)) {
+            for (uint32_t pid : alive) {
+                found.emplace_back(dot(q, postings[pid].centroid.data(), dimension), pid);
+            }
+        } else {
+            size_t ef = std::max<size_t>(count * 2, 48);
+            std::unordered_set<uint32_t> visited;
+            std::priority_queue<std::pair<float, uint32_t>> candidates;
+            std::priority_queue<std::pair<float, uint32_t>,
+                                std::vector<std::pair<float, uint32_t>>,
+                                std::greater<std::pair<float, uint32_t>>> best;
+            size_t stride = std::max<size_t>(1, alive.size() / 8);
+            for (size_t i = 0; i < alive.size(); i += stride) {
+                uint32_t pid = alive[i];
+                float s = dot(q, postings[pid].centroid.data(), dimension);
+                visited.insert(pid);
+                candidates.emplace(s, pid);
+                best.emplace(s, pid);
+            }
+            while (best.size() > ef) best.pop();
+            while (!candidates.empty()) {
+                auto cur = candidates.top();
+                candidates.pop();
+                if (best.size() >= ef && cur.first < best.top().first) break;
+                for (uint32_t nb : graph[cur.second]) {
+                    if (!postings[nb].alive || !visited.insert(nb).second) continue;
+                    float s = dot(q, postings[nb].centroid.data(), dimension);
+                    if (best.size() < ef || s > best.top().first) {
+                        candidates.emplace(s, nb);
+                        best.emplace(s, nb);
+                        if (best.size() > ef) best.pop();
+                    }
+                }
+            }
+            while (!best.empty()) {
+                found.push_back(best.top());
+                best.pop();
             }
         }
+        std::sort(found.begin(), found.end(), std::greater<std::pair<float, uint32_t>>());
+        if (found.size() > count) found.resize(count);
+        return found;
+    }
 
-        if (norms.size() != num_vectors) {
-            norms.resize(num_vectors);
-            for (size_t i = 0; i < num_vectors; i++) {
-                std::vector<float> deq = dequantize_vector(quantized_vectors[i]);
-                norms[i] = calculate_norm(deq);
+    void link_posting(uint32_t pid) {
+        if (graph.size() < postings.size()) graph.resize(postings.size());
+        std::vector<std::pair<float, uint32_t>> scored;
+        for (uint32_t other : alive_postings()) {
+            if (other == pid) continue;
+            scored.emplace_back(dot(postings[pid].centroid.data(), postings[other].centroid.data(), dimension), other);
+        }
+        size_t keep = std::min(graph_degree, scored.size());
+        std::partial_sort(scored.begin(), scored.begin() + keep, scored.end(),
+                          std::greater<std::pair<float, uint32_t>>());
+        graph[pid].clear();
+        for (size_t i = 0; i < keep; i++) {
+            uint32_t nb = scored[i].second;
+            graph[pid].push_back(nb);
+            auto& back = graph[nb];
+            if (std::find(back.begin(), back.end(), pid) != back.end()) continue;
+            back.push_back(pid);
+            if (back.size() > graph_degree) {
+                // Drop dead links first, then the least similar one.
+                back.erase(std::remove_if(back.begin(), back.end(),
+                                          [&](uint32_t x) { return !postings[x].alive; }),
+                           back.end());
+                if (back.size() > graph_degree) {
+                    auto worst = std::min_element(back.begin(), back.end(), [&](uint32_t a, uint32_t b) {
+                        return dot(postings[nb].centroid.data(), postings[a].centroid.data(), dimension) <
+                               dot(postings[nb].centroid.data(), postings[b].centroid.data(), dimension);
+                    });
+                    back.erase(worst);
+                }
             }
-            save_index();
         }
-        return true;
     }
-    
-    bool save_index() {
-        std::lock_guard<std::mutex> lock(mtx);
-        std::string vec_path = path + ".vectors";
-        std::string meta_path = path + ".metadata";
+
+    uint32_t new_posting(const std::vector<float>& centroid) {
+        Posting p;
+        p.centroid = centroid;
+        p.size = 0;
+        p.alive = true;
+        postings.push_back(std::move(p));
+        graph.emplace_back();
+        uint32_t pid = static_cast<uint32_t>(postings.size() - 1);
+        // Start empty, whatever an earlier layout left under this id.
+        std::ofstream(posting_path(pid), std::ios::binary | std::ios::trunc);
+        return pid;
+    }
+
+    // ---------------------------------------------------------------- rebalancing
+
+    // Spherical 2-means over the records of one posting. Falls back to an
+    // index split when the records cannot be separated (e.g. duplicates), so
+    // every split strictly shrinks both halves.
+    void two_means(const std::vector<Record>& records, std::vector<float>& c1, std::vector<float>& c2,
+                   std::vector<int>& side) {
+        std::vector<std::vector<float>> vecs;
+        vecs.reserve(records.size());
+        for (const auto& r : records) vecs.push_back(record_vector(r));
+        c1 = vecs[0];
+        size_t far = 0;
+        float far_sim = 2.0f;
+        for (size_t i = 0; i < vecs.size(); i++) {
+            float s = dot(c1.data(), vecs[i].data(), dimension);
+            if (s < far_sim) { far_sim = s; far = i; }
+        }
+        c2 = vecs[far];
+        side.assign(vecs.size(), 0);
+        for (int iter = 0; iter < 8; iter++) {
+            std::vector<float> s1(dimension, 0.0f), s2(dimension, 0.0f);
+            size_t n1 = 0, n2 = 0;
+            for (size_t i = 0; i < vecs.size(); i++) {
+                float d1 = dot(c1.data(), vecs[i].data(), dimension);
+                float d2 = dot(c2.data(), vecs[i].data(), dimension);
+                side[i] = d2 > d1 ? 1 : 0;
+                auto& acc = side[i] ? s2 : s1;
+                for (size_t j = 0; j < dimension; j++) acc[j] += vecs[i][j];
+                (side[i] ? n2 : n1)++;
+            }
+            if (n1 == 0 || n2 == 0) break;
+            normalize(s1);
+            normalize(s2);
+            c1 = s1;
+            c2 = s2;
+        }
+        size_t n2 = std::count(side.begin(), side.end(), 1);
+        if (n2 == 0 || n2 == side.size()) {
+            for (size_t i = 0; i < side.size(); i++) side[i] = i < side.size() / 2 ? 0 : 1;
+            std::vector<float> s1(dimension, 0.0f), s2(dimension, 0.0f);
+            for (size_t i = 0; i < vecs.size(); i++) {
+                auto& acc = side[i] ? s2 : s1;
+                for (size_t j = 0; j < dimension; j++) acc[j] += vecs[i][j];
+            }
+            normalize(s1);
+            normalize(s2);
+            c1 = s1;
+            c2 = s2;
+        }
+    }
+
+    void split(uint32_t pid, std::deque<uint32_t>& work, bool reassign) {
+        std::vector<Record> records;
+        read_posting(pid, records);
+        if (records.size() <= posting_limit) {
+            postings[pid].size = static_cast<uint32_t>(records.size());
+            return;
+        }
+        std::vector<float> c1, c2;
+        std::vector<int> side;
+        two_means(records, c1, c2, side);
+
+        std::vector<uint32_t> old_neighbors = graph[pid];
+        uint32_t p1 = pid;
+        postings[p1].centroid = c1;
+        uint32_t p2 = new_posting(c2);
+
+        std::vector<Record> r1, r2;
+        std::unordered_map<uint32_t, std::vector<Record>> moved;
+        for (size_t i = 0; i < records.size(); i++) {
+            if (reassign) {
+                // LIRE: a vector in the split posting may now be closer to a
+                // neighbouring centroid than to either half.
+                std::vector<float> v = record_vector(records[i]);
+                float own = dot(v.data(), (side[i] ? c2 : c1).data(), dimension);
+                uint32_t target = side[i] ? p2 : p1;
+                for (uint32_t nb : old_neighbors) {
+                    if (!postings[nb].alive) continue;
+                    float s = dot(v.data(), postings[nb].centroid.data(), dimension);
+                    if (s > own) { own = s; target = nb; }
+                }
+                if (target != p1 && target != p2) {
+                    moved[target].push_back(std::move(records[i]));
+                    continue;
+                }
+            }
+            (side[i] ? r2 : r1).push_back(std::move(records[i]));
+        }
+        write_posting(p1, r1);
+        write_posting(p2, r2);
+        link_posting(p1);
+        link_posting(p2);
+
+        if (reassign) {
+            // LIRE: vectors in nearby postings that are now closer to one of
+            // the new centroids than to their own move over.
+            size_t checked = 0;
+            for (uint32_t nb : old_neighbors) {
+                if (!postings[nb].alive || nb == p1 || nb == p2) continue;
+                if (checked++ >= 8) break;
+                std::vector<Record> nb_records;
+                read_posting(nb, nb_records);
+                std::vector<Record> keep;
+                bool changed = false;
+                for (auto& r : nb_records) {
+                    std::vector<float> v = record_vector(r);
+                    float own = dot(v.data(), postings[nb].centroid.data(), dimension);
+                    float s1 = dot(v.data(), c1.data(), dimension);
+                    float s2 = dot(v.data(), c2.data(), dimension);
+                    if (s1 > own || s2 > own) {
+                        moved[s1 >= s2 ? p1 : p2].push_back(std::move(r));
+                        changed = true;
+                    } else {
+                        keep.push_back(std::move(r));
+                    }
+                }
+                if (changed) {
+                    auto pending = moved.find(nb);
+                    if (pending != moved.end()) {
+                        for (auto& r : pending->second) keep.push_back(std::move(r));
+                        moved.erase(pending);
+                    }
+                    write_posting(nb, keep);
+                    if (keep.size() < posting_min) work.push_back(nb);
+                }
+            }
+        }
+        for (auto& entry : moved) {
+            append_posting(entry.first, entry.second);
+            if (postings[entry.first].size > posting_limit) work.push_back(entry.first);
+        }
+        if (postings[p1].size > posting_limit) work.push_back(p1);
+        if (postings[p2].size > posting_limit) work.push_back(p2);
+    }
+
+    void merge(uint32_t pid, std::deque<uint32_t>& work) {
+        if (!postings[pid].alive || postings[pid].size >= posting_min) return;
+        if (alive_postings().size() <= 1) return;
+        std::vector<Record> records;
+        read_posting(pid, records);
+        postings[pid].alive = false;
+        postings[pid].size = 0;
+        std::remove(posting_path(pid).c_str());
+        std::unordered_map<uint32_t, std::vector<Record>> moved;
+        for (auto& r : records) {
+            std::vector<float> v = record_vector(r);
+            auto target = route(v.data(), 1);
+            if (target.empty()) continue;
+            moved[target[0].second].push_back(std::move(r));
+        }
+        for (auto& entry : moved) {
+            append_posting(entry.first, entry.second);
+            if (postings[entry.first].size > posting_limit) work.push_back(entry.first);
+        }
+    }
+
+    void rebalance(std::deque<uint32_t>& work, bool reassign) {
+        size_t budget = 100000;
+        while (!work.empty() && budget-- > 0) {
+            uint32_t pid = work.front();
+            work.pop_front();
+            if (!postings[pid].alive) continue;
+            if (postings[pid].size > posting_limit) {
+                split(pid, work, reassign);
+            } else if (postings[pid].size < posting_min) {
+                merge(pid, work);
+            }
+        }
+    }
+
+    // Writes records into their nearest posting(s) and rebalances the result.
+    void insert_records(std::vector<Record>& records, bool reassign) {
+        std::unordered_map<uint32_t, std::vector<Record>> by_posting;
+        if (alive_postings().empty()) {
+            new_posting(record_vector(records[0]));
+        }
+        for (auto& r : records) {
+            std::vector<float> v = record_vector(r);
+            auto nearest = route(v.data(), replicas);
+            float best = nearest[0].first;
+            for (size_t i = 0; i < nearest.size(); i++) {
+                // SPANN closure assignment: boundary vectors are also written
+                // to postings whose centroid is almost as close as the best.
+                if (i > 0 && (1.0f - nearest[i].first) > 1.1f * (1.0f - best)) break;
+                by_posting[nearest[i].second].push_back(r);
+            }
+        }
+        std::deque<uint32_t> work;
+        for (auto& entry : by_posting) {
+            append_posting(entry.first, entry.second);
+            if (postings[entry.first].size > posting_limit) work.push_back(entry.first);
+        }
+        rebalance(work, reassign);
+    }
+
+    // ---------------------------------------------------------------- persistence
+
+    void flush_write_buffer() {
+        if (write_buffer_vectors.empty()) return;
 
         {
-            std::ofstream vec_file(vec_path, std::ios::binary | std::ios::trunc);
+            std::ofstream vec_file(vec_path(), std::ios::binary | std::ios::app);
             if (!vec_file) {
-                return false;
+                std::cerr << "Failed to open vector file for writing: " << vec_path() << std::endl;
+                return;
             }
-            for (const auto& qv : quantized_vectors) {
+            for (const auto& qv : write_buffer_vectors) {
                 vec_file.write(reinterpret_cast<const char*>(qv.data()), dimension);
             }
         }
 
         {
-            std::ofstream meta_file(meta_path, std::ios::binary | std::ios::trunc);
+            std::ofstream meta_file(meta_path(), std::ios::binary | std::ios::app);
             if (!meta_file) {
-                return false;
+                std::cerr << "Failed to open metadata file for writing: " << meta_path() << std::endl;
+                return;
+            }
+            meta_file.write(reinterpret_cast<const char*>(write_buffer_norms.data()),
+                           write_buffer_norms.size() * sizeof(float));
+        }
+
+        std::vector<Record> records;
+        records.reserve(write_buffer_vectors.size());
+        for (size_t i = 0; i < write_buffer_vectors.size(); i++) {
+            records.push_back(Record{static_cast<uint32_t>(persisted_count + i), write_buffer_norms[i],
+                                     std::move(write_buffer_vectors[i])});
+        }
+        persisted_count += records.size();
+        write_buffer_vectors.clear();
+        write_buffer_norms.clear();
+
+        insert_records(records, true);
+        save_centroids();
+    }
+
+    bool save_centroids() {
+        std::string tmp = centroids_path() + ".tmp";
+        {
+            std::ofstream f(tmp, std::ios::binary | std::ios::trunc);
+            if (!f) return false;
+            uint32_t header[3] = {CENTROIDS_MAGIC, CENTROIDS_VERSION, static_cast<uint32_t>(dimension)};
+            f.write(reinterpret_cast<const char*>(header), sizeof(header));
+            uint64_t covered = persisted_count;
+            uint64_t count = postings.size();
+            f.write(reinterpret_cast<const char*>(&covered), sizeof(covered));
+            f.write(reinterpret_cast<const char*>(&count), sizeof(count));
+            for (size_t i = 0; i < postings.size(); i++) {
+                uint8_t alive = postings[i].alive ? 1 : 0;
+                f.write(reinterpret_cast<const char*>(&alive), 1);
+                f.write(reinterpret_cast<const char*>(&postings[i].size), sizeof(uint32_t));
+                f.write(reinterpret_cast<const char*>(postings[i].centroid.data()), dimension * sizeof(float));
+                uint32_t degree = static_cast<uint32_t>(graph[i].size());
+                f.write(reinterpret_cast<const char*>(&degree), sizeof(uint32_t));
+                f.write(reinterpret_cast<const char*>(graph[i].data()), degree * sizeof(uint32_t));
+            }
+        }
+        return std::rename(tmp.c_str(), centroids_path().c_str()) == 0;
+    }
+
+    // Returns the number of log entries the saved postings already cover, or
+    // -1 if the centroid file is missing or unusable.
+    long long load_centroids() {
+        std::ifstream f(centroids_path(), std::ios::binary);
+        if (!f) return -1;
+        uint32_t header[3];
+        uint64_t covered = 0, count = 0;
+        f.read(reinterpret_cast<char*>(header), sizeof(header));
+        f.read(reinterpret_cast<char*>(&covered), sizeof(covered));
+        f.read(reinterpret_cast<char*>(&count), sizeof(count));
+        if (!f || header[0] != CENTROIDS_MAGIC || header[1] != CENTROIDS_VERSION || header[2] != dimension) {
+            return -1;
+        }
+        std::vector<Posting> loaded(count);
+        std::vector<std::vector<uint32_t>> links(count);
+        for (size_t i = 0; i < count; i++) {
+            uint8_t alive = 0;
+            uint32_t degree = 0;
+            f.read(reinterpret_cast<char*>(&alive), 1);
+            f.read(reinterpret_cast<char*>(&loaded[i].size), sizeof(uint32_t));
+            loaded[i].alive = alive != 0;
+            loaded[i].centroid.resize(dimension);
+            f.read(reinterpret_cast<char*>(loaded[i].centroid.data()), dimension * sizeof(float));
+            f.read(reinterpret_cast<char*>(&degree), sizeof(uint32_t));
+            if (!f || degree > count) return -1;
+            links[i].resize(degree);
+            f.read(reinterpret_cast<char*>(links[i].data()), degree * sizeof(uint32_t));
+            for (uint32_t nb : links[i]) {
+                if (nb >= count) return -1;
+            }
+        }
+        if (!f) return -1;
+        postings = std::move(loaded);
+        graph = std::move(links);
+        return static_cast<long long>(covered);
+    }
+
+    // Whether every posting file holds exactly the records the centroid file
+    // says it does. Postings are written before the centroids are saved, so
+    // after a crash in between they may hold records the centroids do not
+    // cover, or have been split into postings the centroids do not list.
+    bool postings_match_files() const {
+        for (uint32_t pid = 0; pid < postings.size(); pid++) {
+            struct stat st;
+            size_t bytes = stat(posting_path(pid).c_str(), &st) == 0 ? static_cast<size_t>(st.st_size) : 0;
+            size_t expected = postings[pid].alive ? postings[pid].size * record_size() : 0;
+            if (bytes != expected) return false;
+        }
+        return true;
+    }
+
+    void clear_postings_dir() {
+        DIR* dir = opendir(postings_dir().c_str());
+        if (!dir) return;
+        while (struct dirent* entry = readdir(dir)) {
+            std::string name = entry->d_name;
+            if (name != "." && name != "..") {
+                std::remove((postings_dir() + "/" + name).c_str());
+            }
+        }
+        closedir(dir);
+    }
+
+    // Reads log entries [from, to) back as records.
+    std::vector<Record> read_log(size_t from, size_t to) {
+        std::vector<Record> records;
+        std::ifstream vec_file(vec_path(), std::ios::binary);
+        std::ifstream meta_file(meta_path(), std::ios::binary);
+        if (!vec_file) return records;
+        vec_file.seekg(static_cast<std::streamoff>(data_offset + from * dimension), std::ios::beg);
+        bool have_norms = static_cast<bool>(meta_file);
+        if (have_norms) meta_file.seekg(static_cast<std::streamoff>(from * sizeof(float)), std::ios::beg);
+        for (size_t i = from; i < to; i++) {
+            Record r;
+            r.id = static_cast<uint32_t>(i);
+            r.data.resize(dimension);
+            vec_file.read(reinterpret_cast<char*>(r.data.data()), dimension);
+            r.norm = 0.0f;
+            if (have_norms && !meta_file.read(reinterpret_cast<char*>(&r.norm), sizeof(float))) {
+                have_norms = false;
+            }
+            if (!have_norms) {
+                std::vector<float> deq = dequantize_vector(r.data);
+                r.norm = calculate_norm(deq.data(), deq.size());
+            }
+            records.push_back(std::move(r));
+        }
+        return records;
+    }
+
+    // A crash mid-flush can leave a torn vector at the end of the log, or
+    // fewer norms than vectors. Cuts the log back to its last complete
+    // vector and fills in missing norms, so later appends stay aligned.
+    bool repair_logs(size_t vec_size, size_t num_vectors) {
+        size_t complete = data_offset + num_vectors * dimension;
+        if (vec_size != complete) {
+            std::cerr << "Dropping torn vector at the end of " << vec_path() << std::endl;
+            if (truncate(vec_path().c_str(), static_cast<off_t>(complete)) != 0) return false;
+        }
+        struct stat st;
+        size_t meta_size = stat(meta_path().c_str(), &st) == 0 ? static_cast<size_t>(st.st_size) : 0;
+        size_t norms = std::min(meta_size / sizeof(float), num_vectors);
+        if (meta_size != norms * sizeof(float) &&
+            truncate(meta_path().c_str(), static_cast<off_t>(norms * sizeof(float))) != 0) {
+            return false;
+        }
+        if (norms < num_vectors) {
+            std::vector<Record> missing = read_log(norms, num_vectors);
+            std::ofstream meta_file(meta_path(), std::ios::binary | std::ios::app);
+            if (!meta_file) return false;
+            for (const Record& r : missing) {
+                meta_file.write(reinterpret_cast<const char*>(&r.norm), sizeof(float));
+            }
+            if (!meta_file) return false;
+        }
+        return true;
+    }
+
+    bool load_index() {
+        std::lock_guard<std::mutex> lock(mtx);
+        postings.clear();
+        graph.clear();
+        persisted_count = 0;
+        mkdir(postings_dir().c_str(), 0755);
+
+        std::ifstream vec_file(vec_path(), std::ios::binary);
+        if (!vec_file) {
+            return true;
+        }
+        data_offset = 0;
+        char head[8] = {0};
+        if (vec_file.read(head, sizeof(head)) && std::memcmp(head, VECTOR_FILE_MAGIC, 4) == 0) {
+            uint16_t header_len = 0;
+            std::memcpy(&header_len, head + 6, sizeof(header_len));
+            data_offset = header_len;
+        }
+        vec_file.clear();
+        vec_file.seekg(0, std::ios::end);
+        size_t vec_size = static_cast<size_t>(vec_file.tellg());
+        vec_file.close();
+        if (vec_size < data_offset) {
+            return false;
+        }
+        size_t num_vectors = (vec_size - data_offset) / dimension;
+        if (!repair_logs(vec_size, num_vectors)) {
+            return false;
+        }
+
+        long long covered = load_centroids();
+        if (covered < 0 || static_cast<size_t>(covered) > num_vectors || !postings_match_files()) {
+            // Legacy flat index, postings that no longer match the log, or a
+            // crash between writing postings and saving the centroids:
+            // partition everything from scratch.
+            postings.clear();
+            graph.clear();
+            clear_postings_dir();
+            covered = 0;
+        }
+        persisted_count = num_vectors;
+        if (static_cast<size_t>(covered) < num_vectors) {
+            const size_t chunk = 50000;
+            for (size_t start = covered; start < num_vectors; start += chunk) {
+                std::vector<Record> records = read_log(start, std::min(num_vectors, start + chunk));
+                // Bulk builds skip the neighbour reassignment; plain bisection
+                // already yields well-formed partitions.
+                insert_records(records, covered > 0);
             }
-            meta_file.write(reinterpret_cast<const char*>(norms.data()), norms.size() * sizeof(float));
+            save_centroids();
         }
         return true;
     }
-    
+
+    // ---------------------------------------------------------------- public operations
+
+    size_t size() {
+        std::lock_guard<std::mutex> lock(mtx);
+        return persisted_count + write_buffer_vectors.size();
+    }
+
+    int flush() {
+        std::lock_guard<std::mutex> lock(mtx);
+        flush_write_buffer();
+        return 0;
+    }
+
     int append(const float* vector, size_t dim) {
         std::lock_guard<std::mutex> lock(mtx);
         if (dim != dimension) {
@@ -185,70 +797,68 @@ struct SPFreshIndex {
             return -1;
         }
 
-        std::vector<float> vec(vector, vector + dim);
-        std::vector<uint8_t> quantized_vec = quantize_vector(vec);
-        float norm = calculate_norm(vec);
-
-        quantized_vectors.push_back(quantized_vec);
-        norms.push_back(norm);
+        write_buffer_vectors.push_back(quantize_vector(vector));
+        write_buffer_norms.push_back(calculate_norm(vector, dim));
 
-        write_buffer_vectors.push_back(quantized_vec);
-        write_buffer_norms.push_back(norm);
-
-        if (write_buffer_vectors.size() >= WRITE_BUFFER_SIZE) {
+        if (write_buffer_vectors.size() >= buffer_limit) {
             flush_write_buffer();
         }
-        
-        std::cout << "Successfully appended vector with dimension " << dim << std::endl;
         return 0;
     }
-    
+
     int search(const float* query, size_t dim, size_t top_k,
                size_t* result_indices, float* result_scores) {
         std::lock_guard<std::mutex> lock(mtx);
-        
-        if (dim != dimension || top_k == 0 || quantized_vectors.empty()) {
+
+        size_t total = persisted_count + write_buffer_vectors.size();
+        if (dim != dimension || top_k == 0 || total == 0) {
             return -1;
         }
-        
-        std::vector<std::pair<float, size_t>> scores;
-        scores.reserve(quantized_vectors.size());
-        
-        std::vector<float> query_vec(query, query + dim);
-        float query_norm = calculate_norm(query_vec);
-        
-        for (size_t i = 0; i < quantized_vectors.size(); i++) {
-            const auto& quantized_vec = quantized_vectors[i];
-            float doc_norm = norms[i];
-            
-            float dot_product = 0.0f;
-            for (size_t j = 0; j < dim; j++) {
-                float val1 = query[j];
-                float val2 = dequantize(quantized_vec[j]);
-                dot_product += val1 * val2;
+
+        float query_norm = calculate_norm(query, dim);
+        float query_sum = 0.0f;
+        for (size_t j = 0; j < dim; j++) query_sum += query[j];
+
+        typedef std::pair<float, size_t> Hit;
+        std::priority_queue<Hit, std::vector<Hit>, std::greater<Hit>> heap;
+        auto offer = [&](float s, size_t id) {
+            if (heap.size() < top_k) {
+                heap.emplace(s, id);
+            } else if (s > heap.top().first) {
+                heap.pop();
+                heap.emplace(s, id);
             }
-            
-            float similarity = 0.0f;
-            if (query_norm > 0 && doc_norm > 0) {
-                similarity = dot_product / (query_norm * doc_norm);
+        };
+
+        std::vector<float> unit(query, query + dim);
+        normalize(unit);
+        std::unordered_set<uint32_t> seen;
+        std::vector<Record> records;
+        for (const auto& probe : route(unit.data(), nprobe)) {
+            if (!read_posting(probe.second, records)) continue;
+            for (const auto& r : records) {
+                // Replicated vectors appear in several postings.
+                if (r.id >= persisted_count || !seen.insert(r.id).second) continue;
+                offer(score(query, query_norm, query_sum, r.data.data(), r.norm), r.id);
             }
-            
-            scores.emplace_back(similarity, i);
-        }
-        
-        std::sort(scores.begin(), scores.end(),
-                  [](const std::pair<float, size_t>& a,
-                     const std::pair<float, size_t>& b) {
-            return a.first > b.first;
-        });
-        
-        size_t result_count = std::min(top_k, scores.size());
-        for (size_t i = 0; i < result_count; i++) {
-            result_indices[i] = scores[i].second;
-            result_scores[i] = scores[i].first;
-        }
-        
-        return 0;
+        }
+        for (size_t i = 0; i < write_buffer_vectors.size(); i++) {
+            offer(score(query, query_norm, query_sum, write_buffer_vectors[i].data(), write_buffer_norms[i]),
+                  persisted_count + i);
+        }
+
+        size_t result_count = heap.size();
+        for (size_t i = result_count; i < top_k; i++) {
+            result_indices[i] = 0;
+            result_scores[i] = -1.0f;
+        }
+        for (size_t i = result_count; i > 0; i--) {
+            result_indices[i - 1] = heap.top().second;
+            result_scores[i - 1] = heap.top().first;
+            heap.pop();
+        }
+
+        return static_cast<int>(result_count);
     }
 };
 
@@ -256,11 +866,11 @@ std::unordered_map<void*, SPFreshIndex*> index_map;
 std::mutex map_mutex;
 
 extern "C" {
-    void* spfresh_index_create(const char* path) {
-        if (!path) return nullptr;
-        
+    void* spfresh_index_create_with_dim(const char* path, size_t dim) {
+        if (!path || dim == 0) return nullptr;
+
         try {
-            SPFreshIndex* index = new SPFreshIndex(std::string(path));
+            SPFreshIndex* index = new SPFreshIndex(std::string(path), dim);
             std::lock_guard<std::mutex> lock(map_mutex);
             index_map[index] = index;
             return static_cast<void*>(index);
@@ -268,40 +878,54 @@ extern "C" {
             return nullptr;
         }
     }
-    
+
+    // Kept for callers that predate configurable dimensions.
+    void* spfresh_index_create(const char* path) {
+        return spfresh_index_create_with_dim(path, 128);
+    }
+
     int spfresh_index_append(void* index_ptr, const float* vector, size_t dim) {
         if (!index_ptr || !vector) return -1;
-        
+
         std::lock_guard<std::mutex> lock(map_mutex);
         auto it = index_map.find(index_ptr);
         if (it == index_map.end()) return -1;
-        
+
         return it->second->append(vector, dim);
     }
-    
-    int spfresh_index_search(void* index_ptr, const float* query, size_t dim, 
+
+    int spfresh_index_search(void* index_ptr, const float* query, size_t dim,
                             size_t top_k, size_t* result_indices, float* result_scores) {
         if (!index_ptr || !query || !result_indices || !result_scores) return -1;
-        
+
         std::lock_guard<std::mutex> lock(map_mutex);
         auto it = index_map.find(index_ptr);
         if (it == index_map.end()) return -1;
-        
+
         return it->second->search(query, dim, top_k, result_indices, result_scores);
     }
-    
+
     size_t spfresh_index_size(void* index_ptr) {
         if (!index_ptr) return 0;
-        
+
         std::lock_guard<std::mutex> lock(map_mutex);
         auto it = index_map.find(index_ptr);
         if (it == index_map.end()) return 0;
-        return it->second->quantized_vectors.size();
+        return it->second->size();
     }
-    
+
+    int spfresh_index_flush(void* index_ptr) {
+        if (!index_ptr) return -1;
+
+        std::lock_guard<std::mutex> lock(map_mutex);
+        auto it = index_map.find(index_ptr);
+        if (it == index_map.end()) return -1;
+        return it->second->flush();
+    }
+
     void spfresh_index_destroy(void* index_ptr) {
         if (!index_ptr) return;
-        
+
         std::lock_guard<std::mutex> lock(map_mutex);
         auto it = index_map.find(index_ptr);
         if (it != index_map.end()) {
@@ -309,4 +933,4 @@ extern "C" {
             index_map.erase(it);
         }
     }
-}
\ No newline at end of file
+}
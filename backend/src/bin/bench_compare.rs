@@ -1,52 +1,40 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
 use std::time::Instant;
 
 use anyhow::Result;
-use backend::embed::Embedder;
+use backend::embed::{pca, Embedder, Projection, Reduction, TextKind};
+use backend::handlers::extract_text;
+use backend::storage::index::{open_index, IndexBackend};
+use backend::storage::metadata::open_metadata;
 use reqwest::Client;
 use serde_json::{json, Value};
 
-#[cfg(feature = "spfresh")]
-use spfresh::Index as VectorIndex;
-#[cfg(not(feature = "spfresh"))]
-use backend::storage::vector_store::VectorStore as VectorIndex;
-
 const SAMPLE_QUERIES: usize = 1000;
 const TOP_K: usize = 100;
+/// Reviews and queries for the reduction comparison, which ranks exactly.
+const REDUCTION_DOCS: usize = 2000;
+const REDUCTION_QUERIES: usize = 100;
+const REDUCTION_K: usize = 10;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     let data_dir = manifest_dir.join("data");
-    let input_path = data_dir.join("reviews.jsonl");
-    let index_path = {
-        #[cfg(feature = "spfresh")]
-        {
-            data_dir.join("reviews")
-        }
-        #[cfg(not(feature = "spfresh"))]
-        {
-            data_dir.join("reviews.vectors")
-        }
-    };
-
-    let mut index = VectorIndex::open_or_create(index_path)?;
-    let embedder = Embedder::new()?;
+    let backend = IndexBackend::from_env()?;
+    let embedder = Embedder::open(&data_dir)?;
+    let index = open_index(backend, &data_dir, &embedder.vector_spec())?;
+    if matches!(std::env::var("BENCH_REDUCTIONS").as_deref(), Ok("1") | Ok("true")) {
+        compare_reductions(&data_dir, &embedder)?;
+    }
     let client = Client::new();
     let qdrant_url = std::env::var("QDRANT_URL").unwrap_or_else(|_| "http://localhost:6333".to_string());
     let collection = std::env::var("QDRANT_COLLECTION").unwrap_or_else(|_| "reviews".to_string());
 
     // sample queries
-    let file = File::open(&input_path)?;
-    let reader = BufReader::new(file);
+    let metadata = open_metadata(&data_dir)?;
     let mut queries: Vec<String> = Vec::new();
-    for (i, line) in reader.lines().enumerate() {
-        if i >= SAMPLE_QUERIES {
-            break;
-        }
-        let l = line?;
+    for item in metadata.iter().take(SAMPLE_QUERIES) {
+        let (_, l) = item?;
         let v: Value = serde_json::from_str(&l)?;
         queries.push(extract_text(&v));
     }
@@ -56,9 +44,9 @@ async fn main() -> Result<()> {
     let mut recalls: Vec<f32> = Vec::with_capacity(SAMPLE_QUERIES);
 
     for (idx, q) in queries.iter().enumerate() {
-        let emb = embedder.embed_default(q);
+        let emb = embedder.embed_query(q);
 
-        // spfresh search
+        // local index search
         let t0 = Instant::now();
         let sp_results = index.search(&emb, TOP_K)?; // Vec<(usize, f32)>
         let sp_ids: Vec<usize> = sp_results.iter().map(|(id, _)| *id).collect();
@@ -91,33 +79,104 @@ async fn main() -> Result<()> {
     let avg_recall = recalls.iter().sum::<f32>() / recalls.len() as f32;
 
     println!("\n=== Benchmark Results ===");
-    println!("Avg latency (µs)  - spfresh: {:.2}, qdrant: {:.2}", avg_sp, avg_q);
+    println!("Avg latency (µs)  - {}: {:.2}, qdrant: {:.2}", backend, avg_sp, avg_q);
     println!("Avg recall@{}      : {:.3}", TOP_K, avg_recall);
 
     Ok(())
 }
 
-fn average(xs: &[u128]) -> f64 {
-    if xs.is_empty() { return 0.0; }
-    let sum: u128 = xs.iter().sum();
-    (sum as f64) / (xs.len() as f64)
-}
-
-fn extract_text(v: &Value) -> String {
-    let mut parts = Vec::new();
-    if let Some(title) = v.get("review_title").and_then(|x| x.as_str()) {
-        if !title.trim().is_empty() {
-            parts.push(title.trim());
+/// Reports how many of each sampled query's exact top results at the
+/// provider's full dimension survive mean pooling and the latest fitted
+/// projection. Documents and queries are disjoint, and both skip the reviews
+/// `fit_pca` samples with the current `PCA_SAMPLE`, so the projection is not
+/// scored on its own training data. Only run with `BENCH_REDUCTIONS=1`, as it
+/// embeds a few thousand extra reviews.
+fn compare_reductions(data_dir: &std::path::Path, embedder: &Embedder) -> Result<()> {
+    let metadata = open_metadata(data_dir)?;
+    let rows = metadata.len();
+    let fit_sample = pca::sample_size()?;
+    let held_out = (0..rows).filter(|&p| !pca::in_sample(p, rows, fit_sample)).count();
+    // Every `stride`-th held-out review is a document; queries fall halfway
+    // between documents, spread over the whole store.
+    let stride = (held_out / REDUCTION_DOCS).max(2);
+    let query_stride = stride * (REDUCTION_DOCS / REDUCTION_QUERIES);
+    let mut doc_texts: Vec<String> = Vec::with_capacity(REDUCTION_DOCS);
+    let mut query_texts: Vec<String> = Vec::with_capacity(REDUCTION_QUERIES);
+    let mut seen = 0usize;
+    for (position, item) in metadata.iter().enumerate() {
+        if pca::in_sample(position, rows, fit_sample) {
+            continue;
         }
+        if seen.is_multiple_of(stride) && doc_texts.len() < REDUCTION_DOCS {
+            let (_, l) = item?;
+            doc_texts.push(extract_text(&serde_json::from_str(&l)?));
+        } else if seen % query_stride == stride / 2 && query_texts.len() < REDUCTION_QUERIES {
+            let (_, l) = item?;
+            query_texts.push(extract_text(&serde_json::from_str(&l)?));
+        }
+        seen += 1;
+    }
+    let docs: Vec<Vec<f32>> = embedder.embed_batch_full(&doc_texts, TextKind::Passage)?.into_iter().flatten().collect();
+    let queries: Vec<Vec<f32>> = embedder.embed_batch_full(&query_texts, TextKind::Query)?.into_iter().flatten().collect();
+    if docs.len() < REDUCTION_K || queries.is_empty() {
+        println!("Too few reviews outside the PCA_SAMPLE fit sample to compare reductions");
+        return Ok(());
     }
-    if let Some(body) = v.get("review_body").and_then(|x| x.as_str()) {
-        if !body.trim().is_empty() {
-            parts.push(body.trim());
+    let truth: Vec<Vec<usize>> = queries.iter().map(|q| top_k(q, &docs, REDUCTION_K)).collect();
+
+    let mut reductions = vec![("mean pooling".to_string(), Reduction::Mean)];
+    if let Some(version) = Projection::latest_version(data_dir)? {
+        let projection = Projection::load(data_dir, version)?;
+        if projection.source_model == embedder.source_model_id()
+            && projection.input_dim == embedder.native_size()
+            && projection.output_dim == embedder.embedding_size()
+        {
+            let name = format!("pca-v{}{}", version, if projection.whiten { " (whitened)" } else { "" });
+            reductions.push((name, Reduction::Pca(std::sync::Arc::new(projection))));
+        } else {
+            println!("Skipping pca-v{}: it was fitted for another model or dimension", version);
         }
     }
-    if parts.is_empty() {
-        v.to_string()
-    } else {
-        parts.join(" ")
+
+    println!(
+        "\n=== Reduction recall@{} ({} -> {} dims, {} queries over {} reviews) ===",
+        REDUCTION_K,
+        embedder.native_size(),
+        embedder.embedding_size(),
+        queries.len(),
+        docs.len()
+    );
+    for (name, reduction) in reductions {
+        let reduced: Vec<Vec<f32>> = docs.iter().map(|d| reduction.apply(d, embedder.embedding_size())).collect();
+        let recall = queries
+            .iter()
+            .zip(&truth)
+            .map(|(q, exact)| {
+                let found = top_k(&reduction.apply(q, embedder.embedding_size()), &reduced, REDUCTION_K);
+                found.iter().filter(|id| exact.contains(id)).count() as f32 / REDUCTION_K as f32
+            })
+            .sum::<f32>()
+            / queries.len() as f32;
+        println!("{:<24}: {:.3}", name, recall);
     }
+    Ok(())
+}
+
+/// Indices of the `k` vectors in `docs` most cosine-similar to `query`.
+fn top_k(query: &[f32], docs: &[Vec<f32>], k: usize) -> Vec<usize> {
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt().max(f32::MIN_POSITIVE);
+    let query_norm = norm(query);
+    let mut scored: Vec<(usize, f32)> = docs
+        .iter()
+        .enumerate()
+        .map(|(i, d)| (i, query.iter().zip(d).map(|(a, b)| a * b).sum::<f32>() / (query_norm * norm(d))))
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scored.into_iter().take(k).map(|(i, _)| i).collect()
+}
+
+fn average(xs: &[u128]) -> f64 {
+    if xs.is_empty() { return 0.0; }
+    let sum: u128 = xs.iter().sum();
+    (sum as f64) / (xs.len() as f64)
 }
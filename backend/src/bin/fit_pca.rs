@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use serde_json::Value;
+
+use backend::embed::{pca, Embedder, Projection, TextKind};
+use backend::handlers::extract_text;
+use backend::storage::metadata::open_metadata;
+
+const BATCH: usize = 256;
+
+/// Fits a PCA projection from the provider's full-size vectors to
+/// `EMBEDDING_DIM` on an evenly spread sample of the stored reviews, and
+/// saves it as the next version in `data/reduction`. `PCA_WHITEN=1` also
+/// scales each direction to unit variance.
+fn main() -> Result<()> {
+    let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let data_dir = manifest_dir.join("data");
+    let metadata = open_metadata(&data_dir)?;
+    if metadata.is_empty() {
+        eprintln!("No reviews found in {:?}", data_dir);
+        std::process::exit(1);
+    }
+    let sample = pca::sample_size()?;
+    let whiten = matches!(std::env::var("PCA_WHITEN").as_deref(), Ok("1") | Ok("true"));
+
+    println!("Initializing embedder...");
+    let embedder = Embedder::new()?;
+    let source_model = embedder.source_model_id();
+    println!(
+        "Fitting {} -> {} dimensions for {}{}",
+        embedder.native_size(),
+        embedder.embedding_size(),
+        source_model,
+        if whiten { " (whitened)" } else { "" }
+    );
+
+    let rows = metadata.len();
+    let mut texts: Vec<String> = Vec::with_capacity(sample);
+    for (position, item) in metadata.iter().enumerate() {
+        if !pca::in_sample(position, rows, sample) {
+            continue;
+        }
+        let (_, line) = item?;
+        texts.push(match serde_json::from_str::<Value>(&line) {
+            Ok(v) => extract_text(&v),
+            Err(_) => line,
+        });
+    }
+
+    let mut samples: Vec<Vec<f32>> = Vec::with_capacity(texts.len());
+    for chunk in texts.chunks(BATCH) {
+        samples.extend(embedder.embed_batch_full(chunk, TextKind::Passage)?.into_iter().flatten());
+        print!("Embedded {} / {}\r", samples.len(), texts.len());
+        std::io::Write::flush(&mut std::io::stdout())?;
+    }
+    println!();
+
+    let version = Projection::latest_version(&data_dir)?.map_or(1, |v| v + 1);
+    let projection = Projection::fit(&samples, embedder.embedding_size(), whiten, &source_model, version)?;
+    let path = projection.save(&data_dir)?;
+    println!(
+        "Saved pca-v{} to {:?}; it keeps {:.1}% of the sample's variance",
+        version,
+        path,
+        projection.explained * 100.0
+    );
+    println!("Set EMBEDDING_REDUCTION=pca and rebuild the index with `cargo run --bin index_builder`");
+    Ok(())
+}
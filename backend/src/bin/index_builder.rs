@@ -1,107 +1,94 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
 
 use anyhow::Result;
 use std::io::Write;
-use rayon::prelude::*;
 use serde_json::Value;
 
-use backend::embed::Embedder;
-#[cfg(feature = "spfresh")]
-use spfresh::Index as VectorIndex;
-#[cfg(not(feature = "spfresh"))]
-use backend::storage::vector_store::VectorStore as VectorIndex;
+use backend::embed::{Embedder, TextKind};
+use backend::handlers::extract_text;
+use backend::storage::index::{open_index, remove_index, IndexBackend, VectorIndex};
+use backend::storage::metadata::open_metadata;
+use backend::storage::wal::{self, Wal};
 
 fn main() -> Result<()> {
     let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     let data_dir = manifest_dir.join("data");
-    let input_path = data_dir.join("reviews.jsonl");
-    if !input_path.exists() {
-        eprintln!("Input file {:?} not found", input_path);
+    let mut metadata = open_metadata(&data_dir)?;
+    // Reviews still in the write-ahead log are indexed too; the vectors it
+    // holds are discarded along with the old index.
+    let (mut wal, entries) = Wal::open(Wal::path_for(&data_dir))?;
+    wal::apply(&entries, None, &mut metadata)?;
+    metadata.flush()?;
+    if metadata.is_empty() {
+        eprintln!("No reviews found in {:?}", data_dir);
         std::process::exit(1);
     }
-    let total_lines: usize = {
-        let f = File::open(&input_path)?;
-        let reader = BufReader::new(f);
-        reader.lines().count()
-    };
+    let total_rows = metadata.len();
     println!("Initializing embedder...");
-    let embedder = Embedder::new()?;
-    println!("Resetting index directory/file ...");
-    let index_path = {
-        #[cfg(feature = "spfresh")]
-        { data_dir.join("reviews") }
-        #[cfg(not(feature = "spfresh"))]
-        { data_dir.join("reviews.vectors") }
-    };
-    if index_path.exists() {
-        if index_path.is_dir() {
-            std::fs::remove_dir_all(&index_path)?;
-        } else {
-            std::fs::remove_file(&index_path)?;
-        }
-    }
-    #[cfg(feature = "spfresh")]
-    let index_path = data_dir.join("reviews");
-    #[cfg(not(feature = "spfresh"))]
-    let index_path = data_dir.join("reviews.vectors");
-    let mut index = VectorIndex::open_or_create(index_path)?;
+    let embedder = Embedder::open(&data_dir)?;
+    println!("Embedding with {}", embedder.model_id());
+    let backend = IndexBackend::from_env()?;
+    println!("Resetting {} index files ...", backend);
+    remove_index(backend, &data_dir)?;
+    let mut index = open_index(backend, &data_dir, &embedder.vector_spec())?;
     const BATCH: usize = 200;
 
-    let file = File::open(&input_path)?;
-    let reader = BufReader::new(file);
-    let mut buffer = Vec::with_capacity(BATCH);
+    // `None` stands for a deleted row, so vector rows keep matching metadata rows.
+    let mut buffer: Vec<Option<String>> = Vec::with_capacity(BATCH);
     let mut processed: usize = 0;
-    for (_idx, line) in reader.lines().enumerate() {
-                let line = line?;
-                buffer.push(line);
+    let mut push = |entry: Option<String>| -> Result<()> {
+        buffer.push(entry);
         if buffer.len() == BATCH {
-            process_batch(&buffer, &embedder, &mut index)?;
+            process_batch(&buffer, &embedder, index.as_mut())?;
             processed += buffer.len();
-            print!("Processed {} / {}\r", processed, total_lines);
+            print!("Processed {} / {}\r", processed, total_rows);
             std::io::stdout().flush()?;
             buffer.clear();
         }
+        Ok(())
+    };
+    let mut next_row = 0;
+    for item in metadata.iter() {
+        let (row, line) = item?;
+        for _ in next_row..row {
+            push(None)?;
+        }
+        push(Some(line))?;
+        next_row = row + 1;
+    }
+    for _ in next_row..total_rows {
+        push(None)?;
     }
     if !buffer.is_empty() {
-        process_batch(&buffer, &embedder, &mut index)?;
+        process_batch(&buffer, &embedder, index.as_mut())?;
         processed += buffer.len();
+        println!("Processed {} / {}", processed, total_rows);
     }
-    #[cfg(feature = "spfresh")]
-    println!("Index build completed. Total vectors: {}", index.len());
-    #[cfg(not(feature = "spfresh"))]
+    index.flush()?;
+    wal.reset()?;
     println!("Index build completed. Total vectors: {}", index.len()?);
     Ok(())
 }
 
-fn extract_text(v: &Value) -> String {
-    let mut parts = Vec::new();
-    if let Some(title) = v.get("review_title").and_then(|x| x.as_str()) {
-        if !title.trim().is_empty() { parts.push(title.trim()); }
-    }
-    if let Some(body) = v.get("review_body").and_then(|x| x.as_str()) {
-        if !body.trim().is_empty() { parts.push(body.trim()); }
-    }
-    if parts.is_empty() { v.to_string() } else { parts.join(" ") }
-}
-
-fn process_batch(lines: &[String], embedder: &Embedder, index: &mut VectorIndex) -> Result<()> {
-    let embeddings: Vec<Vec<f32>> = lines
-        .par_iter()
-        .map(|l| {
-            match serde_json::from_str::<Value>(l) {
-                Ok(v) => embedder.embed_default(&extract_text(&v)),
-                Err(_) => {
-                    embedder.embed_default(l)
-                }
-            }
+/// Deleted reviews still get a (zero) vector so rows keep lining up with the
+/// metadata store, and are tombstoned right away.
+fn process_batch(lines: &[Option<String>], embedder: &Embedder, index: &mut dyn VectorIndex) -> Result<()> {
+    let first_row = index.len()?;
+    let texts: Vec<String> = lines
+        .iter()
+        .map(|l| match l {
+            None => String::new(),
+            Some(l) => match serde_json::from_str::<Value>(l) {
+                Ok(v) => extract_text(&v),
+                Err(_) => l.clone(),
+            },
         })
         .collect();
-    for (i, embedding) in embeddings.iter().enumerate() {
-        index.append(embedding)?;
-        if i % 1000 == 0 {
-            println!("Appended {} vectors", i);
+    let embeddings = embedder.embed_batch(&texts, TextKind::Passage)?;
+    index.append_batch(&embeddings)?;
+    for (i, line) in lines.iter().enumerate() {
+        if line.is_none() {
+            index.delete(first_row + i)?;
         }
     }
     Ok(())
@@ -1,5 +1,3 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
 
 use anyhow::Result;
@@ -7,7 +5,9 @@ use serde_json::Value;
 use serde::Serialize;
 use reqwest::Client;
 use tokio::time::{sleep, Duration};
-use backend::embed::Embedder;
+use backend::embed::{Embedder, TextKind};
+use backend::handlers::extract_text;
+use backend::storage::metadata::open_metadata;
 
 #[derive(Serialize)]
 struct Point<'a> {
@@ -26,9 +26,9 @@ struct PointsBatch<'a> {
 async fn main() -> Result<()> {
     let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     let data_dir = manifest_dir.join("data");
-    let input_path = data_dir.join("reviews.jsonl");
-    if !input_path.exists() {
-        eprintln!("reviews.jsonl not found in {:?}", input_path);
+    let metadata = open_metadata(&data_dir)?;
+    if metadata.is_empty() {
+        eprintln!("No reviews found in {:?}", data_dir);
         std::process::exit(1);
     }
     // QDRANT endpoint; override via env var
@@ -36,61 +36,53 @@ async fn main() -> Result<()> {
     let collection = std::env::var("QDRANT_COLLECTION").unwrap_or_else(|_| "reviews".to_string());
 
     let client = Client::new();
-    let embedder = Embedder::new()?;
+    let embedder = Embedder::open(&data_dir)?;
 
     const BATCH: usize = 64;
-    let file = File::open(&input_path)?;
-    let reader = BufReader::new(file);
 
-    let mut batch_points: Vec<Vec<f32>> = Vec::with_capacity(BATCH);
+    let mut batch_ids: Vec<u64> = Vec::with_capacity(BATCH);
     let mut batch_payloads: Vec<Value> = Vec::with_capacity(BATCH);
     let mut total: u64 = 0;
-    for line in reader.lines() {
-        let line = line?;
+    // Point ids are metadata row numbers, so deleted rows leave gaps.
+    for item in metadata.iter() {
+        let (row, line) = item?;
         let v: Value = serde_json::from_str(&line)?;
-        let text = extract_text(&v);
-        let embedding = embedder.embed_default(&text);
-        batch_points.push(embedding);
+        batch_ids.push(row as u64);
         batch_payloads.push(v);
 
-        if batch_points.len() == BATCH {
-            upload_batch(&client, &qdrant_url, &collection, total, &batch_points, &batch_payloads).await?;
-            total += batch_points.len() as u64;
-            batch_points.clear();
+        if batch_ids.len() == BATCH {
+            total += embed_and_upload(&client, &qdrant_url, &collection, &embedder, &batch_ids, &batch_payloads).await?;
+            batch_ids.clear();
             batch_payloads.clear();
         }
     }
-    if !batch_points.is_empty() {
-        upload_batch(&client, &qdrant_url, &collection, total, &batch_points, &batch_payloads).await?;
+    if !batch_ids.is_empty() {
+        total += embed_and_upload(&client, &qdrant_url, &collection, &embedder, &batch_ids, &batch_payloads).await?;
     }
-    println!("Finished uploading to Qdrant. Total points: {}", total + batch_points.len() as u64);
+    println!("Finished uploading to Qdrant. Total points: {}", total);
     Ok(())
 }
 
-fn extract_text(v: &Value) -> String {
-    let mut parts = Vec::new();
-    if let Some(title) = v.get("review_title").and_then(|x| x.as_str()) {
-        if !title.trim().is_empty() {
-            parts.push(title.trim());
-        }
-    }
-    if let Some(body) = v.get("review_body").and_then(|x| x.as_str()) {
-        if !body.trim().is_empty() {
-            parts.push(body.trim());
-        }
-    }
-    if parts.is_empty() {
-        v.to_string()
-    } else {
-        parts.join(" ")
-    }
+/// Embeds the reviews in `payloads` as one batch and uploads them.
+async fn embed_and_upload(
+    client: &Client,
+    base_url: &str,
+    collection: &str,
+    embedder: &Embedder,
+    ids: &[u64],
+    payloads: &[Value],
+) -> Result<u64> {
+    let texts: Vec<String> = payloads.iter().map(extract_text).collect();
+    let vectors = embedder.embed_batch(&texts, TextKind::Passage)?;
+    upload_batch(client, base_url, collection, ids, &vectors, payloads).await?;
+    Ok(ids.len() as u64)
 }
 
 async fn upload_batch(
     client: &Client,
     base_url: &str,
     collection: &str,
-    start_id: u64,
+    ids: &[u64],
     vectors: &[Vec<f32>],
     payloads: &[Value],
 ) -> Result<()> {
@@ -98,13 +90,13 @@ async fn upload_batch(
         .iter()
         .enumerate()
         .map(|(i, vec)| Point {
-            id: start_id + i as u64,
+            id: ids[i],
             vector: vec,
             payload: Some(&payloads[i]),
         })
         .collect();
 
-    let body = serde_json::json!({ "points": points });
+    let body = PointsBatch { points };
 
     let url = format!("{}/collections/{}/points?wait=true", base_url, collection);
     let resp = client.put(url).json(&body).send().await?;
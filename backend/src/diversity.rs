@@ -0,0 +1,130 @@
+//! Maximal marginal relevance re-ranking of search results.
+//!
+//! Near-duplicate reviews tend to fill the top of a ranking together. With a
+//! `diversity` object on a search, results are picked greedily by
+//! `lambda * relevance - (1 - lambda) * max similarity to those already
+//! picked`, where similarity is the cosine of the stored vectors. Reviews of
+//! a product that already has `max_per_product` results are left out.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::paging::Hit;
+
+fn default_lambda() -> f32 {
+    0.7
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Diversity {
+    /// 1 ranks by relevance alone, 0 by novelty alone.
+    #[serde(default = "default_lambda")]
+    pub lambda: f32,
+    /// Most results kept per `product_id`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_per_product: Option<usize>,
+}
+
+impl Diversity {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.lambda) {
+            return Err("diversity.lambda must be between 0 and 1".to_string());
+        }
+        if self.max_per_product == Some(0) {
+            return Err("diversity.max_per_product must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+
+    /// Reorders `hits` (best first) by MMR. `vector` returns a row's stored
+    /// vector, or `None` if the backend cannot read it back, in which case
+    /// the row is treated as similar to nothing. `product` returns a row's
+    /// product id; rows without one are never capped.
+    pub fn apply(
+        &self,
+        hits: Vec<Hit>,
+        mut vector: impl FnMut(usize) -> Option<Vec<f32>>,
+        mut product: impl FnMut(usize) -> Option<String>,
+    ) -> Vec<Hit> {
+        let mut candidates: Vec<(Hit, Option<Vec<f32>>, Option<String>)> = hits
+            .into_iter()
+            .map(|hit| {
+                let v = if self.lambda < 1.0 { vector(hit.row) } else { None };
+                let p = if self.max_per_product.is_some() { product(hit.row) } else { None };
+                (hit, v, p)
+            })
+            .collect();
+        // Highest similarity of each candidate to any picked result.
+        let mut max_sim = vec![f32::NEG_INFINITY; candidates.len()];
+        let mut per_product: HashMap<String, usize> = HashMap::new();
+        let mut picked = Vec::with_capacity(candidates.len());
+
+        while !candidates.is_empty() {
+            if let Some(cap) = self.max_per_product {
+                let mut i = 0;
+                while i < candidates.len() {
+                    let full = candidates[i].2.as_ref().is_some_and(|p| per_product.get(p).copied().unwrap_or(0) >= cap);
+                    if full {
+                        candidates.swap_remove(i);
+                        max_sim.swap_remove(i);
+                    } else {
+                        i += 1;
+                    }
+                }
+                if candidates.is_empty() {
+                    break;
+                }
+            }
+            let mmr = |i: usize| {
+                let penalty = if max_sim[i].is_finite() { max_sim[i] } else { 0.0 };
+                self.lambda * candidates[i].0.score - (1.0 - self.lambda) * penalty
+            };
+            // Ties go to the better ranked hit, so lambda = 1 keeps the order.
+            let best = (0..candidates.len())
+                .max_by(|&a, &b| {
+                    let (x, y) = (&candidates[a].0, &candidates[b].0);
+                    mmr(a).total_cmp(&mmr(b)).then(x.score.total_cmp(&y.score)).then(y.id.cmp(&x.id))
+                })
+                .expect("candidates is not empty");
+            let (hit, chosen, product) = candidates.swap_remove(best);
+            max_sim.swap_remove(best);
+            if let Some(chosen) = &chosen {
+                for (i, (_, v, _)) in candidates.iter().enumerate() {
+                    if let Some(v) = v {
+                        let sim: f32 = v.iter().zip(chosen).map(|(a, b)| a * b).sum();
+                        max_sim[i] = max_sim[i].max(sim);
+                    }
+                }
+            }
+            if let Some(product) = product {
+                *per_product.entry(product).or_insert(0) += 1;
+            }
+            picked.push(hit);
+        }
+        picked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paging::hit;
+
+    #[test]
+    fn mmr_pushes_near_duplicates_down_and_caps_products() {
+        // Rows 1 and 2 are the same text; row 3 is different but less relevant.
+        let vectors = |row: usize| Some(if row == 3 { vec![0.0, 1.0] } else { vec![1.0, 0.0] });
+        let products = |row: usize| Some(if row == 3 { "B" } else { "A" }.to_string());
+        let hits = || vec![hit(1, 0.9), hit(2, 0.88), hit(3, 0.6)];
+        let ids = |hits: Vec<Hit>| hits.into_iter().map(|h| h.id).collect::<Vec<_>>();
+
+        let relevance = Diversity { lambda: 1.0, max_per_product: None };
+        assert_eq!(ids(relevance.apply(hits(), vectors, products)), vec![1, 2, 3]);
+        let diverse = Diversity { lambda: 0.5, max_per_product: None };
+        assert_eq!(ids(diverse.apply(hits(), vectors, products)), vec![1, 3, 2]);
+        let capped = Diversity { lambda: 1.0, max_per_product: Some(1) };
+        assert_eq!(ids(capped.apply(hits(), vectors, products)), vec![1, 3]);
+        assert!(Diversity { lambda: 1.5, max_per_product: None }.validate().is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use anyhow::Result;
+
+use super::EmbeddingProvider;
+use crate::storage::lexical;
+
+/// Output positions each feature is projected onto.
+const PROJECTIONS: u64 = 2;
+/// Weight of a word bigram relative to a word.
+const BIGRAM_WEIGHT: f32 = 0.5;
+/// Weight of each character trigram relative to a word.
+const TRIGRAM_WEIGHT: f32 = 1.0;
+
+/// Embeds text without a model. The features are the words, the word
+/// bigrams and the character trigrams of each word, so texts sharing words
+/// or word stems ("battery", "batteries") get similar vectors. Each feature
+/// is hashed onto a few output positions with random signs (a sparse signed
+/// random projection), and the sum is L2-normalized. The same text always
+/// gets the same vector, on any machine.
+pub struct HashingProvider {
+    dimension: usize,
+}
+
+impl HashingProvider {
+    pub fn new(dimension: usize) -> Self {
+        Self { dimension }
+    }
+
+    fn embed_one(&self, text: &str) -> Vec<f32> {
+        let mut vector = vec![0.0_f32; self.dimension];
+        let words: Vec<String> = lexical::tokenize(text).collect();
+        for (i, word) in words.iter().enumerate() {
+            self.add(&mut vector, &["w:", word], 1.0);
+            if let Some(next) = words.get(i + 1) {
+                self.add(&mut vector, &["b:", word, " ", next], BIGRAM_WEIGHT);
+            }
+            let padded: Vec<char> = std::iter::once('<').chain(word.chars()).chain(std::iter::once('>')).collect();
+            for gram in padded.windows(3) {
+                let gram: String = gram.iter().collect();
+                self.add(&mut vector, &["c:", &gram], TRIGRAM_WEIGHT);
+            }
+        }
+        let norm: f32 = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            for v in &mut vector {
+                *v /= norm;
+            }
+        }
+        vector
+    }
+
+    /// Adds `weight` times the projection of the feature named by the
+    /// concatenation of `parts`.
+    fn add(&self, vector: &mut [f32], parts: &[&str], weight: f32) {
+        let feature = parts.iter().fold(FNV_OFFSET, |hash, part| fnv1a(hash, part.as_bytes()));
+        for seed in 0..PROJECTIONS {
+            let hash = mix(feature ^ seed.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+            let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+            vector[(hash % self.dimension as u64) as usize] += sign * weight;
+        }
+    }
+}
+
+impl EmbeddingProvider for HashingProvider {
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        Ok(texts.iter().map(|text| self.embed_one(text)).collect())
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> String {
+        "hash-ngrams-v1".to_string()
+    }
+}
+
+pub(super) const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// 64-bit FNV-1a, continued from `hash`; unlike `DefaultHasher` it is fixed
+/// across Rust releases, which stored vectors depend on.
+pub(super) fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// The splitmix64 finalizer; spreads FNV's weak bits over the whole word.
+fn mix(mut hash: u64) -> u64 {
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn related_texts_are_closer_than_unrelated_ones() {
+        let provider = HashingProvider::new(128);
+        let texts = ["The battery drains fast", "Batteries drain quickly", "Lovely soft fabric"];
+        let vectors = provider.embed_batch(&texts).unwrap();
+        let cosine = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+
+        assert_eq!(vectors, provider.embed_batch(&texts).unwrap());
+        assert!((cosine(&vectors[0], &vectors[0]) - 1.0).abs() < 1e-5);
+        assert!(cosine(&vectors[0], &vectors[1]) > cosine(&vectors[0], &vectors[2]) + 0.1);
+        assert_eq!(provider.embed_batch(&["  "]).unwrap()[0], vec![0.0; 128]);
+    }
+}
@@ -0,0 +1,201 @@
+use anyhow::Result;
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use super::EmbeddingProvider;
+
+const DEFAULT_URL: &str = "https://api.openai.com/v1";
+const DEFAULT_MODEL: &str = "text-embedding-3-small";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An OpenAI-compatible embedding endpoint: `POST {url}/embeddings` with
+/// `{"model", "input": [...]}`, answered by `{"data": [{"index", "embedding"}]}`.
+pub struct HttpProvider {
+    endpoint: String,
+    model: String,
+    api_key: Option<String>,
+    dimension: usize,
+    /// Built on first use; see [`HttpProvider::embed_batch`].
+    client: OnceLock<Client>,
+}
+
+#[derive(Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [&'a str],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+impl HttpProvider {
+    /// Reads `EMBEDDING_API_URL` (default the OpenAI API), `EMBEDDING_API_KEY`
+    /// (or `OPENAI_API_KEY`), `EMBEDDING_MODEL` and `EMBEDDING_API_DIM`.
+    pub fn from_env() -> Result<Self> {
+        let url = std::env::var("EMBEDDING_API_URL").unwrap_or_else(|_| DEFAULT_URL.to_string());
+        let model = std::env::var("EMBEDDING_MODEL").unwrap_or_else(|_| DEFAULT_MODEL.to_string());
+        let api_key = std::env::var("EMBEDDING_API_KEY").or_else(|_| std::env::var("OPENAI_API_KEY")).ok();
+        let dimension = match std::env::var("EMBEDDING_API_DIM") {
+            Ok(value) => Some(
+                value
+                    .trim()
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|dim| *dim > 0)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid EMBEDDING_API_DIM {:?}", value))?,
+            ),
+            Err(_) => None,
+        };
+        Self::new(&url, &model, api_key, dimension)
+    }
+
+    /// Without a `dimension`, one text is embedded to find it out.
+    pub fn new(url: &str, model: &str, api_key: Option<String>, dimension: Option<usize>) -> Result<Self> {
+        let mut provider = Self {
+            endpoint: format!("{}/embeddings", url.trim_end_matches('/')),
+            model: model.to_string(),
+            api_key,
+            dimension: dimension.unwrap_or(0),
+            client: OnceLock::new(),
+        };
+        if dimension.is_none() {
+            let probe = provider
+                .embed_batch(&["dimension probe"])
+                .map_err(|e| anyhow::anyhow!("Failed to reach embedding endpoint {}: {}", provider.endpoint, e))?;
+            provider.dimension = probe[0].len();
+        }
+        Ok(provider)
+    }
+
+    fn client(&self) -> Result<&Client> {
+        if let Some(client) = self.client.get() {
+            return Ok(client);
+        }
+        let client = Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| anyhow::anyhow!("Failed to build HTTP client: {}", e))?;
+        Ok(self.client.get_or_init(|| client))
+    }
+
+    fn send(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let mut request = self.client()?.post(&self.endpoint).json(&EmbeddingRequest { model: &self.model, input: texts });
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+        let response = request.send().map_err(|e| anyhow::anyhow!("Failed to send embedding request: {}", e))?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().unwrap_or_default();
+            anyhow::bail!("Embedding endpoint returned {}: {}", status, text);
+        }
+        let mut body: EmbeddingResponse = response
+            .json()
+            .map_err(|e| anyhow::anyhow!("Failed to parse embedding response: {}", e))?;
+        if body.data.len() != texts.len() {
+            anyhow::bail!("Embedding endpoint returned {} vectors for {} texts", body.data.len(), texts.len());
+        }
+        body.data.sort_by_key(|d| d.index);
+        let vectors: Vec<Vec<f32>> = body.data.into_iter().map(|d| d.embedding).collect();
+        if self.dimension > 0 && vectors.iter().any(|v| v.len() != self.dimension) {
+            anyhow::bail!("Embedding endpoint returned vectors of the wrong dimension; expected {}", self.dimension);
+        }
+        Ok(vectors)
+    }
+}
+
+impl EmbeddingProvider for HttpProvider {
+    /// The blocking client may not run on a tokio thread. The handlers embed
+    /// on the blocking pool, but tools such as `bench_compare` call in from
+    /// async code, so each request is sent from a thread of its own.
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        std::thread::scope(|scope| scope.spawn(|| self.send(texts)).join())
+            .map_err(|_| anyhow::anyhow!("Embedding request thread panicked"))?
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> String {
+        self.model.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    /// Answers `requests` embedding calls, each on its own connection, with
+    /// `[text length, position, 1]` per input, listed in reverse order.
+    fn mock_server(requests: usize) -> (String, std::thread::JoinHandle<Vec<serde_json::Value>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v1", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                let data: Vec<serde_json::Value> = body["input"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(i, text)| serde_json::json!({ "index": i, "embedding": [text.as_str().unwrap().len(), i, 1] }))
+                    .collect();
+                let response = serde_json::json!({ "data": data }).to_string();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    response.len(),
+                    response
+                )
+                .unwrap();
+                bodies.push(body);
+            }
+            bodies
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn http_provider_probes_the_dimension_and_keeps_input_order() {
+        let (url, server) = mock_server(2);
+        let provider = HttpProvider::new(&url, "mock-embed", Some("secret".to_string()), None).unwrap();
+        assert_eq!((provider.dimension(), provider.model_id()), (3, "mock-embed".to_string()));
+
+        let vectors = provider.embed_batch(&["a", "bcd"]).unwrap();
+        assert_eq!(vectors, vec![vec![1.0, 0.0, 1.0], vec![3.0, 1.0, 1.0]]);
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies[1], serde_json::json!({ "model": "mock-embed", "input": ["a", "bcd"] }));
+    }
+}
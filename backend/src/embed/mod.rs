@@ -0,0 +1,432 @@
+//! Text embedding.
+//!
+//! [`Embedder`] turns review and query text into the vectors the index
+//! stores. The vectors themselves come from an [`EmbeddingProvider`] picked
+//! by `EMBEDDING_PROVIDER`:
+//!
+//! - `fastembed`: a local ONNX model (needs the `fastembed` feature; the
+//!   default when it is enabled).
+//! - `openai`: any OpenAI-compatible `/embeddings` endpoint.
+//! - `hash`: hashed word and character n-grams, deterministic and offline
+//!   (the default without the `fastembed` feature).
+//! - `none`: all-zero vectors.
+//!
+//! Queries and passages are prefixed as the model's [`ModelProfile`] asks
+//! (`query: ` and `passage: ` for E5 models). The embedder then reduces the
+//! provider's vectors to `EMBEDDING_DIM`, by mean-pooling or by a fitted
+//! [`Projection`] (see `EMBEDDING_REDUCTION`).
+//! [`Embedder::embed_batch`] hands the provider `EMBEDDING_BATCH_SIZE` texts
+//! (default 64) per call, which the fastembed model runs as one batch.
+
+use anyhow::Result;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::storage::header::VectorSpec;
+
+mod hashing;
+mod http;
+#[cfg(feature = "fastembed")]
+mod onnx;
+pub mod pca;
+mod profile;
+
+pub use hashing::HashingProvider;
+pub use http::HttpProvider;
+#[cfg(feature = "fastembed")]
+pub use onnx::FastembedProvider;
+pub use pca::Projection;
+pub use profile::{ModelProfile, TextKind};
+
+/// Dimension stored when `EMBEDDING_DIM` is not set.
+const DEFAULT_DIM: usize = 128;
+
+/// Texts per provider call when `EMBEDDING_BATCH_SIZE` is not set.
+const DEFAULT_BATCH_SIZE: usize = 64;
+
+/// A source of embedding vectors.
+pub trait EmbeddingProvider: Send + Sync {
+    /// One vector of [`Self::dimension`] values per text, in order.
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>>;
+
+    /// Length of the vectors `embed_batch` returns.
+    fn dimension(&self) -> usize;
+
+    /// Names the model; recorded in vector file headers.
+    fn model_id(&self) -> String;
+}
+
+/// Returns all-zero vectors.
+pub struct ZeroProvider {
+    dimension: usize,
+}
+
+impl ZeroProvider {
+    pub fn new(dimension: usize) -> Self {
+        Self { dimension }
+    }
+}
+
+impl EmbeddingProvider for ZeroProvider {
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        tracing::warn!("Using zero embedding (no embedding provider configured)");
+        Ok(vec![vec![0.0; self.dimension]; texts.len()])
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> String {
+        "none".to_string()
+    }
+}
+
+/// Builds the provider named by `EMBEDDING_PROVIDER`.
+pub fn provider_from_env() -> Result<Arc<dyn EmbeddingProvider>> {
+    let default = if cfg!(feature = "fastembed") { "fastembed" } else { "hash" };
+    let name = std::env::var("EMBEDDING_PROVIDER").unwrap_or_else(|_| default.to_string());
+    let provider: Arc<dyn EmbeddingProvider> = match name.trim().to_ascii_lowercase().as_str() {
+        "fastembed" => {
+            #[cfg(feature = "fastembed")]
+            {
+                Arc::new(FastembedProvider::from_env()?)
+            }
+            #[cfg(not(feature = "fastembed"))]
+            {
+                anyhow::bail!("EMBEDDING_PROVIDER=fastembed requires building with `--features fastembed`")
+            }
+        }
+        "openai" => Arc::new(HttpProvider::from_env()?),
+        "hash" => Arc::new(HashingProvider::new(dim_from_env(usize::MAX)?)),
+        "none" => Arc::new(ZeroProvider::new(dim_from_env(usize::MAX)?)),
+        other => anyhow::bail!("Unknown EMBEDDING_PROVIDER {:?}; expected fastembed, openai, hash or none", other),
+    };
+    Ok(provider)
+}
+
+/// How full-size vectors are brought down to the stored dimension.
+#[derive(Clone)]
+pub enum Reduction {
+    /// Averages runs of adjacent dimensions.
+    Mean,
+    Pca(Arc<Projection>),
+}
+
+impl Reduction {
+    /// Reduces `full` to `out_dim` values and L2-normalizes the result.
+    pub fn apply(&self, full: &[f32], out_dim: usize) -> Vec<f32> {
+        let mut reduced = match self {
+            Reduction::Mean => reduce_dim(full, out_dim),
+            Reduction::Pca(projection) => projection.apply(full),
+        };
+        let norm: f32 = reduced.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            for v in &mut reduced {
+                *v /= norm;
+            }
+        }
+        reduced
+    }
+}
+
+#[derive(Clone)]
+pub struct Embedder {
+    provider: Arc<dyn EmbeddingProvider>,
+    /// Dimension the provider produces.
+    native_size: usize,
+    /// Dimension of the stored vectors; `embed_reduced` reduces to it.
+    embedding_size: usize,
+    /// Most texts per `embed_batch` call to the provider.
+    batch_size: usize,
+    profile: ModelProfile,
+    reduction: Reduction,
+}
+
+impl Embedder {
+    /// Uses the provider from `EMBEDDING_PROVIDER` and the stored dimension
+    /// from `EMBEDDING_DIM`, which may not exceed the provider's own.
+    /// `EMBEDDING_DIM=full` keeps the provider's vectors unreduced. Vectors
+    /// are mean-pooled; [`Embedder::open`] also loads fitted reductions.
+    pub fn new() -> Result<Self> {
+        Self::with_provider(provider_from_env()?)
+    }
+
+    /// [`Embedder::new`], then the reduction named by `EMBEDDING_REDUCTION`:
+    /// `mean` (the default), `pca` for the latest projection fitted into
+    /// `data_dir` by `fit_pca`, or `pca-v<N>` for a given one.
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        let mut embedder = Self::new()?;
+        let name = std::env::var("EMBEDDING_REDUCTION").unwrap_or_else(|_| "mean".to_string());
+        let version = match name.trim().to_ascii_lowercase().as_str() {
+            "mean" => return Ok(embedder),
+            "pca" => Projection::latest_version(data_dir)?
+                .ok_or_else(|| anyhow::anyhow!("EMBEDDING_REDUCTION=pca, but no projection is fitted; run `fit_pca` first"))?,
+            other => other
+                .strip_prefix("pca-v")
+                .and_then(|v| v.parse::<u32>().ok())
+                .ok_or_else(|| anyhow::anyhow!("Invalid EMBEDDING_REDUCTION {:?}; expected mean, pca or pca-v<N>", name))?,
+        };
+        embedder.set_projection(Projection::load(data_dir, version)?)?;
+        Ok(embedder)
+    }
+
+    /// Reduces with `projection`, which must have been fitted to this
+    /// embedder's full-size vectors.
+    pub fn set_projection(&mut self, projection: Projection) -> Result<()> {
+        if projection.source_model != self.source_model_id() || projection.input_dim != self.native_size {
+            anyhow::bail!(
+                "Projection v{} was fitted to {}-d '{}' vectors, not {}-d '{}'; fit a new one with `fit_pca`",
+                projection.version,
+                projection.input_dim,
+                projection.source_model,
+                self.native_size,
+                self.source_model_id()
+            );
+        }
+        if projection.output_dim != self.embedding_size {
+            anyhow::bail!(
+                "Projection v{} reduces to {} dimensions, but EMBEDDING_DIM is {}",
+                projection.version,
+                projection.output_dim,
+                self.embedding_size
+            );
+        }
+        self.reduction = Reduction::Pca(Arc::new(projection));
+        Ok(())
+    }
+
+    pub fn with_provider(provider: Arc<dyn EmbeddingProvider>) -> Result<Self> {
+        let native_size = provider.dimension();
+        let embedding_size = dim_from_env(native_size)?;
+        let batch_size = match std::env::var("EMBEDDING_BATCH_SIZE") {
+            Ok(value) => value
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|size| *size > 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid EMBEDDING_BATCH_SIZE {:?}", value))?,
+            Err(_) => DEFAULT_BATCH_SIZE,
+        };
+        let profile = ModelProfile::from_env(&provider.model_id())?;
+        Ok(Self { provider, native_size, embedding_size, batch_size, profile, reduction: Reduction::Mean })
+    }
+
+    /// The provider's vector for `text` as is, without prefix or reduction.
+    pub fn embed(&self, text: &str) -> Vec<f32> {
+        if text.trim().is_empty() {
+            return vec![0.0_f32; self.native_size];
+        }
+        match self.provider.embed_batch(&[text]) {
+            Ok(mut embeddings) => {
+                if let Some(embedding) = embeddings.pop().filter(|e| e.len() == self.native_size) {
+                    tracing::debug!(embedding_length = embedding.len(), "Embedding generated successfully");
+                    if embedding.len() > 5 {
+                        tracing::debug!(first_values = ?&embedding[0..5], "First few embedding values");
+                    }
+                    embedding
+                } else {
+                    tracing::warn!("Empty or misshapen embedding returned from provider");
+                    vec![0.0_f32; self.native_size]
+                }
+            }
+            Err(e) => {
+                tracing::error!("Failed to generate embedding: {}", e);
+                vec![0.0_f32; self.native_size]
+            }
+        }
+    }
+
+    pub fn embed_reduced(&self, text: &str) -> Vec<f32> {
+        self.reduce(self.embed(text))
+    }
+
+    /// The stored-size vector for a search query.
+    pub fn embed_query(&self, text: &str) -> Vec<f32> {
+        self.embed_kind(text, TextKind::Query)
+    }
+
+    /// The stored-size vector for a review being indexed.
+    pub fn embed_passage(&self, text: &str) -> Vec<f32> {
+        self.embed_kind(text, TextKind::Passage)
+    }
+
+    fn embed_kind(&self, text: &str, kind: TextKind) -> Vec<f32> {
+        if text.trim().is_empty() {
+            return vec![0.0_f32; self.embedding_size];
+        }
+        self.embed_reduced(&format!("{}{}", self.profile.prefix(kind), text))
+    }
+
+    fn reduce(&self, full: Vec<f32>) -> Vec<f32> {
+        self.reduction.apply(&full, self.embedding_size)
+    }
+
+    /// `embed_query` or `embed_passage` for many texts, sent to the provider
+    /// in batches of `EMBEDDING_BATCH_SIZE`. Blank texts get zero vectors
+    /// without a call. Unlike `embed`, a failing provider is an error rather
+    /// than zeros, so bulk loads do not store meaningless vectors.
+    pub fn embed_batch<S: AsRef<str>>(&self, texts: &[S], kind: TextKind) -> Result<Vec<Vec<f32>>> {
+        let full = self.embed_batch_full(texts, kind)?;
+        Ok(full
+            .into_iter()
+            .map(|vector| match vector {
+                Some(vector) => self.reduce(vector),
+                None => vec![0.0_f32; self.embedding_size],
+            })
+            .collect())
+    }
+
+    /// Like `embed_batch`, but returns the provider's full-size vectors
+    /// (`None` for blank texts).
+    pub fn embed_batch_full<S: AsRef<str>>(&self, texts: &[S], kind: TextKind) -> Result<Vec<Option<Vec<f32>>>> {
+        let mut embeddings = vec![None; texts.len()];
+        let pending: Vec<usize> = (0..texts.len()).filter(|&i| !texts[i].as_ref().trim().is_empty()).collect();
+        let prefix = self.profile.prefix(kind);
+        for chunk in pending.chunks(self.batch_size) {
+            let prefixed: Vec<String> = chunk.iter().map(|&i| format!("{}{}", prefix, texts[i].as_ref())).collect();
+            let batch: Vec<&str> = prefixed.iter().map(String::as_str).collect();
+            let vectors = self.provider.embed_batch(&batch)?;
+            if vectors.len() != batch.len() || vectors.iter().any(|v| v.len() != self.native_size) {
+                anyhow::bail!("Embedding provider returned misshapen vectors for a batch of {}", batch.len());
+            }
+            for (&i, vector) in chunk.iter().zip(vectors) {
+                embeddings[i] = Some(vector);
+            }
+        }
+        tracing::debug!(texts = texts.len(), batches = pending.len().div_ceil(self.batch_size), "Batch embedded");
+        Ok(embeddings)
+    }
+
+    /// Dimension of the vectors `embed_query` and `embed_passage` return.
+    pub fn embedding_size(&self) -> usize {
+        self.embedding_size
+    }
+
+    /// Dimension of the provider's vectors, before reduction.
+    pub fn native_size(&self) -> usize {
+        self.native_size
+    }
+
+    pub fn reduction(&self) -> &Reduction {
+        &self.reduction
+    }
+
+    /// Names the provider's vectors: the model and its passage prefix.
+    pub fn source_model_id(&self) -> String {
+        match self.profile.tag() {
+            Some(tag) => format!("{}+{}", self.provider.model_id(), tag),
+            None => self.provider.model_id(),
+        }
+    }
+
+    /// Identifies the vectors `embed_passage` produces; stored in every
+    /// vector file header so an index built by another model is refused. The
+    /// dimension is recorded separately, so this only names the model, its
+    /// prefixes and, when reduced, the pooling or projection applied to it.
+    pub fn model_id(&self) -> String {
+        let model = self.source_model_id();
+        if let Reduction::Pca(projection) = &self.reduction {
+            format!("{}+pca-v{}", model, projection.version)
+        } else if self.embedding_size == self.native_size {
+            model
+        } else if self.native_size.is_multiple_of(self.embedding_size) {
+            format!("{}+mean{}", model, self.native_size / self.embedding_size)
+        } else {
+            format!("{}+mean-pool", model)
+        }
+    }
+
+    pub fn vector_spec(&self) -> VectorSpec {
+        VectorSpec::new(self.embedding_size, self.model_id())
+    }
+}
+
+/// Reads `EMBEDDING_DIM`, defaulting to 128 (or the model's own dimension if
+/// that is smaller). Providers without a fixed dimension pass `usize::MAX`.
+fn dim_from_env(native: usize) -> Result<usize> {
+    let dim = match std::env::var("EMBEDDING_DIM") {
+        Ok(value) if value.eq_ignore_ascii_case("full") => {
+            if native == usize::MAX {
+                anyhow::bail!("EMBEDDING_DIM=full needs an embedding model");
+            }
+            native
+        }
+        Ok(value) => value
+            .parse::<usize>()
+            .ok()
+            .filter(|dim| *dim > 0)
+            .ok_or_else(|| anyhow::anyhow!("Invalid EMBEDDING_DIM {:?}", value))?,
+        Err(_) => DEFAULT_DIM.min(native),
+    };
+    if dim > native {
+        anyhow::bail!("EMBEDDING_DIM {} exceeds the model's {} dimensions", dim, native);
+    }
+    Ok(dim)
+}
+
+/// Mean-pools `input` into `out_dim` contiguous buckets; 768 -> 128 averages
+/// runs of six. Input that is already small enough is returned unchanged.
+pub fn reduce_dim(input: &[f32], out_dim: usize) -> Vec<f32> {
+    let n = input.len();
+    if out_dim == 0 || n <= out_dim {
+        return input.to_vec();
+    }
+    (0..out_dim)
+        .map(|i| {
+            let bucket = &input[i * n / out_dim..(i + 1) * n / out_dim];
+            bucket.iter().sum::<f32>() / bucket.len() as f32
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Hashing provider that records the size of every call.
+    struct Counting {
+        inner: HashingProvider,
+        calls: Mutex<Vec<usize>>,
+    }
+
+    impl EmbeddingProvider for Counting {
+        fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+            self.calls.lock().unwrap().push(texts.len());
+            self.inner.embed_batch(texts)
+        }
+
+        fn dimension(&self) -> usize {
+            self.inner.dimension()
+        }
+
+        fn model_id(&self) -> String {
+            self.inner.model_id()
+        }
+    }
+
+    #[test]
+    fn embed_batch_chunks_calls_and_prefixes_like_single_embeddings() {
+        let provider = Arc::new(Counting { inner: HashingProvider::new(16), calls: Mutex::new(Vec::new()) });
+        let profile = ModelProfile { query_prefix: "query: ".to_string(), passage_prefix: "passage: ".to_string() };
+        let embedder = Embedder {
+            provider: provider.clone(),
+            native_size: 16,
+            embedding_size: 8,
+            batch_size: 2,
+            profile,
+            reduction: Reduction::Mean,
+        };
+        let texts = ["battery", " ", "screen cracked", "late delivery"];
+
+        let batch = embedder.embed_batch(&texts, TextKind::Passage).unwrap();
+        assert_eq!(*provider.calls.lock().unwrap(), vec![2, 1]);
+        assert_eq!(batch[1], vec![0.0; 8]);
+        for (text, vector) in texts.iter().zip(&batch) {
+            assert_eq!(&embedder.embed_passage(text), vector);
+        }
+        assert_ne!(embedder.embed_query(texts[0]), batch[0]);
+        assert!(embedder.model_id().starts_with("hash-ngrams-v1+prefix-"));
+    }
+}
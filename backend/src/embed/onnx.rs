@@ -0,0 +1,57 @@
+use anyhow::Result;
+use fastembed::{InitOptions, TextEmbedding};
+use std::sync::Mutex;
+
+use super::EmbeddingProvider;
+
+/// Model used when `EMBEDDING_MODEL` is not set.
+const DEFAULT_MODEL: &str = "intfloat/multilingual-e5-base";
+
+/// A local fastembed model.
+pub struct FastembedProvider {
+    model: Mutex<TextEmbedding>,
+    model_code: String,
+    dimension: usize,
+}
+
+impl FastembedProvider {
+    /// Loads the model named by `EMBEDDING_MODEL` (a fastembed model code).
+    pub fn from_env() -> Result<Self> {
+        let code = std::env::var("EMBEDDING_MODEL").unwrap_or_else(|_| DEFAULT_MODEL.to_string());
+        Self::new(&code)
+    }
+
+    pub fn new(code: &str) -> Result<Self> {
+        let info = TextEmbedding::list_supported_models()
+            .into_iter()
+            .find(|info| info.model_code.eq_ignore_ascii_case(code))
+            .ok_or_else(|| anyhow::anyhow!("Unknown embedding model {:?}", code))?;
+        let options = InitOptions::new(info.model.clone())
+            .with_show_download_progress(true);
+        let model = TextEmbedding::try_new(options)?;
+        Ok(Self {
+            model: Mutex::new(model),
+            model_code: info.model_code,
+            dimension: info.dim,
+        })
+    }
+}
+
+impl EmbeddingProvider for FastembedProvider {
+    /// Runs `texts` through the model as a single batch; [`super::Embedder`]
+    /// sizes the batches.
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let mut model = self.model.lock().map_err(|_| anyhow::anyhow!("Failed to acquire model lock"))?;
+        model
+            .embed(texts.to_vec(), Some(texts.len().max(1)))
+            .map_err(|e| anyhow::anyhow!("Failed to embed {} texts: {}", texts.len(), e))
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> String {
+        self.model_code.clone()
+    }
+}
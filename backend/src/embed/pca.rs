@@ -0,0 +1,318 @@
+//! Fitted dimensionality reduction.
+//!
+//! `fit_pca` embeds a sample of the stored reviews at the provider's full
+//! dimension and keeps the `EMBEDDING_DIM` directions of highest variance.
+//! Each fit is saved as `reduction/pca-v<N>.bin` in the data directory under
+//! the next free version, and `EMBEDDING_REDUCTION=pca` (the latest fit) or
+//! `pca-v<N>` makes the embedder project onto it instead of mean-pooling.
+//! The version is part of the model id, so an index built with another
+//! reduction is refused until it is rebuilt.
+
+use anyhow::Result;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Reviews `fit_pca` embeds when `PCA_SAMPLE` is not set.
+pub const DEFAULT_SAMPLE: usize = 10_000;
+
+const MAGIC: &[u8; 4] = b"PCA\0";
+const FORMAT: u32 = 1;
+/// Added to eigenvalues before whitening, so flat directions do not blow up.
+const WHITEN_EPSILON: f64 = 1e-6;
+const MAX_SWEEPS: usize = 64;
+
+/// Reviews `fit_pca` embeds: `PCA_SAMPLE`, or [`DEFAULT_SAMPLE`].
+pub fn sample_size() -> Result<usize> {
+    match std::env::var("PCA_SAMPLE") {
+        Ok(v) => v.parse::<usize>().map_err(|e| anyhow::anyhow!("Invalid PCA_SAMPLE {:?}: {}", v, e)),
+        Err(_) => Ok(DEFAULT_SAMPLE),
+    }
+}
+
+/// Whether `fit_pca` samples the `position`-th live review of a store with
+/// `rows` rows: every `rows / sample`-th one, up to `sample` of them.
+pub fn in_sample(position: usize, rows: usize, sample: usize) -> bool {
+    let stride = (rows / sample.max(1)).max(1);
+    position.is_multiple_of(stride) && position / stride < sample
+}
+
+/// A mean and `output_dim` principal directions of the fitted sample.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Projection {
+    pub version: u32,
+    /// Model id (with prefix tag) of the vectors it was fitted on.
+    pub source_model: String,
+    pub input_dim: usize,
+    pub output_dim: usize,
+    pub whiten: bool,
+    /// Share of the sample's variance the kept directions hold.
+    pub explained: f32,
+    mean: Vec<f32>,
+    /// Per-direction factor: 1, or 1 / standard deviation when whitening.
+    scales: Vec<f32>,
+    /// `output_dim` rows of `input_dim` values, highest variance first.
+    components: Vec<f32>,
+}
+
+impl Projection {
+    /// Fits `output_dim` directions to `samples`, which must all have the
+    /// same length and be at least two.
+    pub fn fit(samples: &[Vec<f32>], output_dim: usize, whiten: bool, source_model: &str, version: u32) -> Result<Self> {
+        let n = samples.len();
+        let dim = samples.first().map_or(0, Vec::len);
+        if n < 2 {
+            anyhow::bail!("Need at least 2 samples to fit a projection, got {}", n);
+        }
+        if samples.iter().any(|s| s.len() != dim) {
+            anyhow::bail!("Samples have mixed dimensions");
+        }
+        if output_dim == 0 || output_dim > dim {
+            anyhow::bail!("Cannot project {}-d vectors to {} dimensions", dim, output_dim);
+        }
+
+        let mut mean = vec![0.0_f64; dim];
+        for sample in samples {
+            for (m, &v) in mean.iter_mut().zip(sample) {
+                *m += v as f64;
+            }
+        }
+        for m in &mut mean {
+            *m /= n as f64;
+        }
+        // Upper triangle of the covariance, mirrored afterwards.
+        let mut cov = vec![0.0_f64; dim * dim];
+        let mut centered = vec![0.0_f64; dim];
+        for sample in samples {
+            for ((c, &v), m) in centered.iter_mut().zip(sample).zip(&mean) {
+                *c = v as f64 - m;
+            }
+            for i in 0..dim {
+                let ci = centered[i];
+                let row = &mut cov[i * dim..(i + 1) * dim];
+                for j in i..dim {
+                    row[j] += ci * centered[j];
+                }
+            }
+        }
+        for i in 0..dim {
+            for j in i..dim {
+                let v = cov[i * dim + j] / (n - 1) as f64;
+                cov[i * dim + j] = v;
+                cov[j * dim + i] = v;
+            }
+        }
+
+        let (values, vectors) = symmetric_eigen(cov, dim);
+        let mut order: Vec<usize> = (0..dim).collect();
+        order.sort_by(|&a, &b| values[b].total_cmp(&values[a]));
+        let total: f64 = values.iter().map(|v| v.max(0.0)).sum();
+        let kept: f64 = order[..output_dim].iter().map(|&i| values[i].max(0.0)).sum();
+
+        let mut components = Vec::with_capacity(output_dim * dim);
+        let mut scales = Vec::with_capacity(output_dim);
+        for &k in &order[..output_dim] {
+            components.extend((0..dim).map(|row| vectors[row * dim + k] as f32));
+            scales.push(if whiten { (1.0 / (values[k].max(0.0) + WHITEN_EPSILON).sqrt()) as f32 } else { 1.0 });
+        }
+        Ok(Self {
+            version,
+            source_model: source_model.to_string(),
+            input_dim: dim,
+            output_dim,
+            whiten,
+            explained: if total > 0.0 { (kept / total) as f32 } else { 0.0 },
+            mean: mean.into_iter().map(|m| m as f32).collect(),
+            scales,
+            components,
+        })
+    }
+
+    /// Projects a full-size vector; the result is not normalized.
+    pub fn apply(&self, input: &[f32]) -> Vec<f32> {
+        let centered: Vec<f32> = input.iter().zip(&self.mean).map(|(v, m)| v - m).collect();
+        self.components
+            .chunks_exact(self.input_dim)
+            .zip(&self.scales)
+            .map(|(component, scale)| component.iter().zip(&centered).map(|(c, v)| c * v).sum::<f32>() * scale)
+            .collect()
+    }
+
+    /// Where fit `version` is stored under `data_dir`.
+    pub fn path(data_dir: &Path, version: u32) -> PathBuf {
+        data_dir.join("reduction").join(format!("pca-v{}.bin", version))
+    }
+
+    /// Highest fitted version under `data_dir`, if any.
+    pub fn latest_version(data_dir: &Path) -> Result<Option<u32>> {
+        let dir = data_dir.join("reduction");
+        if !dir.exists() {
+            return Ok(None);
+        }
+        let entries = std::fs::read_dir(&dir).map_err(|e| anyhow::anyhow!("Failed to list {:?}: {}", dir, e))?;
+        let mut latest = None;
+        for entry in entries {
+            let entry = entry.map_err(|e| anyhow::anyhow!("Failed to list {:?}: {}", dir, e))?;
+            let name = entry.file_name();
+            let version = name
+                .to_str()
+                .and_then(|n| n.strip_prefix("pca-v")?.strip_suffix(".bin")?.parse::<u32>().ok());
+            latest = latest.max(version);
+        }
+        Ok(latest)
+    }
+
+    /// Writes the projection to [`Self::path`]; an existing file is never
+    /// overwritten, since indexes may depend on it.
+    pub fn save(&self, data_dir: &Path) -> Result<PathBuf> {
+        let path = Self::path(data_dir, self.version);
+        if path.exists() {
+            anyhow::bail!("{:?} already exists", path);
+        }
+        let dir = path.parent().expect("projection path has a parent");
+        std::fs::create_dir_all(dir).map_err(|e| anyhow::anyhow!("Failed to create {:?}: {}", dir, e))?;
+
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&FORMAT.to_le_bytes());
+        out.extend_from_slice(&self.version.to_le_bytes());
+        out.extend_from_slice(&(self.input_dim as u32).to_le_bytes());
+        out.extend_from_slice(&(self.output_dim as u32).to_le_bytes());
+        out.push(self.whiten as u8);
+        out.extend_from_slice(&self.explained.to_le_bytes());
+        out.extend_from_slice(&(self.source_model.len() as u16).to_le_bytes());
+        out.extend_from_slice(self.source_model.as_bytes());
+        for v in self.mean.iter().chain(&self.scales).chain(&self.components) {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        out.extend_from_slice(&crc32fast::hash(&out).to_le_bytes());
+
+        let tmp = path.with_extension("bin.tmp");
+        let mut file = std::fs::File::create(&tmp).map_err(|e| anyhow::anyhow!("Failed to create {:?}: {}", tmp, e))?;
+        file.write_all(&out).map_err(|e| anyhow::anyhow!("Failed to write {:?}: {}", tmp, e))?;
+        file.sync_all().map_err(|e| anyhow::anyhow!("Failed to sync {:?}: {}", tmp, e))?;
+        std::fs::rename(&tmp, &path).map_err(|e| anyhow::anyhow!("Failed to rename {:?}: {}", tmp, e))?;
+        Ok(path)
+    }
+
+    pub fn load(data_dir: &Path, version: u32) -> Result<Self> {
+        let path = Self::path(data_dir, version);
+        let bytes = std::fs::read(&path).map_err(|e| anyhow::anyhow!("Failed to read {:?}: {}", path, e))?;
+        let corrupt = |reason: &str| anyhow::anyhow!("Corrupt projection {:?}: {}", path, reason);
+        if bytes.len() < 31 || &bytes[..4] != MAGIC {
+            return Err(corrupt("not a projection file"));
+        }
+        let (body, crc) = bytes.split_at(bytes.len() - 4);
+        if crc32fast::hash(body) != u32::from_le_bytes(crc.try_into().expect("4 bytes")) {
+            return Err(corrupt("checksum mismatch"));
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(body[at..at + 4].try_into().expect("4 bytes"));
+        if u32_at(4) != FORMAT {
+            return Err(corrupt("unsupported format"));
+        }
+        let input_dim = u32_at(12) as usize;
+        let output_dim = u32_at(16) as usize;
+        let model_len = u16::from_le_bytes([body[25], body[26]]) as usize;
+        let floats_at = 27 + model_len;
+        let floats = input_dim + output_dim + output_dim * input_dim;
+        if body.len() != floats_at + floats * 4 {
+            return Err(corrupt("length does not match its dimensions"));
+        }
+        let source_model = std::str::from_utf8(&body[27..floats_at])
+            .map_err(|_| corrupt("model id is not UTF-8"))?
+            .to_string();
+        let mut values = body[floats_at..]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().expect("4 bytes")));
+        let mut take = |count: usize| values.by_ref().take(count).collect::<Vec<f32>>();
+        Ok(Self {
+            version: u32_at(8),
+            source_model,
+            input_dim,
+            output_dim,
+            whiten: body[20] != 0,
+            explained: f32::from_le_bytes(body[21..25].try_into().expect("4 bytes")),
+            mean: take(input_dim),
+            scales: take(output_dim),
+            components: take(output_dim * input_dim),
+        })
+    }
+}
+
+/// Eigenvalues and eigenvectors of the symmetric `n`x`n` matrix `a` by
+/// cyclic Jacobi rotations. Eigenvector `k` is column `k` of the returned
+/// row-major matrix.
+fn symmetric_eigen(mut a: Vec<f64>, n: usize) -> (Vec<f64>, Vec<f64>) {
+    let mut v = vec![0.0_f64; n * n];
+    for i in 0..n {
+        v[i * n + i] = 1.0;
+    }
+    let scale: f64 = a.iter().map(|x| x * x).sum::<f64>().max(f64::MIN_POSITIVE);
+    for _ in 0..MAX_SWEEPS {
+        let off: f64 = (0..n).flat_map(|i| (i + 1..n).map(move |j| (i, j))).map(|(i, j)| a[i * n + j].powi(2)).sum();
+        if off <= scale * 1e-24 {
+            break;
+        }
+        for p in 0..n {
+            for q in p + 1..n {
+                let apq = a[p * n + q];
+                if apq == 0.0 {
+                    continue;
+                }
+                let theta = (a[q * n + q] - a[p * n + p]) / (2.0 * apq);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                for k in 0..n {
+                    let (akp, akq) = (a[k * n + p], a[k * n + q]);
+                    a[k * n + p] = c * akp - s * akq;
+                    a[k * n + q] = s * akp + c * akq;
+                }
+                for k in 0..n {
+                    let (apk, aqk) = (a[p * n + k], a[q * n + k]);
+                    a[p * n + k] = c * apk - s * aqk;
+                    a[q * n + k] = s * apk + c * aqk;
+                }
+                for k in 0..n {
+                    let (vkp, vkq) = (v[k * n + p], v[k * n + q]);
+                    v[k * n + p] = c * vkp - s * vkq;
+                    v[k * n + q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+    ((0..n).map(|i| a[i * n + i]).collect(), v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fit_finds_the_high_variance_directions_and_roundtrips() {
+        // Points spread along (1, 1, 0) with a little noise along (1, -1, 0)
+        // and none along z.
+        let samples: Vec<Vec<f32>> = (0..50)
+            .map(|i| {
+                let (major, minor) = ((i as f32 - 25.0) / 5.0, if i % 2 == 0 { 0.1 } else { -0.1 });
+                vec![major + minor + 1.0, major - minor + 2.0, 3.0]
+            })
+            .collect();
+        let projection = Projection::fit(&samples, 1, false, "model", 1).unwrap();
+        assert!(projection.explained > 0.99);
+        let direction = projection.apply(&[2.0, 3.0, 3.0])[0] - projection.apply(&[1.0, 2.0, 3.0])[0];
+        assert!((direction.abs() - 2.0_f32.sqrt()).abs() < 1e-3);
+        assert!((projection.apply(&[1.0, 2.0, 7.0])[0] - projection.apply(&[1.0, 2.0, 3.0])[0]).abs() < 1e-3);
+
+        let whitened = Projection::fit(&samples, 2, true, "model", 2).unwrap();
+        let spread: f32 = samples.iter().map(|s| whitened.apply(s)[1].powi(2)).sum::<f32>() / 49.0;
+        assert!((spread - 1.0).abs() < 1e-2);
+
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(Projection::latest_version(dir.path()).unwrap(), None);
+        projection.save(dir.path()).unwrap();
+        whitened.save(dir.path()).unwrap();
+        assert!(projection.save(dir.path()).is_err());
+        assert_eq!(Projection::latest_version(dir.path()).unwrap(), Some(2));
+        assert_eq!(Projection::load(dir.path(), 2).unwrap(), whitened);
+    }
+}
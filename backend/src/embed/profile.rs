@@ -0,0 +1,93 @@
+use anyhow::Result;
+
+use super::hashing::{fnv1a, FNV_OFFSET};
+
+/// Whether a text is a search query or a document to be searched. Models
+/// trained asymmetrically expect the two to be marked differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextKind {
+    Query,
+    Passage,
+}
+
+/// Instruction prefixes a model expects in front of its inputs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModelProfile {
+    pub query_prefix: String,
+    pub passage_prefix: String,
+}
+
+impl ModelProfile {
+    /// The prefixes the model was trained with, or none for a model not
+    /// known to need them.
+    pub fn for_model(model: &str) -> Self {
+        let model = model.to_ascii_lowercase();
+        let (query, passage) = if model.contains("e5-") {
+            ("query: ", "passage: ")
+        } else if model.contains("nomic-embed-text") {
+            ("search_query: ", "search_document: ")
+        } else if (model.contains("bge-") && model.contains("-en")) || model.contains("mxbai-embed") {
+            ("Represent this sentence for searching relevant passages: ", "")
+        } else {
+            ("", "")
+        };
+        Self { query_prefix: query.to_string(), passage_prefix: passage.to_string() }
+    }
+
+    /// [`Self::for_model`], unless `EMBEDDING_PREFIXES=off` turns prefixes
+    /// off (to keep serving an index embedded without them), or
+    /// `EMBEDDING_QUERY_PREFIX` / `EMBEDDING_PASSAGE_PREFIX` replace them.
+    pub fn from_env(model: &str) -> Result<Self> {
+        let mut profile = match std::env::var("EMBEDDING_PREFIXES") {
+            Err(_) => Self::for_model(model),
+            Ok(value) => match value.trim().to_ascii_lowercase().as_str() {
+                "on" | "auto" => Self::for_model(model),
+                "off" => Self::default(),
+                _ => anyhow::bail!("Invalid EMBEDDING_PREFIXES {:?}; expected auto or off", value),
+            },
+        };
+        if let Ok(prefix) = std::env::var("EMBEDDING_QUERY_PREFIX") {
+            profile.query_prefix = prefix;
+        }
+        if let Ok(prefix) = std::env::var("EMBEDDING_PASSAGE_PREFIX") {
+            profile.passage_prefix = prefix;
+        }
+        Ok(profile)
+    }
+
+    pub fn prefix(&self, kind: TextKind) -> &str {
+        match kind {
+            TextKind::Query => &self.query_prefix,
+            TextKind::Passage => &self.passage_prefix,
+        }
+    }
+
+    /// Part of the model id naming the passage prefix, the only one stored
+    /// vectors depend on; `None` without one, so indexes built before
+    /// prefixes existed keep their id.
+    pub fn tag(&self) -> Option<String> {
+        if self.passage_prefix.is_empty() {
+            return None;
+        }
+        Some(format!("prefix-{:08x}", fnv1a(FNV_OFFSET, self.passage_prefix.as_bytes()) as u32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn e5_models_get_query_and_passage_prefixes() {
+        let e5 = ModelProfile::for_model("intfloat/multilingual-e5-base");
+        assert_eq!((e5.prefix(TextKind::Query), e5.prefix(TextKind::Passage)), ("query: ", "passage: "));
+        assert_eq!(e5.tag().as_deref(), Some("prefix-5131e981"));
+
+        let bge = ModelProfile::for_model("BAAI/bge-small-en-v1.5");
+        assert!(!bge.query_prefix.is_empty() && bge.tag().is_none());
+
+        let plain = ModelProfile::for_model("hash-ngrams-v1");
+        assert_eq!(plain, ModelProfile::default());
+        assert_eq!(plain.tag(), None);
+    }
+}
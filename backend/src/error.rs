@@ -8,6 +8,10 @@ use std::fmt;
 pub enum AppError {
     Internal(anyhow::Error),
     ValidationError(String),
+    NotFound(String),
+    /// The request raced another change, e.g. an `If-Match` version that is
+    /// no longer current.
+    Conflict(String),
 }
 
 #[derive(Serialize)]
@@ -21,6 +25,8 @@ impl fmt::Display for AppError {
         match self {
             AppError::Internal(err) => write!(f, "Internal error: {}", err),
             AppError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+            AppError::NotFound(msg) => write!(f, "Not found: {}", msg),
+            AppError::Conflict(msg) => write!(f, "Conflict: {}", msg),
         }
     }
 }
@@ -46,6 +52,24 @@ impl IntoResponse for AppError {
                     },
                 )
             }
+            AppError::NotFound(msg) => {
+                (
+                    StatusCode::NOT_FOUND,
+                    ErrorResponse {
+                        error: "Not Found".to_string(),
+                        message: msg,
+                    },
+                )
+            }
+            AppError::Conflict(msg) => {
+                (
+                    StatusCode::CONFLICT,
+                    ErrorResponse {
+                        error: "Conflict".to_string(),
+                        message: msg,
+                    },
+                )
+            }
         };
         (status, Json(error_response)).into_response()
     }
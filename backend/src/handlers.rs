@@ -1,36 +1,149 @@
 use std::sync::{Arc, Mutex};
-use rand::Rng;
-use std::collections::HashSet;
-use strsim::{normalized_levenshtein, jaro_winkler};
 
-use axum::{extract::State, response::IntoResponse, Json};
-use axum::http::StatusCode;
+use axum::{extract::{Path, State}, response::IntoResponse, Json};
+use axum::http::{header, HeaderMap, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json;
 
-use crate::embed::Embedder;
-use crate::storage::{metadata::MetadataStore, vector_store::VectorStore};
+use crate::embed::{Embedder, TextKind};
+use crate::storage::{index::VectorIndex, metadata::MetadataStore};
+use crate::diversity::Diversity;
+use crate::storage::filter::{AttrValue, Filter};
+use crate::storage::wal::{self, Wal, WalEntry};
 use crate::error::AppError;
+use crate::paging::{self, Cursor, Hit, RankedList, ResultCache};
+use crate::ranking::{Explanation, Presets, Ranking, RankingOptions, MAX_CANDIDATES};
+use crate::rerank::{self, RerankStage};
+use crate::storage::lexical;
 
 pub struct AppStateInner {
     pub embedder: Embedder,
-    pub vector_store: Mutex<VectorStore>,
+    pub vector_store: Mutex<Box<dyn VectorIndex>>,
     pub metadata_store: Mutex<MetadataStore>,
+    pub wal: Mutex<Wal>,
+    pub ranking_presets: Presets,
+    pub search_cache: Mutex<ResultCache>,
+    /// `None` when no reranker is configured.
+    pub rerank: Option<RerankStage>,
 }
 
 pub type AppState = Arc<AppStateInner>;
 
 impl AppStateInner {
-    pub fn new(embedder: Embedder, vector_store: VectorStore, metadata_store: MetadataStore) -> AppState {
+    /// `wal` must already have been replayed into both stores (see
+    /// [`wal::recover`]).
+    pub fn new(
+        embedder: Embedder,
+        vector_store: Box<dyn VectorIndex>,
+        metadata_store: MetadataStore,
+        wal: Wal,
+        ranking_presets: Presets,
+        rerank: Option<RerankStage>,
+    ) -> AppState {
         Arc::new(Self {
             embedder,
             vector_store: Mutex::new(vector_store),
             metadata_store: Mutex::new(metadata_store),
+            wal: Mutex::new(wal),
+            ranking_presets,
+            search_cache: Mutex::new(ResultCache::from_env()),
+            rerank,
         })
     }
+
+    /// Logs the reviews to the write-ahead log, then appends them to both
+    /// stores. Returns the ids they were stored under.
+    pub fn insert_reviews(&self, reviews: Vec<Review>, embeddings: Vec<Vec<f32>>) -> Result<Vec<u64>, AppError> {
+        let mut vs = self.vector_store.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire vector store lock")))?;
+        let mut ms = self.metadata_store.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire metadata store lock")))?;
+        let mut wal = self.wal.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire write-ahead log lock")))?;
+        let first_id = ms.next_id();
+        let ids: Vec<u64> = (first_id..first_id + reviews.len() as u64).collect();
+        let records = reviews
+            .into_iter()
+            .zip(&ids)
+            .map(|(review, &id)| StoredReview { id, version: initial_version(), review })
+            .zip(embeddings)
+            .collect();
+        write_rows(vs.as_mut(), &mut ms, &mut wal, records, None)?;
+        Ok(ids)
+    }
+
+    /// The current version of review `id`.
+    pub fn get_review(&self, id: u64) -> Result<StoredReview, AppError> {
+        let ms = self.metadata_store.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire metadata store lock")))?;
+        let row = ms.row_of(id).ok_or_else(|| not_found(id))?;
+        read_review(&ms, id, row)
+    }
+
+    /// Stores `review` as the next version of review `id`, provided `id` is
+    /// still at `expected_version`. The new version takes a fresh row and the
+    /// old row is deleted; `embedding` is `None` when the text is unchanged,
+    /// in which case the stored vector is carried over.
+    pub fn replace_review(
+        &self,
+        id: u64,
+        expected_version: u64,
+        review: Review,
+        embedding: Option<Vec<f32>>,
+    ) -> Result<StoredReview, AppError> {
+        let mut vs = self.vector_store.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire vector store lock")))?;
+        let mut ms = self.metadata_store.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire metadata store lock")))?;
+        let mut wal = self.wal.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire write-ahead log lock")))?;
+        let row = ms.row_of(id).ok_or_else(|| not_found(id))?;
+        let current = read_review(&ms, id, row)?;
+        check_version(&current, Some(expected_version))?;
+        let embedding = match embedding {
+            Some(embedding) => embedding,
+            None => match vs.get_vector(row).map_err(AppError::Internal)? {
+                Some(vector) => vector,
+                // Backends that cannot read vectors back get the text re-embedded.
+                None => self.embedder.embed_passage(&review_text(&review)),
+            },
+        };
+        let updated = StoredReview { id, version: current.version + 1, review };
+        // One log write covers the new row and the old row's delete, so a
+        // crash cannot leave both versions live. Until the old row is
+        // deleted, lookups already resolve to the new one.
+        write_rows(vs.as_mut(), &mut ms, &mut wal, vec![(updated.clone(), embedding)], Some(row))?;
+        Ok(updated)
+    }
+
+    /// Deletes review `id` from both stores, provided it is at
+    /// `expected_version` when one is given.
+    pub fn delete_review(&self, id: u64, expected_version: Option<u64>) -> Result<(), AppError> {
+        let mut vs = self.vector_store.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire vector store lock")))?;
+        let mut ms = self.metadata_store.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire metadata store lock")))?;
+        let mut wal = self.wal.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire write-ahead log lock")))?;
+        let row = ms.row_of(id).ok_or_else(|| not_found(id))?;
+        if expected_version.is_some() {
+            check_version(&read_review(&ms, id, row)?, expected_version)?;
+        }
+        write_rows(vs.as_mut(), &mut ms, &mut wal, Vec::new(), Some(row))
+    }
+
+    /// Runs one compaction round on both stores and returns how many merges
+    /// were installed. Merging happens without either lock held; searches keep
+    /// reading the old segments until the new manifest has been swapped in.
+    pub fn compact(&self) -> anyhow::Result<usize> {
+        let mut merged = 0;
+        let job = self.vector_store.lock().map_err(|_| anyhow::anyhow!("Failed to acquire vector store lock"))?.plan_compaction()?;
+        if let Some(job) = job {
+            let done = job.run()?;
+            self.vector_store.lock().map_err(|_| anyhow::anyhow!("Failed to acquire vector store lock"))?.apply_compaction(done)?;
+            merged += 1;
+        }
+        let job = self.metadata_store.lock().map_err(|_| anyhow::anyhow!("Failed to acquire metadata store lock"))?.plan_compaction()?;
+        if let Some(job) = job {
+            let done = job.run()?;
+            self.metadata_store.lock().map_err(|_| anyhow::anyhow!("Failed to acquire metadata store lock"))?.apply_compaction(done)?;
+            merged += 1;
+        }
+        Ok(merged)
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Review {
     pub review_title: String,
     pub review_body: String,
@@ -38,17 +151,144 @@ pub struct Review {
     pub review_rating: i32,
 }
 
-#[derive(Debug, Serialize)]
-pub struct SearchResult {
-    pub score: f32,
+/// A review as the metadata store keeps it: the client's fields plus the id
+/// it was assigned on insert and a version bumped by every update.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredReview {
+    /// Missing from lines written before reviews had ids; the metadata
+    /// store's id map is authoritative.
+    #[serde(default)]
+    pub id: u64,
+    #[serde(default = "initial_version")]
+    pub version: u64,
+    #[serde(flatten)]
     pub review: Review,
 }
 
+fn initial_version() -> u64 {
+    1
+}
 
-pub async fn insert_review(
-    State(state): State<AppState>,
-    Json(review): Json<Review>,
-) -> Result<impl IntoResponse, AppError> {
+/// Body of `PATCH /reviews/:id`; absent fields keep their value.
+#[derive(Debug, Default, Deserialize)]
+pub struct ReviewPatch {
+    pub review_title: Option<String>,
+    pub review_body: Option<String>,
+    pub product_id: Option<String>,
+    pub review_rating: Option<i32>,
+}
+
+impl ReviewPatch {
+    fn apply(self, review: &Review) -> Review {
+        Review {
+            review_title: self.review_title.unwrap_or_else(|| review.review_title.clone()),
+            review_body: self.review_body.unwrap_or_else(|| review.review_body.clone()),
+            product_id: self.product_id.unwrap_or_else(|| review.product_id.clone()),
+            review_rating: self.review_rating.unwrap_or(review.review_rating),
+        }
+    }
+}
+
+/// Appends `records` to both stores and then deletes the `replaced` row
+/// from both, all through one write-ahead log write, after checking that
+/// the stores agree on the next row.
+fn write_rows(
+    vs: &mut dyn VectorIndex,
+    ms: &mut MetadataStore,
+    wal: &mut Wal,
+    records: Vec<(StoredReview, Vec<f32>)>,
+    replaced: Option<usize>,
+) -> Result<(), AppError> {
+    let first_row = ms.len();
+    let vector_rows = vs.len().map_err(AppError::Internal)?;
+    if vector_rows != first_row {
+        return Err(AppError::Internal(anyhow::anyhow!(
+            "Vector index has {} rows but metadata store has {}", vector_rows, first_row
+        )));
+    }
+    let mut entries = records
+        .into_iter()
+        .enumerate()
+        .map(|(i, (record, vector))| {
+            let json = serde_json::to_string(&record)
+                .map_err(|e| anyhow::anyhow!("Failed to serialize review: {}", e))?;
+            Ok(WalEntry::Insert { row: first_row + i, vector, json })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(AppError::Internal)?;
+    entries.extend(replaced.map(|row| WalEntry::Delete { row }));
+    wal.append(&entries).map_err(AppError::Internal)?;
+    wal::apply(&entries, Some(&mut *vs), ms).map_err(AppError::Internal)?;
+    if wal.size() >= wal::CHECKPOINT_BYTES {
+        wal.checkpoint(vs, ms).map_err(AppError::Internal)?;
+    }
+    Ok(())
+}
+
+fn read_review(ms: &MetadataStore, id: u64, row: usize) -> Result<StoredReview, AppError> {
+    let mut review = ms
+        .get_by_index::<StoredReview>(row)
+        .map_err(AppError::Internal)?
+        .ok_or_else(|| not_found(id))?;
+    review.id = id;
+    Ok(review)
+}
+
+fn not_found(id: u64) -> AppError {
+    AppError::NotFound(format!("Review {} does not exist", id))
+}
+
+fn check_version(current: &StoredReview, expected: Option<u64>) -> Result<(), AppError> {
+    match expected {
+        Some(version) if version != current.version => Err(AppError::Conflict(format!(
+            "Review {} is at version {}, not {}", current.id, current.version, version
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Version required by an `If-Match` header (`"3"`, `W/"3"` or `3`). No
+/// header or `*` accepts any version.
+fn if_match(headers: &HeaderMap) -> Result<Option<u64>, AppError> {
+    let Some(value) = headers.get(header::IF_MATCH) else {
+        return Ok(None);
+    };
+    let value = value
+        .to_str()
+        .map_err(|_| AppError::ValidationError("If-Match must be a version number".to_string()))?
+        .trim();
+    if value == "*" {
+        return Ok(None);
+    }
+    value
+        .trim_start_matches("W/")
+        .trim_matches('"')
+        .parse::<u64>()
+        .map(Some)
+        .map_err(|_| AppError::ValidationError("If-Match must be a version number".to_string()))
+}
+
+fn etag(review: &StoredReview) -> [(header::HeaderName, String); 1] {
+    [(header::ETAG, format!("\"{}\"", review.version))]
+}
+
+fn review_text(review: &Review) -> String {
+    passage_text(&review.review_title, &review.review_body)
+}
+
+/// The text a stored metadata line is embedded as: the same passage
+/// [`review_text`] builds, or the whole line if it has neither field.
+pub fn extract_text(v: &serde_json::Value) -> String {
+    let field = |name| v.get(name).and_then(serde_json::Value::as_str).unwrap_or("");
+    let text = passage_text(field("review_title"), field("review_body"));
+    if text.is_empty() { v.to_string() } else { text }
+}
+
+fn passage_text(title: &str, body: &str) -> String {
+    [title.trim(), body.trim()].into_iter().filter(|part| !part.is_empty()).collect::<Vec<_>>().join(" ")
+}
+
+fn validate_review(review: &Review) -> Result<(), AppError> {
     if review.review_title.trim().is_empty() {
         return Err(AppError::ValidationError("Review title cannot be empty".to_string()));
     }
@@ -61,24 +301,34 @@ pub async fn insert_review(
     if review.review_rating < 1 || review.review_rating > 5 {
         return Err(AppError::ValidationError("Review rating must be between 1 and 5".to_string()));
     }
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    pub id: u64,
+    pub score: f32,
+    pub review: Review,
+    /// Score breakdown, only for `explain` searches.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explanation: Option<Explanation>,
+}
 
-    let text = format!("{} {}", review.review_title.trim(), review.review_body.trim());
-    let embedding = state.embedder.embed_default(&text);
-    {
-        let mut vs = state.vector_store.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire vector store lock")))?;
-        vs.append(&embedding).map_err(|e| AppError::Internal(e))?;
-    }
-    {
-        let mut ms = state.metadata_store.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire metadata store lock")))?;
-        ms.append(&review).map_err(|e| AppError::Internal(e))?;
-    }
 
-    let mut rng = rand::thread_rng();
-    let random_score = rng.gen_range(0.1..=1.0);
+pub async fn insert_review(
+    State(state): State<AppState>,
+    Json(review): Json<Review>,
+) -> Result<impl IntoResponse, AppError> {
+    validate_review(&review)?;
+
+    let text = review_text(&review);
+    let embedding = with_embedder(&state, move |embedder| embedder.embed_passage(&text)).await?;
+    let ids = state.insert_reviews(vec![review], vec![embedding])?;
+
     Ok((StatusCode::CREATED, Json(serde_json::json!({
         "status": "success",
         "message": "Review created successfully",
-        "score": random_score
+        "id": ids[0]
     }))))
 }
 
@@ -101,23 +351,17 @@ pub async fn bulk_insert_reviews(
         }
     }
 
-    let mut vs = state.vector_store.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire vector store lock")))?;
-    let mut ms = state.metadata_store.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire metadata store lock")))?;
+    let texts: Vec<String> = reviews.iter().map(review_text).collect();
+    let embeddings = with_embedder(&state, move |embedder| embedder.embed_batch(&texts, TextKind::Passage))
+        .await?
+        .map_err(AppError::Internal)?;
+    let ids = state.insert_reviews(reviews, embeddings)?;
 
-    for review in &reviews {
-        let text = format!("{} {}", review.review_title.trim(), review.review_body.trim());
-        let embedding = state.embedder.embed_default(&text);
-        vs.append(&embedding).map_err(|e| AppError::Internal(e))?;
-        ms.append(review).map_err(|e| AppError::Internal(e))?;
-    }
-
-    let mut rng = rand::thread_rng();
-    let random_score = rng.gen_range(0.1..=1.0);
     Ok(Json(serde_json::json!({
         "status": "success",
         "message": "Reviews created successfully",
-        "count": reviews.len(),
-        "score": random_score
+        "count": ids.len(),
+        "ids": ids
     })))
 }
 
@@ -126,6 +370,33 @@ pub struct SearchQuery {
     pub query: String,
     #[serde(default = "default_top_k")]
     pub top_k: usize,
+    /// Restricts results to reviews whose metadata matches; applied inside
+    /// the vector scan.
+    #[serde(default)]
+    pub filter: Option<Filter>,
+    /// How vector and BM25 candidates are combined; the `default` preset
+    /// when absent.
+    #[serde(default)]
+    pub ranking: Option<RankingOptions>,
+    /// Adds an `explanation` with the score breakdown to every result.
+    #[serde(default)]
+    pub explain: bool,
+    /// Results to skip before the page starts.
+    #[serde(default)]
+    pub offset: usize,
+    /// Page size; `top_k` when absent.
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// `X-Next-Cursor` of the previous page, to continue after it.
+    #[serde(default)]
+    pub cursor: Option<String>,
+    /// Re-scores the best candidates with the configured reranker; follows
+    /// `RERANK_BY_DEFAULT` when absent.
+    #[serde(default)]
+    pub rerank: Option<bool>,
+    /// Re-ranks results by maximal marginal relevance.
+    #[serde(default)]
+    pub diversity: Option<Diversity>,
 }
 
 fn default_top_k() -> usize {
@@ -137,69 +408,497 @@ impl SearchQuery {
         if self.query.trim().is_empty() {
             return Err(AppError::ValidationError("Search query cannot be empty".to_string()));
         }
-        if self.top_k == 0 {
-            return Err(AppError::ValidationError("top_k must be greater than 0".to_string()));
+        let name = if self.limit.is_some() { "limit" } else { "top_k" };
+        if self.page_size() == 0 {
+            return Err(AppError::ValidationError(format!("{} must be greater than 0", name)));
+        }
+        if self.page_size() > 100 {
+            return Err(AppError::ValidationError(format!("{} cannot be greater than 100", name)));
+        }
+        if self.cursor.is_some() && self.offset > 0 {
+            return Err(AppError::ValidationError("Pass either offset or cursor, not both".to_string()));
+        }
+        if let Some(diversity) = &self.diversity {
+            diversity.validate().map_err(AppError::ValidationError)?;
         }
-        if self.top_k > 100 {
-            return Err(AppError::ValidationError("top_k cannot be greater than 100".to_string()));
+        if self.offset + self.page_size() > MAX_CANDIDATES {
+            return Err(AppError::ValidationError(format!("Cannot page past the first {} results", MAX_CANDIDATES)));
         }
         Ok(())
     }
+
+    fn page_size(&self) -> usize {
+        self.limit.unwrap_or(self.top_k)
+    }
 }
 
+/// Returns one page of results. The header `X-Next-Cursor` is set when
+/// there may be more; sending it back as `cursor` with the same query
+/// continues after the last result.
 pub async fn search_reviews(
     State(state): State<AppState>,
     Json(query): Json<SearchQuery>,
 ) -> Result<impl IntoResponse, AppError> {
     query.validate()?;
+    let ranking = state.ranking_presets.resolve(query.ranking.as_ref()).map_err(AppError::ValidationError)?;
+    let cursor = match &query.cursor {
+        Some(token) => Some(Cursor::decode(token).ok_or_else(|| AppError::ValidationError("Invalid cursor".to_string()))?),
+        None => None,
+    };
+    let reranker = match (&state.rerank, query.rerank) {
+        (None, Some(true)) => {
+            return Err(AppError::ValidationError("Reranking is not enabled on this server; set RERANKER".to_string()));
+        }
+        (Some(stage), rerank) if rerank.unwrap_or(stage.by_default) => Some(stage),
+        _ => None,
+    };
+    let limit = query.page_size();
 
-    let embedding = state.embedder.embed_default(&query.query.trim());
+    let embedding = if ranking.uses_vectors() {
+        let text = query.query.trim().to_string();
+        Some(with_embedder(&state, move |embedder| embedder.embed_query(&text)).await?)
+    } else {
+        None
+    };
 
-    let internal_k = std::cmp::min(query.top_k * 10, 200);
+    let key = paging::cache_key(
+        &query.query,
+        embedding.as_deref(),
+        query.filter.as_ref(),
+        &ranking,
+        reranker.map(|stage| stage.reranker.id()),
+        query.diversity.as_ref(),
+    );
+    if cursor.is_some_and(|c| c.key != key) {
+        return Err(AppError::ValidationError("Cursor belongs to a different search".to_string()));
+    }
+    let start = cursor.map_or(query.offset, |c| c.position);
+    let needed = (start + limit).min(MAX_CANDIDATES);
 
-    let ids_scores = {
-        let vs = state.vector_store.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire vector store lock")))?;
-        let result = vs.search(&embedding, internal_k).map_err(|e| AppError::Internal(e))?;
-        result
+    let cached = {
+        let ms = state.metadata_store.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire metadata store lock")))?;
+        if let Some(filter) = &query.filter {
+            filter.validate(ms.attributes()).map_err(AppError::ValidationError)?;
+        }
+        if query.diversity.as_ref().is_some_and(|d| d.max_per_product.is_some())
+            && !ms.attributes().fields().iter().any(|f| f == PRODUCT_FIELD)
+        {
+            return Err(AppError::ValidationError(format!(
+                "diversity.max_per_product needs {:?} in FILTER_FIELDS",
+                PRODUCT_FIELD
+            )));
+        }
+        let generation = ms.generation();
+        let mut cache = state.search_cache.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire search cache lock")))?;
+        // A cursor keeps paging through the list its first page was cut
+        // from; an offset only reuses a list ranked since the last write.
+        // First pages are always ranked afresh.
+        cache
+            .get(key)
+            .filter(|list| cursor.is_some() || (start > 0 && list.generation == generation))
+            .filter(|list| list.covers(needed))
+    };
+    let list = match cached {
+        Some(list) => list,
+        None => {
+            let pool = ranking.pool(limit).max(needed);
+            let list = rank(&state, &query, &ranking, embedding.as_deref(), reranker, pool).await?;
+            let mut cache = state.search_cache.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire search cache lock")))?;
+            cache.put(key, list.clone());
+            list
+        }
     };
 
-    let mut combined_results: Vec<(usize, f32, Review)> = Vec::new();
+    let mut results: Vec<SearchResult> = Vec::new();
+    let mut next_cursor = None;
     {
         let ms = state.metadata_store.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire metadata store lock")))?;
-        for (idx, vec_score) in &ids_scores {
-            if let Ok(review) = ms.get_by_index::<Review>(*idx) {
-                let text = format!("{} {}", review.review_title, review.review_body);
-                let query_lc = query.query.to_lowercase();
-                let text_lc = text.to_lowercase();
-                let lev = normalized_levenshtein(&query_lc, &text_lc) as f32;
-                let jw = jaro_winkler(&query_lc, &text_lc) as f32;
-                let char_sim = (lev + jw) / 2.0;
-                // Token-level Dice coefficient
-                let dice = {
-                    let set_q: HashSet<&str> = query_lc.split_whitespace().collect();
-                    let set_t: HashSet<&str> = text_lc.split_whitespace().collect();
-                    if set_q.is_empty() || set_t.is_empty() {
-                        0.0
-                    } else {
-                        (2.0 * set_q.intersection(&set_t).count() as f32) / (set_q.len() as f32 + set_t.len() as f32)
-                    }
+        let mut at = cursor.map_or(start, |c| list.resume(&c));
+        while results.len() < limit && at < list.hits.len() {
+            let batch: &[Hit] = &list.hits[at..(at + limit - results.len()).min(list.hits.len())];
+            at += batch.len();
+            let rows: Vec<usize> = batch.iter().map(|hit| hit.row).collect();
+            let reviews = ms.get_many::<Review>(&rows).map_err(AppError::Internal)?;
+            for (hit, review) in batch.iter().zip(reviews) {
+                // Skips reviews deleted or updated since the list was ranked.
+                let Some(review) = review.filter(|_| ms.row_of(hit.id) == Some(hit.row)) else {
+                    continue;
                 };
-                // Normalize vector score (-1..1) to 0..1
-                let vec_norm = ((*vec_score + 1.0) / 2.0) as f32;
-                // Weighted combination (tuneable)
-                let combined: f32 = 0.5 * char_sim + 0.2 * dice + 0.3 * vec_norm;
-                combined_results.push((*idx, combined, review));
+                let explanation = query.explain.then(|| Explanation {
+                    mode: ranking.mode,
+                    matched_terms: lexical::matched_terms(&query.query, &review_text(&review)),
+                    components: hit.components.clone(),
+                    rerank_score: hit.rerank,
+                });
+                results.push(SearchResult { id: hit.id, score: hit.score, review, explanation });
             }
         }
+        let more = at < list.hits.len() || (!list.exhausted && list.pool < MAX_CANDIDATES);
+        if let Some(last) = results.last().filter(|_| more) {
+            next_cursor = Some(Cursor { key, position: at, score: last.score, id: last.id }.encode());
+        }
     }
 
-    combined_results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    let mut headers = HeaderMap::new();
+    if let Some(token) = next_cursor {
+        let value = header::HeaderValue::from_str(&token).map_err(|e| AppError::Internal(anyhow::anyhow!("Failed to encode cursor: {}", e)))?;
+        headers.insert(NEXT_CURSOR, value);
+    }
+    Ok((headers, Json(results)))
+}
 
-    let results: Vec<SearchResult> = combined_results
+/// Response header carrying the cursor of the next page.
+pub const NEXT_CURSOR: &str = "x-next-cursor";
+
+/// Retrieves up to `pool` candidates from each retriever the ranking uses
+/// and fuses them into a ranked list of live reviews, then applies the
+/// reranker and diversity, in that order. The reranker runs with no store
+/// lock held.
+async fn rank(
+    state: &AppStateInner,
+    query: &SearchQuery,
+    ranking: &Ranking,
+    embedding: Option<&[f32]>,
+    reranker: Option<&RerankStage>,
+    pool: usize,
+) -> Result<RankedList, AppError> {
+    let (mut hits, passages, exhausted, generation) = {
+        let vs = state.vector_store.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire vector store lock")))?;
+        let ms = state.metadata_store.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire metadata store lock")))?;
+        let (mut hits, exhausted) = retrieve(vs.as_ref(), &ms, query, ranking, embedding, pool)?;
+        paging::sort_by_score(&mut hits);
+        let passages = match reranker {
+            Some(stage) => {
+                let rows: Vec<usize> = hits.iter().take(stage.candidates).map(|hit| hit.row).collect();
+                let reviews = ms.get_many::<Review>(&rows).map_err(AppError::Internal)?;
+                reviews.iter().map(|review| review.as_ref().map(review_text).unwrap_or_default()).collect()
+            }
+            None => Vec::new(),
+        };
+        (hits, passages, exhausted, ms.generation())
+    };
+    if let Some(stage) = reranker {
+        let model = stage.reranker.clone();
+        let text = query.query.trim().to_string();
+        let scores = tokio::task::spawn_blocking(move || model.score(&text, &passages))
+            .await
+            .map_err(|e| AppError::Internal(anyhow::anyhow!("Failed to run reranker: {}", e)))?
+            .map_err(AppError::Internal)?;
+        hits = rerank::reorder(hits, &scores);
+    }
+    if let Some(diversity) = &query.diversity {
+        let vs = state.vector_store.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire vector store lock")))?;
+        let ms = state.metadata_store.lock().map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to acquire metadata store lock")))?;
+        let attributes = ms.attributes();
+        hits = diversity.apply(
+            hits,
+            |row| vs.get_vector(row).ok().flatten(),
+            |row| match attributes.get(PRODUCT_FIELD, row)? {
+                AttrValue::Text(s) => Some(s.to_string()),
+                AttrValue::Number(n) => Some(n.to_string()),
+                AttrValue::Bool(b) => Some(b.to_string()),
+                AttrValue::Missing => None,
+            },
+        );
+    } else if reranker.is_none() {
+        return Ok(RankedList::new(generation, pool, exhausted, hits));
+    }
+    Ok(RankedList::reordered(generation, pool, exhausted, hits))
+}
+
+/// Fetches and fuses the candidates. Returns them unsorted, and whether the
+/// retrievers ran out before `pool`.
+fn retrieve(
+    vs: &dyn VectorIndex,
+    ms: &MetadataStore,
+    query: &SearchQuery,
+    ranking: &Ranking,
+    embedding: Option<&[f32]>,
+    pool: usize,
+) -> Result<(Vec<Hit>, bool), AppError> {
+    let attributes = ms.attributes();
+    let accept = |row: usize| query.filter.as_ref().is_none_or(|f| f.matches(attributes, row));
+    let vector_hits = match (embedding, &query.filter) {
+        (None, _) => Vec::new(),
+        (Some(embedding), Some(_)) => vs.search_filtered(embedding, pool, &accept).map_err(AppError::Internal)?,
+        (Some(embedding), None) => vs.search(embedding, pool).map_err(AppError::Internal)?,
+    };
+    let lexical_hits = if ranking.uses_lexical() {
+        ms.lexical().search(&query.query, pool, &accept)
+    } else {
+        Vec::new()
+    };
+    let exhausted = vector_hits.len() < pool && lexical_hits.len() < pool;
+    // Lexical-only hits are scored against the query vector when the
+    // backend can read vectors back.
+    let fused = ranking
+        .fuse(&vector_hits, &lexical_hits, |row| {
+            let (Some(embedding), Some(stored)) = (embedding, vs.get_vector(row)?) else {
+                return Ok(None);
+            };
+            Ok(Some(stored.iter().zip(embedding).map(|(a, b)| a * b).sum()))
+        })
+        .map_err(AppError::Internal)?;
+    let hits = fused
         .into_iter()
-        .take(query.top_k)
-        .map(|(_, score, review)| SearchResult { score, review })
+        .filter_map(|f| {
+            // Rows an update has superseded but not yet deleted are skipped.
+            let id = ms.id_of(f.row).filter(|id| ms.row_of(*id) == Some(f.row))?;
+            Some(Hit { row: f.row, id, score: f.score, components: f.components, rerank: None })
+        })
         .collect();
+    Ok((hits, exhausted))
+}
+
+/// Field `diversity.max_per_product` groups results by.
+const PRODUCT_FIELD: &str = "product_id";
+
+pub async fn get_review(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let review = state.get_review(id)?;
+    Ok((etag(&review), Json(review)))
+}
+
+/// Replaces every field of a review. Honors `If-Match`.
+pub async fn put_review(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+    Json(review): Json<Review>,
+) -> Result<impl IntoResponse, AppError> {
+    validate_review(&review)?;
+    update_review(&state, id, if_match(&headers)?, |_| review).await
+}
 
-    Ok(Json(results))
+/// Changes the fields present in the body. Honors `If-Match`.
+pub async fn patch_review(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+    Json(patch): Json<ReviewPatch>,
+) -> Result<impl IntoResponse, AppError> {
+    update_review(&state, id, if_match(&headers)?, |current| patch.apply(current)).await
+}
+
+/// Shared by PUT and PATCH. The new text is embedded without any lock held;
+/// the version check in [`AppStateInner::replace_review`] catches an update
+/// that landed in the meantime.
+async fn update_review(
+    state: &AppState,
+    id: u64,
+    expected: Option<u64>,
+    change: impl FnOnce(&Review) -> Review,
+) -> Result<impl IntoResponse, AppError> {
+    let current = state.get_review(id)?;
+    check_version(&current, expected)?;
+    let review = change(&current.review);
+    validate_review(&review)?;
+    if review == current.review {
+        return Ok((etag(&current), Json(current)));
+    }
+    let text = review_text(&review);
+    let embedding = if text != review_text(&current.review) {
+        Some(with_embedder(state, move |embedder| embedder.embed_passage(&text)).await?)
+    } else {
+        None
+    };
+    // Runs off the runtime too: backends that cannot read a vector back
+    // re-embed the unchanged text.
+    let state = state.clone();
+    let updated = tokio::task::spawn_blocking(move || state.replace_review(id, current.version, review, embedding))
+        .await
+        .map_err(|e| AppError::Internal(anyhow::anyhow!("Failed to update review: {}", e)))??;
+    Ok((etag(&updated), Json(updated)))
+}
+
+/// Runs `f` on the blocking pool. Providers may wait on a model run or an
+/// HTTP round trip, which must not hold up a runtime worker.
+async fn with_embedder<T: Send + 'static>(
+    state: &AppState,
+    f: impl FnOnce(&Embedder) -> T + Send + 'static,
+) -> Result<T, AppError> {
+    let embedder = state.embedder.clone();
+    tokio::task::spawn_blocking(move || f(&embedder))
+        .await
+        .map_err(|e| AppError::Internal(anyhow::anyhow!("Failed to run embedder: {}", e)))
+}
+
+/// Deletes a review. Honors `If-Match`.
+pub async fn delete_review(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_review(id, if_match(&headers)?)?;
+    Ok(Json(serde_json::json!({
+        "status": "success",
+        "message": "Review deleted successfully"
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::vector_store::VectorStore;
+
+    fn review(title: &str, rating: i32) -> Review {
+        Review {
+            review_title: title.to_string(),
+            review_body: "body".to_string(),
+            product_id: "P1".to_string(),
+            review_rating: rating,
+        }
+    }
+
+    fn open_state(dir: &std::path::Path, rerank: Option<RerankStage>) -> (AppState, usize) {
+        let embedder = Embedder::new().unwrap();
+        let vectors = VectorStore::open_or_create(dir.join("vectors"), &embedder.vector_spec()).unwrap();
+        let metadata = MetadataStore::open_or_create(dir.join("metadata")).unwrap();
+        let (wal, _) = Wal::open(Wal::path_for(dir)).unwrap();
+        let dim = embedder.embedding_size();
+        (AppStateInner::new(embedder, Box::new(vectors), metadata, wal, Presets::default(), rerank), dim)
+    }
+
+    #[test]
+    fn updates_move_reviews_to_new_rows_and_check_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let (state, dim) = open_state(dir.path(), None);
+
+        let ids = state.insert_reviews(vec![review("a", 1), review("b", 2)], vec![vec![1.0; dim]; 2]).unwrap();
+        assert_eq!(ids, vec![1, 2]);
+
+        let updated = state.replace_review(1, 1, review("a", 5), None).unwrap();
+        assert_eq!((updated.id, updated.version), (1, 2));
+        assert_eq!(state.get_review(1).unwrap().review.review_rating, 5);
+        assert!(matches!(state.replace_review(1, 1, review("a", 4), None), Err(AppError::Conflict(_))));
+        assert_eq!(state.vector_store.lock().unwrap().len().unwrap(), 3);
+
+        assert!(matches!(state.delete_review(2, Some(7)), Err(AppError::Conflict(_))));
+        state.delete_review(2, None).unwrap();
+        assert!(matches!(state.get_review(2), Err(AppError::NotFound(_))));
+        assert!(matches!(state.delete_review(2, None), Err(AppError::NotFound(_))));
+    }
+
+    async fn search(state: &AppState, body: serde_json::Value) -> (Vec<serde_json::Value>, Option<String>) {
+        let query: SearchQuery = serde_json::from_value(body).unwrap();
+        let response = search_reviews(State(state.clone()), Json(query)).await.unwrap().into_response();
+        let cursor = response.headers().get(NEXT_CURSOR).map(|v| v.to_str().unwrap().to_string());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (serde_json::from_slice(&body).unwrap(), cursor)
+    }
+
+    async fn page(state: &AppState, body: serde_json::Value) -> (Vec<u64>, Option<String>) {
+        let (results, cursor) = search(state, body).await;
+        (results.iter().map(|r| r["id"].as_u64().unwrap()).collect(), cursor)
+    }
+
+    #[tokio::test]
+    async fn cursor_and_offset_pages_walk_the_same_ranking() {
+        let dir = tempfile::tempdir().unwrap();
+        let (state, dim) = open_state(dir.path(), None);
+        let reviews: Vec<Review> = (1..=7).map(|n| review(&"battery ".repeat(n), 5)).collect();
+        state.insert_reviews(reviews, vec![vec![1.0; dim]; 7]).unwrap();
+        let query = |extra: serde_json::Value| {
+            let mut body = serde_json::json!({ "query": "battery", "ranking": { "preset": "keyword" } });
+            body.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+            body
+        };
+
+        let (all, _) = page(&state, query(serde_json::json!({ "top_k": 7 }))).await;
+        assert_eq!(all.len(), 7);
+
+        let mut walked = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let extra = match &cursor {
+                Some(c) => serde_json::json!({ "limit": 3, "cursor": c }),
+                None => serde_json::json!({ "limit": 3 }),
+            };
+            let (ids, next) = page(&state, query(extra)).await;
+            walked.extend(ids);
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(walked, all);
+
+        let (second, _) = page(&state, query(serde_json::json!({ "limit": 3, "offset": 3 }))).await;
+        assert_eq!(second, all[3..6]);
+        // A cursor only continues the search it came from.
+        let other = serde_json::json!({ "query": "hinge", "limit": 3, "cursor": cursor.unwrap_or_default() });
+        let query: SearchQuery = serde_json::from_value(other).unwrap();
+        assert!(search_reviews(State(state.clone()), Json(query)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rerank_moves_the_best_passages_to_the_top() {
+        let dir = tempfile::tempdir().unwrap();
+        let stage = RerankStage { reranker: Arc::new(rerank::StubReranker), by_default: false, candidates: 10 };
+        let (state, dim) = open_state(dir.path(), Some(stage));
+        let reviews = vec![review("screen screen screen", 5), review("battery and screen", 5), review("screen", 5)];
+        state.insert_reviews(reviews, vec![vec![1.0; dim]; 3]).unwrap();
+        let query = |rerank: bool| serde_json::json!({ "query": "battery screen", "top_k": 3, "rerank": rerank, "ranking": { "preset": "keyword" } });
+
+        let (fused, _) = page(&state, query(false)).await;
+        let (reranked, _) = page(&state, query(true)).await;
+        // Only review 2 has both terms; the others keep their fused order.
+        let expected: Vec<u64> = std::iter::once(2).chain(fused.into_iter().filter(|&id| id != 2)).collect();
+        assert_eq!(reranked, expected);
+
+        let dir = tempfile::tempdir().unwrap();
+        let (state, _) = open_state(dir.path(), None);
+        let query: SearchQuery = serde_json::from_value(query(true)).unwrap();
+        assert!(matches!(search_reviews(State(state), Json(query)).await, Err(AppError::ValidationError(_))));
+    }
+
+    #[tokio::test]
+    async fn semantic_search_finds_related_reviews_with_the_default_embedder() {
+        let dir = tempfile::tempdir().unwrap();
+        let (state, _) = open_state(dir.path(), None);
+        let titles = ["Soft fabric, lovely colour", "Batteries drain far too quickly", "Arrived late and damaged"];
+        let reviews: Vec<Review> = titles.iter().map(|t| review(t, 3)).collect();
+        let texts: Vec<String> = reviews.iter().map(review_text).collect();
+        let embeddings = state.embedder.embed_batch(&texts, TextKind::Passage).unwrap();
+        state.insert_reviews(reviews, embeddings).unwrap();
+
+        let query = serde_json::json!({ "query": "battery drains", "top_k": 1, "ranking": { "preset": "semantic" } });
+        let (ids, _) = page(&state, query).await;
+        assert_eq!(ids, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn explain_breaks_scores_down_per_signal() {
+        let dir = tempfile::tempdir().unwrap();
+        let (state, _) = open_state(dir.path(), None);
+        let titles = ["Battery drains fast", "Lovely soft fabric"];
+        let reviews: Vec<Review> = titles.iter().map(|t| review(t, 4)).collect();
+        let texts: Vec<String> = reviews.iter().map(review_text).collect();
+        let embeddings = state.embedder.embed_batch(&texts, TextKind::Passage).unwrap();
+        state.insert_reviews(reviews, embeddings).unwrap();
+        let query = |explain: bool| serde_json::json!({ "query": "battery charger", "top_k": 2, "explain": explain });
+
+        let (results, _) = search(&state, query(true)).await;
+        assert_eq!(results.len(), 2);
+        for result in &results {
+            let explanation = &result["explanation"];
+            assert_eq!(explanation["mode"], serde_json::to_value(Ranking::default().mode).unwrap());
+            let components = explanation["components"].as_array().unwrap();
+            let vector = components.iter().find(|c| c["signal"] == "vector").unwrap();
+            assert!(vector["raw"].is_number());
+            assert!(vector["rank"].as_u64().unwrap() >= 1);
+            assert!(explanation.get("rerank_score").is_none());
+        }
+        let top = &results[0];
+        assert_eq!(top["id"], 1);
+        assert_eq!(top["explanation"]["matched_terms"], serde_json::json!(["battery"]));
+        assert_eq!(results[1]["explanation"]["matched_terms"], serde_json::json!([]));
+        let lexical = top["explanation"]["components"].as_array().unwrap().iter().find(|c| c["signal"] == "lexical").unwrap();
+        assert_eq!(lexical["rank"], 1);
+
+        let (results, _) = search(&state, query(false)).await;
+        assert!(results.iter().all(|r| r.get("explanation").is_none()));
+    }
 }
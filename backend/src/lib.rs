@@ -1,6 +1,10 @@
+pub mod diversity;
 pub mod embed;
 pub mod error;
 pub mod handlers;
+pub mod paging;
+pub mod ranking;
+pub mod rerank;
 pub mod storage;
 #[cfg(feature = "fastembed")]
 pub mod bulk_insert;
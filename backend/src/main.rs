@@ -1,17 +1,25 @@
-use axum::{routing::post, Router, serve};
+use axum::{routing::{get, post}, Router, serve};
 use tower_http::cors::{CorsLayer, Any};
 use axum::http::Method;
 use std::net::SocketAddr;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use std::env;
 
 
-use backend::handlers::{bulk_insert_reviews, insert_review, search_reviews};
+use backend::handlers::{
+    bulk_insert_reviews, delete_review, get_review, insert_review, patch_review, put_review, search_reviews,
+};
 use backend::handlers as handlers;
 #[cfg(feature = "fastembed")]
 use backend::bulk_insert;
 use backend::embed::Embedder;
-use backend::storage::{metadata::MetadataStore, vector_store::VectorStore};
+use backend::ranking::Presets;
+use backend::rerank::RerankStage;
+use backend::storage::error::StorageError;
+use backend::storage::index::{open_index, IndexBackend};
+use backend::storage::metadata::open_metadata;
+use backend::storage::wal::{self, Wal};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -20,21 +28,43 @@ async fn main() -> anyhow::Result<()> {
     let data_dir = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("data");
     std::fs::create_dir_all(&data_dir)
         .map_err(|e| anyhow::anyhow!("Failed to create data directory: {}", e))?;
-    let embedder = Embedder::new()
+    let embedder = Embedder::open(&data_dir)
         .map_err(|e| anyhow::anyhow!("Failed to initialize embedder: {}", e))?;
-    #[cfg(feature = "spfresh")]
-    let index_path = data_dir.join("reviews");
-    #[cfg(not(feature = "spfresh"))]
-    let index_path = data_dir.join("reviews.vectors");
-    let vector_store = VectorStore::open_or_create(index_path)
-        .map_err(|e| anyhow::anyhow!("Failed to open or create vector store: {}", e))?;
-    let metadata_store = MetadataStore::open_or_create(data_dir.join("reviews.jsonl"))
+    tracing::info!("Embedding with {}", embedder.model_id());
+    let backend = IndexBackend::from_env()?;
+    tracing::info!("Using {} vector backend", backend);
+    let mut vector_store = open_index(backend, &data_dir, &embedder.vector_spec())
+        .map_err(|e| {
+            if matches!(e.downcast_ref::<StorageError>(), Some(StorageError::HeaderMismatch { field: "embedding model", .. })) {
+                anyhow::anyhow!(
+                    "Failed to open vector store: {}. Re-embed the reviews with `cargo run --bin index_builder`, \
+                     or set EMBEDDING_PREFIXES=off or EMBEDDING_REDUCTION to match how they were embedded",
+                    e
+                )
+            } else {
+                anyhow::anyhow!("Failed to open or create vector store: {}", e)
+            }
+        })?;
+    let mut metadata_store = open_metadata(&data_dir)
         .map_err(|e| anyhow::anyhow!("Failed to open or create metadata store: {}", e))?;
-    let app_state = handlers::AppStateInner::new(embedder, vector_store, metadata_store);
+    let (mut wal, entries) = Wal::open(Wal::path_for(&data_dir))?;
+    let recovered = wal::recover(&mut wal, &entries, vector_store.as_mut(), &mut metadata_store, embedder.embedding_size())
+        .map_err(|e| anyhow::anyhow!("Failed to replay write-ahead log: {}", e))?;
+    if recovered > 0 {
+        tracing::info!("Recovered {} reviews from the write-ahead log", recovered);
+    }
+    let presets = Presets::from_env()?;
+    let rerank = RerankStage::from_env()?;
+    if let Some(stage) = &rerank {
+        tracing::info!("Reranking up to {} candidates with {}", stage.candidates, stage.reranker.id());
+    }
+    let app_state = handlers::AppStateInner::new(embedder, vector_store, metadata_store, wal, presets, rerank);
+    tokio::spawn(run_compaction(app_state.clone(), compaction_interval()));
 
     let api_routes = Router::new()
         .route("/reviews", post(insert_review))
         .route("/reviews/bulk", post(bulk_insert_reviews))
+        .route("/reviews/:id", get(get_review).put(put_review).patch(patch_review).delete(delete_review))
         .route("/search", post(search_reviews));
 
     let app = Router::new()
@@ -43,8 +73,9 @@ async fn main() -> anyhow::Result<()> {
         .layer(
             CorsLayer::new()
                 .allow_origin(Any)
-                .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+                .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE, Method::OPTIONS])
                 .allow_headers(Any)
+                .expose_headers([axum::http::header::ETAG, axum::http::HeaderName::from_static(handlers::NEXT_CURSOR)])
         );
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 8000));
@@ -61,3 +92,38 @@ async fn main() -> anyhow::Result<()> {
     serve(listener, app).await
         .map_err(|e| anyhow::anyhow!("Server error: {}", e))
 }
+
+/// Reads `COMPACTION_INTERVAL_SECS` (default 300).
+fn compaction_interval() -> Duration {
+    let secs = env::var("COMPACTION_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(300);
+    Duration::from_secs(secs)
+}
+
+/// Periodically merges small segments and drops deleted rows until there is
+/// nothing left worth compacting.
+async fn run_compaction(state: handlers::AppState, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        loop {
+            let state = state.clone();
+            match tokio::task::spawn_blocking(move || state.compact()).await {
+                Ok(Ok(0)) => break,
+                Ok(Ok(merged)) => tracing::info!("Compacted {} segment runs", merged),
+                Ok(Err(e)) => {
+                    tracing::error!("Segment compaction failed: {}", e);
+                    break;
+                }
+                Err(e) => {
+                    tracing::error!("Segment compaction task panicked: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
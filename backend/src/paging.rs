@@ -0,0 +1,215 @@
+//! Paging through search results.
+//!
+//! The first page of a search ranks up to a candidate pool and keeps the
+//! ranked list in an LRU cache, keyed by a hash of the query embedding, the
+//! query text, the filter, the ranking, the reranker and the diversity
+//! settings. Later pages are cut from that list instead of ranking a larger
+//! top-k again. A [`Cursor`] names the list and the last result served, so a
+//! follow-up page continues right after it even if the list had to be
+//! recomputed in between.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use lru::LruCache;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+use crate::diversity::Diversity;
+use crate::ranking::{Component, Ranking};
+use crate::storage::filter::Filter;
+
+const CURSOR_VERSION: u8 = 1;
+const CURSOR_LEN: usize = 1 + 8 + 4 + 4 + 8;
+
+/// Ranked lists kept when `SEARCH_CACHE_SIZE` is not set.
+const DEFAULT_CACHE_SIZE: usize = 256;
+
+/// Identifies the ranked list of one search: same key, same ranking inputs.
+pub fn cache_key(
+    query: &str,
+    embedding: Option<&[f32]>,
+    filter: Option<&Filter>,
+    ranking: &Ranking,
+    reranker: Option<&str>,
+    diversity: Option<&Diversity>,
+) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    query.trim().hash(&mut hasher);
+    if let Some(embedding) = embedding {
+        for v in embedding {
+            v.to_bits().hash(&mut hasher);
+        }
+    }
+    format!("{:?}", filter).hash(&mut hasher);
+    format!("{:?}", (ranking.mode, ranking.vector_weight, ranking.lexical_weight, ranking.rrf_k)).hash(&mut hasher);
+    reranker.hash(&mut hasher);
+    format!("{:?}", diversity).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Position after the last result of a page, handed out as an opaque token.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cursor {
+    pub key: u64,
+    /// Results of the ranked list consumed so far.
+    pub position: usize,
+    pub score: f32,
+    pub id: u64,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        let mut bytes = Vec::with_capacity(CURSOR_LEN);
+        bytes.push(CURSOR_VERSION);
+        bytes.extend_from_slice(&self.key.to_le_bytes());
+        bytes.extend_from_slice(&(self.position as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.score.to_le_bytes());
+        bytes.extend_from_slice(&self.id.to_le_bytes());
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    pub fn decode(token: &str) -> Option<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(token).ok()?;
+        if bytes.len() != CURSOR_LEN || bytes[0] != CURSOR_VERSION {
+            return None;
+        }
+        Some(Self {
+            key: u64::from_le_bytes(bytes[1..9].try_into().ok()?),
+            position: u32::from_le_bytes(bytes[9..13].try_into().ok()?) as usize,
+            score: f32::from_le_bytes(bytes[13..17].try_into().ok()?),
+            id: u64::from_le_bytes(bytes[17..25].try_into().ok()?),
+        })
+    }
+
+    /// Whether `hit` comes after the cursor in ranked order.
+    fn precedes(&self, hit: &Hit) -> bool {
+        hit.score < self.score || (hit.score == self.score && hit.id > self.id)
+    }
+}
+
+/// One entry of a ranked list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hit {
+    pub row: usize,
+    pub id: u64,
+    pub score: f32,
+    pub components: Vec<Component>,
+    /// Reranker score, when the hit was among the reranked candidates.
+    pub rerank: Option<f32>,
+}
+
+/// Sorts best first, ties broken by ascending id.
+pub fn sort_by_score(hits: &mut [Hit]) {
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.id.cmp(&b.id)));
+}
+
+/// A ranked list, best first: by score with ties broken by ascending id, or
+/// in the order a reranker or diversity re-ranking picked.
+#[derive(Debug, Clone)]
+pub struct RankedList {
+    /// [`crate::storage::metadata::MetadataStore::generation`] it was ranked at.
+    pub generation: u64,
+    /// Candidates fetched from each retriever to build it.
+    pub pool: usize,
+    /// Whether the retrievers returned fewer than `pool` candidates, i.e. a
+    /// larger pool would not find more.
+    pub exhausted: bool,
+    /// Whether `hits` is in reranker or diversity order rather than score
+    /// order.
+    pub reordered: bool,
+    pub hits: Arc<Vec<Hit>>,
+}
+
+impl RankedList {
+    pub fn new(generation: u64, pool: usize, exhausted: bool, mut hits: Vec<Hit>) -> Self {
+        sort_by_score(&mut hits);
+        Self { generation, pool, exhausted, reordered: false, hits: Arc::new(hits) }
+    }
+
+    /// A list whose order was set by [`crate::rerank`] or
+    /// [`crate::diversity::Diversity`].
+    pub fn reordered(generation: u64, pool: usize, exhausted: bool, hits: Vec<Hit>) -> Self {
+        Self { generation, pool, exhausted, reordered: true, hits: Arc::new(hits) }
+    }
+
+    /// Whether the list holds the first `needed` results, or all there are.
+    pub fn covers(&self, needed: usize) -> bool {
+        self.exhausted || self.hits.len() >= needed
+    }
+
+    /// Index of the first hit after `cursor`. A reordered list is not in
+    /// score order, so it resumes after the cursor's review, or at the
+    /// cursor's position if that review is no longer in the list.
+    pub fn resume(&self, cursor: &Cursor) -> usize {
+        if self.reordered {
+            return match self.hits.iter().position(|hit| hit.id == cursor.id) {
+                Some(i) => i + 1,
+                None => cursor.position.min(self.hits.len()),
+            };
+        }
+        self.hits.partition_point(|hit| !cursor.precedes(hit))
+    }
+}
+
+/// LRU cache of ranked lists by [`cache_key`].
+pub struct ResultCache {
+    lists: Option<LruCache<u64, RankedList>>,
+}
+
+impl ResultCache {
+    /// Holds up to `capacity` lists; 0 disables caching.
+    pub fn new(capacity: usize) -> Self {
+        Self { lists: NonZeroUsize::new(capacity).map(LruCache::new) }
+    }
+
+    /// Reads `SEARCH_CACHE_SIZE` (default 256).
+    pub fn from_env() -> Self {
+        let capacity = std::env::var("SEARCH_CACHE_SIZE")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(DEFAULT_CACHE_SIZE);
+        Self::new(capacity)
+    }
+
+    pub fn get(&mut self, key: u64) -> Option<RankedList> {
+        self.lists.as_mut()?.get(&key).cloned()
+    }
+
+    pub fn put(&mut self, key: u64, list: RankedList) {
+        if let Some(lists) = self.lists.as_mut() {
+            lists.put(key, list);
+        }
+    }
+}
+
+/// A bare hit on row `id`, for tests here and in the modules that rank hits.
+#[cfg(test)]
+pub(crate) fn hit(id: u64, score: f32) -> Hit {
+    Hit { row: id as usize, id, score, components: Vec::new(), rerank: None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursors_roundtrip_and_resume_after_ties() {
+        let cursor = Cursor { key: 0xfeed, position: 2, score: 0.5, id: 7 };
+        assert_eq!(Cursor::decode(&cursor.encode()), Some(cursor));
+        assert_eq!(Cursor::decode("not a cursor"), None);
+
+        let list = RankedList::new(1, 10, true, vec![hit(9, 0.5), hit(3, 0.9), hit(7, 0.5), hit(4, 0.1)]);
+        let ids: Vec<u64> = list.hits.iter().map(|h| h.id).collect();
+        assert_eq!(ids, vec![3, 7, 9, 4]);
+        assert_eq!(list.resume(&cursor), 2);
+        // A recomputed list that lost the cursor's own row still resumes in place.
+        let list = RankedList::new(2, 10, true, vec![hit(3, 0.9), hit(9, 0.5), hit(4, 0.1)]);
+        assert_eq!(list.hits[list.resume(&cursor)].id, 9);
+
+        let mut cache = ResultCache::new(1);
+        cache.put(1, list.clone());
+        cache.put(2, list);
+        assert!(cache.get(1).is_none() && cache.get(2).is_some());
+    }
+}
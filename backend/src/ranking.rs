@@ -0,0 +1,337 @@
+//! How `/search` turns vector and BM25 candidates into one ranking.
+//!
+//! A request picks a [`FusionMode`] and its parameters through the `ranking`
+//! object, either directly or by naming a server-side preset. Presets are
+//! built in, and more can be loaded from the JSON file `RANKING_PRESETS`
+//! points to, e.g. `{"support": {"mode": "rrf", "lexical_weight": 2}}`. A
+//! file entry named like a built-in replaces it, including `default`, which
+//! applies when a request names no preset.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Preset used when a request does not name one.
+pub const DEFAULT_PRESET: &str = "default";
+
+/// Largest candidate pool a request may ask each retriever for.
+pub const MAX_CANDIDATES: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FusionMode {
+    /// Vector similarity only.
+    Vector,
+    /// BM25 only.
+    Lexical,
+    /// Weighted sum of vector similarity and BM25 scaled to the best match.
+    Linear,
+    /// Reciprocal rank fusion: `weight / (rrf_k + rank)` summed over both lists.
+    Rrf,
+}
+
+/// The `ranking` object of a search request. Every field is optional; unset
+/// fields come from the preset.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RankingOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<FusionMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vector_weight: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lexical_weight: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rrf_k: Option<f32>,
+    /// Candidates fetched from each retriever before fusion.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub candidates: Option<usize>,
+}
+
+/// A fully resolved ranking configuration.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Ranking {
+    pub mode: FusionMode,
+    pub vector_weight: f32,
+    pub lexical_weight: f32,
+    pub rrf_k: f32,
+    /// `None` keeps the pool at `min(top_k * 10, 200)`.
+    pub candidates: Option<usize>,
+}
+
+impl Default for Ranking {
+    fn default() -> Self {
+        Self { mode: FusionMode::Linear, vector_weight: 0.7, lexical_weight: 0.3, rrf_k: 60.0, candidates: None }
+    }
+}
+
+impl Ranking {
+    fn with(&self, options: &RankingOptions) -> Self {
+        Self {
+            mode: options.mode.unwrap_or(self.mode),
+            vector_weight: options.vector_weight.unwrap_or(self.vector_weight),
+            lexical_weight: options.lexical_weight.unwrap_or(self.lexical_weight),
+            rrf_k: options.rrf_k.unwrap_or(self.rrf_k),
+            candidates: options.candidates.or(self.candidates),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (name, weight) in [("vector_weight", self.vector_weight), ("lexical_weight", self.lexical_weight)] {
+            if !weight.is_finite() || weight < 0.0 {
+                return Err(format!("{} must be a non-negative number", name));
+            }
+        }
+        if matches!(self.mode, FusionMode::Linear | FusionMode::Rrf) && self.vector_weight + self.lexical_weight <= 0.0 {
+            return Err("vector_weight and lexical_weight cannot both be 0".to_string());
+        }
+        if !self.rrf_k.is_finite() || self.rrf_k < 0.0 {
+            return Err("rrf_k must be a non-negative number".to_string());
+        }
+        if let Some(candidates) = self.candidates {
+            if candidates == 0 || candidates > MAX_CANDIDATES {
+                return Err(format!("candidates must be between 1 and {}", MAX_CANDIDATES));
+            }
+        }
+        Ok(())
+    }
+
+    /// Candidates to fetch from each retriever for `top_k` results.
+    pub fn pool(&self, top_k: usize) -> usize {
+        self.candidates.unwrap_or(std::cmp::min(top_k * 10, 200)).max(top_k)
+    }
+
+    pub fn uses_vectors(&self) -> bool {
+        self.mode != FusionMode::Lexical
+    }
+
+    pub fn uses_lexical(&self) -> bool {
+        self.mode != FusionMode::Vector
+    }
+
+    /// Merges both candidate lists, each best first, into rows sorted by
+    /// descending score in 0..1. In linear mode a row only the lexical list
+    /// has is scored with `vector_score`, which returns `None` when the
+    /// backend cannot read the vector back; such rows get the weakest vector
+    /// score seen.
+    pub fn fuse(
+        &self,
+        vector: &[(usize, f32)],
+        lexical: &[(usize, f32)],
+        mut vector_score: impl FnMut(usize) -> Result<Option<f32>>,
+    ) -> Result<Vec<Fused>> {
+        // (rank, raw score) of each row in the vector and the lexical list.
+        let mut hits: HashMap<usize, [Option<(usize, f32)>; 2]> = HashMap::new();
+        for (slot, list, used) in [(0, vector, self.uses_vectors()), (1, lexical, self.uses_lexical())] {
+            if used {
+                for (rank, (row, score)) in list.iter().enumerate() {
+                    hits.entry(*row).or_default()[slot] = Some((rank + 1, *score));
+                }
+            }
+        }
+        let (vector_weight, lexical_weight) = match self.mode {
+            FusionMode::Vector => (1.0, 0.0),
+            FusionMode::Lexical => (0.0, 1.0),
+            FusionMode::Linear => {
+                let total = self.vector_weight + self.lexical_weight;
+                (self.vector_weight / total, self.lexical_weight / total)
+            }
+            FusionMode::Rrf => (self.vector_weight, self.lexical_weight),
+        };
+        // RRF is scaled so ranking first in both lists scores 1.
+        let scale = match self.mode {
+            FusionMode::Rrf => (self.vector_weight + self.lexical_weight) / (self.rrf_k + 1.0),
+            _ => 1.0,
+        };
+        let max_bm25 = lexical.first().map_or(0.0, |(_, s)| *s);
+        let floor = vector.last().map_or(-1.0, |(_, s)| *s);
+
+        let mut fused = Vec::with_capacity(hits.len());
+        for (row, [vector_hit, lexical_hit]) in hits {
+            let mut components = Vec::with_capacity(2);
+            if self.uses_vectors() {
+                let (rank, raw) = match vector_hit {
+                    Some((rank, score)) => (Some(rank), Some(score)),
+                    None if self.mode == FusionMode::Linear && vector_weight > 0.0 => (None, vector_score(row)?),
+                    None => (None, None),
+                };
+                let normalized = match self.mode {
+                    FusionMode::Rrf => rank.map_or(0.0, |r| 1.0 / (self.rrf_k + r as f32)),
+                    // Cosine similarity, -1..1, mapped to 0..1.
+                    _ => ((raw.unwrap_or(floor) + 1.0) / 2.0).clamp(0.0, 1.0),
+                };
+                components.push(Component::new(Signal::Vector, raw, normalized, vector_weight, rank, scale));
+            }
+            if self.uses_lexical() {
+                let (rank, raw) = lexical_hit.map_or((None, None), |(rank, score)| (Some(rank), Some(score)));
+                let normalized = match self.mode {
+                    FusionMode::Rrf => rank.map_or(0.0, |r| 1.0 / (self.rrf_k + r as f32)),
+                    // BM25 relative to the best lexical match.
+                    _ => raw.filter(|_| max_bm25 > 0.0).map_or(0.0, |s| s / max_bm25),
+                };
+                components.push(Component::new(Signal::Lexical, raw, normalized, lexical_weight, rank, scale));
+            }
+            let score = components.iter().map(|c| c.contribution).sum();
+            fused.push(Fused { row, score, components });
+        }
+        fused.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.row.cmp(&b.row)));
+        Ok(fused)
+    }
+}
+
+/// Where a score component comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Signal {
+    Vector,
+    Lexical,
+}
+
+/// One signal's part in a fused score.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Component {
+    pub signal: Signal,
+    /// Cosine similarity or BM25 score as the retriever returned it; `None`
+    /// when the row was not scored by this signal.
+    pub raw: Option<f32>,
+    /// The score in 0..1, or `1 / (rrf_k + rank)` in RRF mode.
+    pub normalized: f32,
+    pub weight: f32,
+    /// 1-based position among this retriever's candidates.
+    pub rank: Option<usize>,
+    /// What this component adds to the final score.
+    pub contribution: f32,
+}
+
+impl Component {
+    fn new(signal: Signal, raw: Option<f32>, normalized: f32, weight: f32, rank: Option<usize>, scale: f32) -> Self {
+        Self { signal, raw, normalized, weight, rank, contribution: weight * normalized / scale }
+    }
+}
+
+/// A row with its fused score and how it was made up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fused {
+    pub row: usize,
+    pub score: f32,
+    pub components: Vec<Component>,
+}
+
+/// Score breakdown returned for each result of an `explain` search.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Explanation {
+    pub mode: FusionMode,
+    pub components: Vec<Component>,
+    /// Query terms found in the review's title or body.
+    pub matched_terms: Vec<String>,
+    /// Reranker score, which replaces the fused score when present.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rerank_score: Option<f32>,
+}
+
+/// Named rankings a request can refer to.
+#[derive(Debug, Clone)]
+pub struct Presets {
+    presets: HashMap<String, Ranking>,
+}
+
+impl Default for Presets {
+    fn default() -> Self {
+        let hybrid = Ranking::default();
+        let presets = [
+            (DEFAULT_PRESET, hybrid.clone()),
+            ("hybrid", hybrid.clone()),
+            ("semantic", Ranking { mode: FusionMode::Vector, ..hybrid.clone() }),
+            ("keyword", Ranking { mode: FusionMode::Lexical, ..hybrid.clone() }),
+            ("rrf", Ranking { mode: FusionMode::Rrf, vector_weight: 1.0, lexical_weight: 1.0, ..hybrid }),
+        ];
+        Self { presets: presets.into_iter().map(|(name, r)| (name.to_string(), r)).collect() }
+    }
+}
+
+impl Presets {
+    /// The built-in presets, plus those in the file `RANKING_PRESETS` names.
+    pub fn from_env() -> Result<Self> {
+        let mut presets = Self::default();
+        if let Ok(path) = std::env::var("RANKING_PRESETS") {
+            let data = std::fs::read_to_string(&path)
+                .map_err(|e| anyhow::anyhow!("Failed to read ranking presets {:?}: {}", path, e))?;
+            let file: HashMap<String, RankingOptions> = serde_json::from_str(&data)
+                .map_err(|e| anyhow::anyhow!("Failed to parse ranking presets {:?}: {}", path, e))?;
+            presets.extend(file).map_err(|e| anyhow::anyhow!("Invalid ranking preset in {:?}: {}", path, e))?;
+        }
+        Ok(presets)
+    }
+
+    /// Adds presets given as options over the `default` preset. Entries may
+    /// not name another preset.
+    pub fn extend(&mut self, presets: HashMap<String, RankingOptions>) -> Result<(), String> {
+        let base = self.presets[DEFAULT_PRESET].clone();
+        for (name, options) in presets {
+            if options.preset.is_some() {
+                return Err(format!("preset {:?} cannot itself name a preset", name));
+            }
+            let ranking = base.with(&options);
+            ranking.validate().map_err(|e| format!("{}: {}", name, e))?;
+            self.presets.insert(name, ranking);
+        }
+        Ok(())
+    }
+
+    /// Applies a request's options over the preset they name.
+    pub fn resolve(&self, options: Option<&RankingOptions>) -> Result<Ranking, String> {
+        let Some(options) = options else {
+            return Ok(self.presets[DEFAULT_PRESET].clone());
+        };
+        let name = options.preset.as_deref().unwrap_or(DEFAULT_PRESET);
+        let preset = self.presets.get(name).ok_or_else(|| {
+            let mut names: Vec<&str> = self.presets.keys().map(String::as_str).collect();
+            names.sort_unstable();
+            format!("Unknown ranking preset {:?}; available presets are {}", name, names.join(", "))
+        })?;
+        let ranking = preset.with(options);
+        ranking.validate()?;
+        Ok(ranking)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_resolve_and_modes_fuse_differently() {
+        let mut presets = Presets::default();
+        let file = serde_json::from_str(r#"{"titles": {"mode": "rrf", "lexical_weight": 3, "candidates": 50}}"#).unwrap();
+        presets.extend(file).unwrap();
+        let options: RankingOptions = serde_json::from_str(r#"{"preset": "titles", "rrf_k": 0}"#).unwrap();
+        let ranking = presets.resolve(Some(&options)).unwrap();
+        assert_eq!((ranking.mode, ranking.lexical_weight, ranking.rrf_k, ranking.pool(5)), (FusionMode::Rrf, 3.0, 0.0, 50));
+        assert!(presets.resolve(Some(&RankingOptions { preset: Some("nope".into()), ..Default::default() })).is_err());
+        assert!(presets.resolve(Some(&RankingOptions { candidates: Some(0), ..Default::default() })).is_err());
+
+        let vector = [(1, 0.9), (2, 0.5)];
+        let lexical = [(3, 8.0), (2, 4.0)];
+        let no_vectors = |_: usize| Ok(None);
+        let rows = |fused: Vec<Fused>| fused.into_iter().map(|f| f.row).collect::<Vec<_>>();
+
+        let rrf = rows(ranking.fuse(&vector, &lexical, no_vectors).unwrap());
+        assert_eq!(rrf, vec![3, 2, 1]);
+        let semantic = presets.resolve(Some(&RankingOptions { preset: Some("semantic".into()), ..Default::default() })).unwrap();
+        assert_eq!(rows(semantic.fuse(&vector, &lexical, no_vectors).unwrap()), vec![1, 2]);
+        let keyword = presets.resolve(Some(&RankingOptions { mode: Some(FusionMode::Lexical), ..Default::default() })).unwrap();
+        let scores: Vec<(usize, f32)> = keyword.fuse(&vector, &lexical, no_vectors).unwrap().iter().map(|f| (f.row, f.score)).collect();
+        assert_eq!(scores, vec![(3, 1.0), (2, 0.5)]);
+
+        // Row 3 is only a lexical hit; its vector is read back for the linear score.
+        let linear = presets.resolve(None).unwrap();
+        let fused = linear.fuse(&vector, &lexical, |row| Ok((row == 3).then_some(1.0))).unwrap();
+        assert_eq!(rows(fused.clone()), vec![3, 2, 1]);
+        assert!(fused.iter().all(|f| (0.0..=1.0).contains(&f.score)));
+        let row3 = &fused[0].components;
+        assert_eq!((row3[0].signal, row3[0].raw, row3[0].rank), (Signal::Vector, Some(1.0), None));
+        assert_eq!((row3[1].raw, row3[1].rank, row3[1].normalized), (Some(8.0), Some(1), 1.0));
+    }
+}
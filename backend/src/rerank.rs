@@ -0,0 +1,171 @@
+//! Optional cross-encoder stage that re-scores the best fused candidates.
+//!
+//! `RERANKER` picks the model: a fastembed reranker code such as
+//! `BAAI/bge-reranker-base` (needs the `fastembed` feature), or `stub` for a
+//! deterministic term-overlap scorer that builds everywhere and keeps the
+//! stage testable offline. Requests opt in with `"rerank": true`; set
+//! `RERANK_BY_DEFAULT=1` to rerank every search that does not opt out.
+//! `RERANK_CANDIDATES` (default 50) is how many of the best candidates are
+//! re-scored; the rest keep their place behind them.
+
+use anyhow::Result;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+#[cfg(feature = "fastembed")]
+use fastembed::{RerankInitOptions, TextRerank};
+#[cfg(feature = "fastembed")]
+use std::sync::Mutex;
+
+use crate::paging::Hit;
+use crate::storage::lexical;
+
+/// Candidates re-scored when `RERANK_CANDIDATES` is not set.
+const DEFAULT_CANDIDATES: usize = 50;
+
+/// Scores how well each passage answers a query.
+pub trait Reranker: Send + Sync {
+    /// Names the model; part of the search cache key.
+    fn id(&self) -> &str;
+
+    /// One relevance score in 0..1 per passage, higher is better.
+    fn score(&self, query: &str, passages: &[String]) -> Result<Vec<f32>>;
+}
+
+/// Fraction of the distinct query terms each passage contains.
+pub struct StubReranker;
+
+impl Reranker for StubReranker {
+    fn id(&self) -> &str {
+        "stub"
+    }
+
+    fn score(&self, query: &str, passages: &[String]) -> Result<Vec<f32>> {
+        let terms: HashSet<String> = lexical::tokenize(query).collect();
+        if terms.is_empty() {
+            return Ok(vec![0.0; passages.len()]);
+        }
+        Ok(passages
+            .iter()
+            .map(|passage| lexical::matched_terms(query, passage).len() as f32 / terms.len() as f32)
+            .collect())
+    }
+}
+
+/// A fastembed cross-encoder. Its logits are squashed into 0..1.
+#[cfg(feature = "fastembed")]
+pub struct FastembedReranker {
+    model: Mutex<TextRerank>,
+    model_code: String,
+}
+
+#[cfg(feature = "fastembed")]
+impl FastembedReranker {
+    pub fn new(code: &str) -> Result<Self> {
+        let info = TextRerank::list_supported_models()
+            .into_iter()
+            .find(|info| info.model_code.eq_ignore_ascii_case(code))
+            .ok_or_else(|| anyhow::anyhow!("Unknown reranker model {:?}", code))?;
+        let options = RerankInitOptions::new(info.model.clone()).with_show_download_progress(true);
+        let model = TextRerank::try_new(options)
+            .map_err(|e| anyhow::anyhow!("Failed to load reranker {:?}: {}", code, e))?;
+        Ok(Self { model: Mutex::new(model), model_code: info.model_code })
+    }
+}
+
+#[cfg(feature = "fastembed")]
+impl Reranker for FastembedReranker {
+    fn id(&self) -> &str {
+        &self.model_code
+    }
+
+    fn score(&self, query: &str, passages: &[String]) -> Result<Vec<f32>> {
+        let documents: Vec<&str> = passages.iter().map(String::as_str).collect();
+        let mut model = self.model.lock().map_err(|_| anyhow::anyhow!("Failed to acquire reranker lock"))?;
+        let results = model
+            .rerank(query, documents, false, None)
+            .map_err(|e| anyhow::anyhow!("Failed to rerank candidates: {}", e))?;
+        let mut scores = vec![0.0; passages.len()];
+        for result in results {
+            scores[result.index] = 1.0 / (1.0 + (-result.score).exp());
+        }
+        Ok(scores)
+    }
+}
+
+/// The configured reranker and when to use it.
+#[derive(Clone)]
+pub struct RerankStage {
+    pub reranker: Arc<dyn Reranker>,
+    /// Rerank requests that do not say either way.
+    pub by_default: bool,
+    pub candidates: usize,
+}
+
+impl RerankStage {
+    /// Reads `RERANKER`, `RERANK_BY_DEFAULT` and `RERANK_CANDIDATES`.
+    /// Returns `None` when no reranker is configured.
+    pub fn from_env() -> Result<Option<Self>> {
+        let code = match std::env::var("RERANKER") {
+            Ok(code) if !code.trim().is_empty() && code.trim() != "none" => code.trim().to_string(),
+            _ => return Ok(None),
+        };
+        let reranker: Arc<dyn Reranker> = if code == "stub" {
+            Arc::new(StubReranker)
+        } else {
+            #[cfg(feature = "fastembed")]
+            {
+                Arc::new(FastembedReranker::new(&code)?)
+            }
+            #[cfg(not(feature = "fastembed"))]
+            {
+                anyhow::bail!("Reranker {:?} requires building with `--features fastembed`; use RERANKER=stub", code)
+            }
+        };
+        let by_default = std::env::var("RERANK_BY_DEFAULT").is_ok_and(|v| matches!(v.trim(), "1" | "true"));
+        let candidates = std::env::var("RERANK_CANDIDATES")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(DEFAULT_CANDIDATES);
+        Ok(Some(Self { reranker, by_default, candidates }))
+    }
+}
+
+/// Moves the first `scores.len()` hits (best first) into reranker order,
+/// with the reranker score as their score. Equal scores keep their fused
+/// order, and the hits past them follow unchanged.
+pub fn reorder(mut hits: Vec<Hit>, scores: &[f32]) -> Vec<Hit> {
+    let tail = hits.split_off(scores.len().min(hits.len()));
+    for (hit, &score) in hits.iter_mut().zip(scores) {
+        hit.score = score;
+        hit.rerank = Some(score);
+    }
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+    hits.extend(tail);
+    hits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paging::hit;
+
+    #[test]
+    fn stub_scores_term_overlap_and_reorders_the_head() {
+        let passages = vec![
+            "Screen is bright".to_string(),
+            "Battery life is great, screen too".to_string(),
+            "battery died".to_string(),
+        ];
+        let scores = StubReranker.score("battery screen", &passages).unwrap();
+        assert_eq!(scores, vec![0.5, 1.0, 0.5]);
+
+        let hits = vec![hit(1, 0.9), hit(2, 0.8), hit(3, 0.7), hit(4, 0.6)];
+        let reranked = reorder(hits, &scores);
+        let ids: Vec<u64> = reranked.iter().map(|h| h.id).collect();
+        assert_eq!(ids, vec![2, 1, 3, 4]);
+        assert_eq!((reranked[0].score, reranked[0].rerank), (1.0, Some(1.0)));
+        assert_eq!((reranked[3].score, reranked[3].rerank), (0.6, None));
+    }
+}
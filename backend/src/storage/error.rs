@@ -0,0 +1,21 @@
+use std::path::PathBuf;
+
+/// Errors the storage layer reports with enough detail to act on, as opposed
+/// to the plain I/O failures it wraps in `anyhow`.
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("{path:?} has a corrupt vector file header: {reason}")]
+    CorruptHeader { path: PathBuf, reason: String },
+
+    #[error("{path:?} uses vector file format version {found}, this build reads up to version {supported}")]
+    UnsupportedVersion { path: PathBuf, found: u16, supported: u16 },
+
+    #[error("{path:?} was written with {field} {found}, but this index expects {expected}")]
+    HeaderMismatch { path: PathBuf, field: &'static str, found: String, expected: String },
+
+    #[error("vector has {found} dimensions, but this index stores {expected}-d vectors")]
+    DimensionMismatch { expected: usize, found: usize },
+
+    #[error("I/O error on {path:?}: {source}")]
+    Io { path: PathBuf, source: std::io::Error },
+}
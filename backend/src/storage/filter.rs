@@ -0,0 +1,221 @@
+//! Metadata filters that are evaluated inside the vector scan.
+//!
+//! The metadata store keeps the filterable fields of every row in memory as
+//! [`Attributes`] columns, so a [`Filter`] can be checked per candidate row
+//! before top-k selection instead of post-filtering a fixed candidate pool.
+//! Which fields are columns is set with `FILTER_FIELDS` (default
+//! `product_id,review_rating`).
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Fields indexed when `FILTER_FIELDS` is not set.
+const DEFAULT_FIELDS: &[&str] = &["product_id", "review_rating"];
+
+/// A boolean expression over top-level metadata fields, e.g.
+///
+/// ```json
+/// {"and": [{"in": {"field": "product_id", "values": ["P123"]}},
+///          {"range": {"field": "review_rating", "lte": 2}}]}
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    Eq { field: String, value: Value },
+    In { field: String, values: Vec<Value> },
+    Range {
+        field: String,
+        gt: Option<f64>,
+        gte: Option<f64>,
+        lt: Option<f64>,
+        lte: Option<f64>,
+    },
+}
+
+impl Filter {
+    /// Checks that every field the filter mentions is an attribute column.
+    pub fn validate(&self, attributes: &Attributes) -> Result<(), String> {
+        match self {
+            Filter::And(parts) | Filter::Or(parts) => parts.iter().try_for_each(|f| f.validate(attributes)),
+            Filter::Not(inner) => inner.validate(attributes),
+            Filter::Eq { field, .. } | Filter::In { field, .. } | Filter::Range { field, .. } => {
+                if attributes.column(field).is_some() {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "Cannot filter on {:?}; filterable fields are {}",
+                        field,
+                        attributes.fields.join(", ")
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Whether `row` satisfies the filter. Unknown fields never match.
+    pub fn matches(&self, attributes: &Attributes, row: usize) -> bool {
+        match self {
+            Filter::And(parts) => parts.iter().all(|f| f.matches(attributes, row)),
+            Filter::Or(parts) => parts.iter().any(|f| f.matches(attributes, row)),
+            Filter::Not(inner) => !inner.matches(attributes, row),
+            Filter::Eq { field, value } => attributes.get(field, row).is_some_and(|v| v.equals(value)),
+            Filter::In { field, values } => attributes
+                .get(field, row)
+                .is_some_and(|v| values.iter().any(|value| v.equals(value))),
+            Filter::Range { field, gt, gte, lt, lte } => match attributes.get(field, row) {
+                Some(AttrValue::Number(n)) => {
+                    let n = *n;
+                    gt.is_none_or(|b| n > b)
+                        && gte.is_none_or(|b| n >= b)
+                        && lt.is_none_or(|b| n < b)
+                        && lte.is_none_or(|b| n <= b)
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+/// A scalar metadata value as kept in an attribute column.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttrValue {
+    Missing,
+    Bool(bool),
+    Number(f64),
+    Text(Arc<str>),
+}
+
+impl AttrValue {
+    fn equals(&self, value: &Value) -> bool {
+        match (self, value) {
+            (AttrValue::Bool(a), Value::Bool(b)) => a == b,
+            (AttrValue::Number(a), Value::Number(b)) => b.as_f64() == Some(*a),
+            (AttrValue::Text(a), Value::String(b)) => a.as_ref() == b,
+            _ => false,
+        }
+    }
+}
+
+/// In-memory columns of the filterable fields, one value per row. Strings
+/// are interned since fields like `product_id` repeat heavily.
+#[derive(Debug)]
+pub struct Attributes {
+    fields: Vec<String>,
+    columns: Vec<Vec<AttrValue>>,
+    strings: HashSet<Arc<str>>,
+    rows: usize,
+}
+
+impl Attributes {
+    pub fn new(fields: Vec<String>) -> Self {
+        let columns = fields.iter().map(|_| Vec::new()).collect();
+        Self { fields, columns, strings: HashSet::new(), rows: 0 }
+    }
+
+    /// Reads `FILTER_FIELDS`, a comma-separated list of top-level fields.
+    pub fn from_env() -> Self {
+        let fields = match std::env::var("FILTER_FIELDS") {
+            Ok(value) => value
+                .split(',')
+                .map(|f| f.trim().to_string())
+                .filter(|f| !f.is_empty())
+                .collect(),
+            Err(_) => DEFAULT_FIELDS.iter().map(|f| f.to_string()).collect(),
+        };
+        Self::new(fields)
+    }
+
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    /// Number of rows recorded.
+    pub fn len(&self) -> usize {
+        self.rows
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Records the next row from its JSON line. Anything that is not an
+    /// object, and fields that are missing or not scalars, are `Missing`.
+    pub fn push_line(&mut self, line: &str) {
+        let value = serde_json::from_str::<Value>(line).unwrap_or(Value::Null);
+        for i in 0..self.fields.len() {
+            let attr = match value.get(&self.fields[i]) {
+                Some(Value::Bool(b)) => AttrValue::Bool(*b),
+                Some(Value::Number(n)) => n.as_f64().map_or(AttrValue::Missing, AttrValue::Number),
+                Some(Value::String(s)) => AttrValue::Text(self.intern(s)),
+                _ => AttrValue::Missing,
+            };
+            self.columns[i].push(attr);
+        }
+        self.rows += 1;
+    }
+
+    /// Records a row whose line is gone (deleted before the store was opened).
+    pub fn push_missing(&mut self) {
+        for column in &mut self.columns {
+            column.push(AttrValue::Missing);
+        }
+        self.rows += 1;
+    }
+
+    pub fn get(&self, field: &str, row: usize) -> Option<&AttrValue> {
+        self.column(field).and_then(|c| c.get(row))
+    }
+
+    fn column(&self, field: &str) -> Option<&Vec<AttrValue>> {
+        self.fields.iter().position(|f| f == field).map(|i| &self.columns[i])
+    }
+
+    fn intern(&mut self, s: &str) -> Arc<str> {
+        if let Some(existing) = self.strings.get(s) {
+            return existing.clone();
+        }
+        let interned: Arc<str> = Arc::from(s);
+        self.strings.insert(interned.clone());
+        interned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_combine_over_attribute_columns() {
+        let mut attributes = Attributes::new(vec!["product_id".to_string(), "review_rating".to_string()]);
+        attributes.push_line(r#"{"product_id":"P123","review_rating":2}"#);
+        attributes.push_line(r#"{"product_id":"P123","review_rating":5}"#);
+        attributes.push_line(r#"{"product_id":"P9","review_rating":1}"#);
+        attributes.push_missing();
+
+        let filter: Filter = serde_json::from_str(
+            r#"{"and": [{"in": {"field": "product_id", "values": ["P123", "P7"]}},
+                        {"range": {"field": "review_rating", "lte": 2}}]}"#,
+        )
+        .unwrap();
+        let rows: Vec<usize> = (0..4).filter(|&row| filter.matches(&attributes, row)).collect();
+        assert_eq!(rows, vec![0]);
+
+        let filter: Filter = serde_json::from_str(
+            r#"{"or": [{"eq": {"field": "product_id", "value": "P9"}},
+                       {"not": {"range": {"field": "review_rating", "lt": 5}}}]}"#,
+        )
+        .unwrap();
+        let rows: Vec<usize> = (0..4).filter(|&row| filter.matches(&attributes, row)).collect();
+        assert_eq!(rows, vec![1, 2, 3]);
+
+        let unknown: Filter = serde_json::from_str(r#"{"eq": {"field": "review_title", "value": "x"}}"#).unwrap();
+        assert!(unknown.validate(&attributes).is_err());
+        assert!(filter.validate(&attributes).is_ok());
+    }
+}
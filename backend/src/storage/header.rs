@@ -0,0 +1,331 @@
+//! Self-describing header at the start of every vector file.
+//!
+//! Layout (little endian), padded with zeros to a multiple of 64 bytes so
+//! the vectors that follow stay aligned for memory mapping:
+//!
+//! | offset | size | field                                  |
+//! |--------|------|----------------------------------------|
+//! | 0      | 4    | magic `RVEC`                           |
+//! | 4      | 2    | format version                         |
+//! | 6      | 2    | header length, i.e. offset of the data |
+//! | 8      | 4    | dimension                              |
+//! | 12     | 1    | element type (0 f32, 1 i8, 2 u8)       |
+//! | 13     | 1    | metric (0 cosine, 1 dot, 2 l2)         |
+//! | 14     | 2    | reserved                               |
+//! | 16     | 4    | quantization scale (f32)               |
+//! | 20     | 4    | quantization offset (f32)              |
+//! | 24     | 2    | model id length                        |
+//! | 26     | n    | model id, UTF-8                        |
+//!
+//! A stored element `q` stands for the value `q / scale + offset`.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use super::error::StorageError;
+
+pub const MAGIC: &[u8; 4] = b"RVEC";
+pub const VERSION: u16 = 1;
+const ALIGN: usize = 64;
+const FIXED_LEN: usize = 26;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElementType {
+    F32,
+    I8,
+    U8,
+}
+
+impl ElementType {
+    pub fn size(self) -> usize {
+        match self {
+            ElementType::F32 => 4,
+            ElementType::I8 | ElementType::U8 => 1,
+        }
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            ElementType::F32 => 0,
+            ElementType::I8 => 1,
+            ElementType::U8 => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(ElementType::F32),
+            1 => Some(ElementType::I8),
+            2 => Some(ElementType::U8),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ElementType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ElementType::F32 => "f32",
+            ElementType::I8 => "i8",
+            ElementType::U8 => "u8",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    /// Dot product of vectors normalized before they were stored.
+    Cosine,
+    Dot,
+    L2,
+}
+
+impl Metric {
+    fn code(self) -> u8 {
+        match self {
+            Metric::Cosine => 0,
+            Metric::Dot => 1,
+            Metric::L2 => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Metric::Cosine),
+            1 => Some(Metric::Dot),
+            2 => Some(Metric::L2),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Metric::Cosine => "cosine",
+            Metric::Dot => "dot",
+            Metric::L2 => "l2",
+        })
+    }
+}
+
+/// What the vectors of a collection are: produced by which model, with how
+/// many dimensions. Every vector file of an index is checked against it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VectorSpec {
+    pub dim: usize,
+    pub model_id: String,
+}
+
+impl VectorSpec {
+    pub fn new(dim: usize, model_id: impl Into<String>) -> Self {
+        Self { dim, model_id: model_id.into() }
+    }
+}
+
+/// Rejects a vector whose length is not the collection's dimension.
+pub fn check_dim(expected: usize, found: usize) -> Result<(), StorageError> {
+    if found != expected {
+        return Err(StorageError::DimensionMismatch { expected, found });
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VectorHeader {
+    pub dim: usize,
+    pub element: ElementType,
+    pub scale: f32,
+    pub offset: f32,
+    pub metric: Metric,
+    pub model_id: String,
+}
+
+impl VectorHeader {
+    /// Header of a file holding normalized vectors quantized to `i8` with
+    /// `scale`, as written by the flat and HNSW backends.
+    pub fn quantized_i8(spec: &VectorSpec, scale: f32) -> Self {
+        Self {
+            dim: spec.dim,
+            element: ElementType::I8,
+            scale,
+            offset: 0.0,
+            metric: Metric::Cosine,
+            model_id: spec.model_id.clone(),
+        }
+    }
+
+    /// Bytes taken by one stored vector.
+    pub fn row_size(&self) -> usize {
+        self.dim * self.element.size()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let model = self.model_id.as_bytes();
+        let len = (FIXED_LEN + model.len()).div_ceil(ALIGN) * ALIGN;
+        let mut out = Vec::with_capacity(len);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&(len as u16).to_le_bytes());
+        out.extend_from_slice(&(self.dim as u32).to_le_bytes());
+        out.push(self.element.code());
+        out.push(self.metric.code());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(&self.scale.to_le_bytes());
+        out.extend_from_slice(&self.offset.to_le_bytes());
+        out.extend_from_slice(&(model.len() as u16).to_le_bytes());
+        out.extend_from_slice(model);
+        out.resize(len, 0);
+        out
+    }
+
+    /// Parses the header at the start of `bytes`. Returns `Ok(None)` for a
+    /// legacy headerless file, otherwise the header and the data offset.
+    pub fn decode(bytes: &[u8], path: &Path) -> Result<Option<(Self, usize)>, StorageError> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Ok(None);
+        }
+        let corrupt = |reason: &str| StorageError::CorruptHeader { path: path.to_path_buf(), reason: reason.to_string() };
+        if bytes.len() < FIXED_LEN {
+            return Err(corrupt("header is truncated"));
+        }
+        let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().expect("4 bytes"));
+        let version = u16_at(4);
+        if version > VERSION {
+            return Err(StorageError::UnsupportedVersion { path: path.to_path_buf(), found: version, supported: VERSION });
+        }
+        let len = u16_at(6) as usize;
+        let model_len = u16_at(24) as usize;
+        if len < FIXED_LEN + model_len || !len.is_multiple_of(ALIGN) || bytes.len() < len {
+            return Err(corrupt("header length is inconsistent"));
+        }
+        let element = ElementType::from_code(bytes[12]).ok_or_else(|| corrupt("unknown element type"))?;
+        let metric = Metric::from_code(bytes[13]).ok_or_else(|| corrupt("unknown metric"))?;
+        let model_id = std::str::from_utf8(&bytes[FIXED_LEN..FIXED_LEN + model_len])
+            .map_err(|_| corrupt("model id is not UTF-8"))?
+            .to_string();
+        let header = Self {
+            dim: u32_at(8) as usize,
+            element,
+            scale: f32::from_bits(u32_at(16)),
+            offset: f32::from_bits(u32_at(20)),
+            metric,
+            model_id,
+        };
+        Ok(Some((header, len)))
+    }
+
+    /// Fails with the first field of `self` (read from `path`) that differs
+    /// from `expected`.
+    pub fn check(&self, expected: &Self, path: &Path) -> Result<(), StorageError> {
+        let mismatch = |field: &'static str, found: String, expected: String| {
+            Err(StorageError::HeaderMismatch { path: path.to_path_buf(), field, found, expected })
+        };
+        if self.dim != expected.dim {
+            return mismatch("dimension", self.dim.to_string(), expected.dim.to_string());
+        }
+        if self.element != expected.element {
+            return mismatch("element type", self.element.to_string(), expected.element.to_string());
+        }
+        if self.scale != expected.scale || self.offset != expected.offset {
+            return mismatch(
+                "quantization",
+                format!("scale {} offset {}", self.scale, self.offset),
+                format!("scale {} offset {}", expected.scale, expected.offset),
+            );
+        }
+        if self.metric != expected.metric {
+            return mismatch("metric", self.metric.to_string(), expected.metric.to_string());
+        }
+        if self.model_id != expected.model_id {
+            return mismatch("embedding model", format!("'{}'", self.model_id), format!("'{}'", expected.model_id));
+        }
+        Ok(())
+    }
+}
+
+/// Makes sure `path` is a vector file matching `expected` and returns the
+/// offset its vectors start at. A missing or empty file gets a fresh header;
+/// a legacy headerless file is rewritten with one (assuming it was written
+/// for `expected`), dropping a torn trailing vector.
+pub fn prepare(path: &Path, expected: &VectorHeader) -> Result<usize, StorageError> {
+    let io = |source: std::io::Error| StorageError::Io { path: path.to_path_buf(), source };
+    let mut head = Vec::new();
+    if path.exists() {
+        File::open(path)
+            .map_err(io)?
+            .take(u16::MAX as u64)
+            .read_to_end(&mut head)
+            .map_err(io)?;
+    }
+    if let Some((header, offset)) = VectorHeader::decode(&head, path)? {
+        header.check(expected, path)?;
+        return Ok(offset);
+    }
+
+    let header = expected.encode();
+    if head.is_empty() {
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(path).map_err(io)?;
+        file.write_all(&header).map_err(io)?;
+        file.sync_all().map_err(io)?;
+        return Ok(header.len());
+    }
+
+    tracing::info!("Adding a format header to legacy vector file {:?}", path);
+    let data = std::fs::read(path).map_err(io)?;
+    let usable = data.len() - data.len() % expected.row_size();
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp = std::path::PathBuf::from(tmp_name);
+    {
+        let mut file = File::create(&tmp).map_err(io)?;
+        file.write_all(&header).map_err(io)?;
+        file.write_all(&data[..usable]).map_err(io)?;
+        file.sync_all().map_err(io)?;
+    }
+    std::fs::rename(&tmp, path).map_err(io)?;
+    Ok(header.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_round_trip_and_report_mismatches() {
+        let header = VectorHeader::quantized_i8(&VectorSpec::new(384, "all-MiniLM-L6-v2"), 127.0);
+        let bytes = header.encode();
+        assert_eq!(bytes.len() % ALIGN, 0);
+        let path = Path::new("reviews.vectors");
+        let (decoded, offset) = VectorHeader::decode(&bytes, path).unwrap().unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(offset, bytes.len());
+        assert!(VectorHeader::decode(&[1, 2, 3, 4, 5], path).unwrap().is_none());
+
+        let other = VectorHeader::quantized_i8(&VectorSpec::new(128, "all-MiniLM-L6-v2"), 127.0);
+        let err = decoded.check(&other, path).unwrap_err();
+        assert!(matches!(err, StorageError::HeaderMismatch { field: "dimension", .. }), "{}", err);
+    }
+
+    #[test]
+    fn legacy_files_are_migrated_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reviews.vectors");
+        let expected = VectorHeader::quantized_i8(&VectorSpec::new(4, "model"), 127.0);
+        std::fs::write(&path, [1u8, 2, 3, 4, 5, 6, 7, 8, 9]).unwrap();
+
+        let offset = prepare(&path, &expected).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(&data[offset..], &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(prepare(&path, &expected).unwrap(), offset);
+
+        let other_model = VectorHeader::quantized_i8(&VectorSpec::new(4, "other"), 127.0);
+        assert!(matches!(
+            prepare(&path, &other_model),
+            Err(StorageError::HeaderMismatch { field: "embedding model", .. })
+        ));
+    }
+}
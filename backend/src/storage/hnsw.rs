@@ -0,0 +1,662 @@
+use anyhow::Result;
+use ordered_float::OrderedFloat;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::header::{self, VectorHeader, VectorSpec};
+use super::index::RowFilter;
+use super::kernels::{Kernel, QuantizedQuery};
+use super::tombstones::Tombstones;
+
+const GRAPH_MAGIC: &[u8; 4] = b"HNSW";
+const GRAPH_VERSION: u32 = 1;
+const MAX_LEVEL: usize = 16;
+const NO_ENTRY: u32 = u32::MAX;
+/// Number of appends after which the graph snapshot is rewritten. Vectors are
+/// always durable; anything newer than the snapshot is re-inserted on open.
+const CHECKPOINT_EVERY: usize = 10_000;
+
+/// Build and query parameters for [`HnswIndex`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HnswConfig {
+    /// Maximum number of links per node on the upper layers (layer 0 keeps `2 * m`).
+    pub m: usize,
+    /// Candidate list size used while inserting.
+    pub ef_construction: usize,
+    /// Default candidate list size used while searching.
+    pub ef_search: usize,
+}
+
+impl Default for HnswConfig {
+    fn default() -> Self {
+        Self { m: 16, ef_construction: 200, ef_search: 64 }
+    }
+}
+
+impl HnswConfig {
+    /// Reads `HNSW_M`, `HNSW_EF_CONSTRUCTION` and `HNSW_EF_SEARCH`, falling back
+    /// to the defaults for anything unset or unparsable.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let read = |name: &str, fallback: usize| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .filter(|v| *v > 0)
+                .unwrap_or(fallback)
+        };
+        Self {
+            m: read("HNSW_M", default.m).max(2),
+            ef_construction: read("HNSW_EF_CONSTRUCTION", default.ef_construction),
+            ef_search: read("HNSW_EF_SEARCH", default.ef_search),
+        }
+    }
+}
+
+/// Hierarchical navigable small world index over the quantized vector file.
+///
+/// Vectors are stored like the flat `VectorStore` (normalized, `i8`, scale
+/// 127) but in a single `reviews.vectors` file. The graph lives in a
+/// `<path>.hnsw` sidecar that is rebuilt for any vectors it does not cover.
+/// Deleted nodes stay in the graph for navigation but are never returned.
+#[derive(Debug)]
+pub struct HnswIndex {
+    path: PathBuf,
+    graph_path: PathBuf,
+    dim: usize,
+    scale: f32,
+    config: HnswConfig,
+    kernel: Kernel,
+    vectors: Vec<i8>,
+    /// `links[node][layer]` holds the neighbour ids of `node` on `layer`.
+    links: Vec<Vec<Vec<u32>>>,
+    entry_point: u32,
+    max_level: usize,
+    unsaved: usize,
+    tombstones: Tombstones,
+}
+
+type Scored = (OrderedFloat<f32>, u32);
+
+impl HnswIndex {
+    pub fn open_or_create(path: PathBuf, spec: &VectorSpec) -> Result<Self> {
+        Self::open_with_config(path, spec, HnswConfig::from_env())
+    }
+
+    pub fn open_with_config(path: PathBuf, spec: &VectorSpec, config: HnswConfig) -> Result<Self> {
+        let graph_path = graph_path_for(&path);
+        let dim = spec.dim;
+        let data_offset = header::prepare(&path, &VectorHeader::quantized_i8(spec, 127.0))?;
+        let data = std::fs::read(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to read vector store file: {}", e))?;
+        let data = &data[data_offset..];
+        let usable = data.len() - data.len() % dim;
+        if usable < data.len() {
+            // Drop a torn trailing vector so later appends stay aligned.
+            OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .and_then(|f| f.set_len((data_offset + usable) as u64))
+                .map_err(|e| anyhow::anyhow!("Failed to truncate vector store file: {}", e))?;
+        }
+        let vectors: Vec<i8> = bytemuck::cast_slice(&data[..usable]).to_vec();
+        let tombstones = Tombstones::open(Tombstones::path_for(&path))?;
+        let mut index = Self {
+            path,
+            graph_path,
+            dim,
+            scale: 127.0,
+            config,
+            kernel: Kernel::detect(),
+            vectors,
+            links: Vec::new(),
+            entry_point: NO_ENTRY,
+            max_level: 0,
+            unsaved: 0,
+            tombstones,
+        };
+        match index.load_graph() {
+            Ok(true) => {}
+            Ok(false) => tracing::info!("HNSW graph snapshot missing or stale, rebuilding"),
+            Err(e) => tracing::warn!("Failed to load HNSW graph snapshot, rebuilding: {}", e),
+        }
+        let total = index.vectors.len() / dim;
+        let covered = index.links.len();
+        for node in covered..total {
+            index.insert(node as u32);
+        }
+        if total > covered {
+            index.save_graph()?;
+        }
+        Ok(index)
+    }
+
+    pub fn config(&self) -> HnswConfig {
+        self.config
+    }
+
+    /// Changes the default `ef` used by [`HnswIndex::search`].
+    pub fn set_ef_search(&mut self, ef_search: usize) {
+        self.config.ef_search = ef_search.max(1);
+    }
+
+    pub fn append(&mut self, vector: &[f32]) -> Result<()> {
+        header::check_dim(self.dim, vector.len())?;
+        let norm: f32 = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+        let norm = if norm == 0.0 { 1.0 } else { norm };
+        let quantized: Vec<i8> = vector
+            .iter()
+            .map(|&v| ((v / norm).clamp(-1.0, 1.0) * self.scale).round() as i8)
+            .collect();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .map_err(|e| anyhow::anyhow!("Failed to open vector store file: {}", e))?;
+        file.write_all(bytemuck::cast_slice(&quantized))
+            .map_err(|e| anyhow::anyhow!("Failed to write vector to file: {}", e))?;
+        file.sync_all()
+            .map_err(|e| anyhow::anyhow!("Failed to sync vector store to disk: {}", e))?;
+
+        let node = self.links.len() as u32;
+        self.vectors.extend_from_slice(&quantized);
+        self.insert(node);
+        self.unsaved += 1;
+        if self.unsaved >= CHECKPOINT_EVERY {
+            self.save_graph()?;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.links.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.links.is_empty())
+    }
+
+    /// The normalized vector of `node`, dequantized.
+    pub fn get_vector(&self, node: usize) -> Option<Vec<f32>> {
+        let vector = self.vectors.get(node * self.dim..(node + 1) * self.dim)?;
+        Some(vector.iter().map(|&q| q as f32 / self.scale).collect())
+    }
+
+    /// Tombstones `node`; it keeps routing searches but is no longer returned.
+    pub fn delete(&mut self, node: usize) -> Result<()> {
+        if node >= self.links.len() {
+            anyhow::bail!("Vector row {} does not exist", node);
+        }
+        self.tombstones.mark(node)?;
+        Ok(())
+    }
+
+    pub fn search(&self, query: &[f32], top_k: usize) -> Result<Vec<(usize, f32)>> {
+        self.search_with_ef(query, top_k, self.config.ef_search)
+    }
+
+    /// Searches with an explicit candidate list size; larger `ef` trades
+    /// latency for recall. `ef` is raised to `top_k` if smaller.
+    pub fn search_with_ef(&self, query: &[f32], top_k: usize, ef: usize) -> Result<Vec<(usize, f32)>> {
+        self.search_filtered_with_ef(query, top_k, ef, &|_| true)
+    }
+
+    /// Searches only among rows `accept` returns `true` for. Rejected nodes
+    /// still route the search, so a selective filter costs recall rather than
+    /// correctness; `ef` grows with `top_k` to compensate.
+    pub fn search_filtered(&self, query: &[f32], top_k: usize, accept: &RowFilter) -> Result<Vec<(usize, f32)>> {
+        let ef = self.config.ef_search.max(top_k * 4);
+        self.search_filtered_with_ef(query, top_k, ef, accept)
+    }
+
+    fn search_filtered_with_ef(&self, query: &[f32], top_k: usize, ef: usize, accept: &RowFilter) -> Result<Vec<(usize, f32)>> {
+        assert!(top_k > 0, "top_k must be > 0");
+        header::check_dim(self.dim, query.len())?;
+        if self.entry_point == NO_ENTRY {
+            return Ok(Vec::new());
+        }
+        let query = QuantizedQuery::new(query, self.scale);
+        let entry = self.greedy_descent(&query, self.max_level, 1);
+        let accept = |node: u32| !self.tombstones.contains(node as usize) && accept(node as usize);
+        let found = self.search_layer(&query, &[entry], ef.max(top_k), 0, &accept);
+        Ok(found
+            .into_iter()
+            .take(top_k)
+            .map(|(s, id)| (id as usize, s.into_inner()))
+            .collect())
+    }
+
+    /// Writes the graph snapshot so the next open does not have to re-insert.
+    pub fn flush(&mut self) -> Result<()> {
+        self.save_graph()
+    }
+
+    fn vector(&self, node: u32) -> &[i8] {
+        let start = node as usize * self.dim;
+        &self.vectors[start..start + self.dim]
+    }
+
+    fn similarity(&self, query: &QuantizedQuery, node: u32) -> OrderedFloat<f32> {
+        OrderedFloat(query.score(self.kernel, self.vector(node)))
+    }
+
+    /// A stored node used as a query, for node-to-node similarities.
+    fn node_query(&self, node: u32) -> QuantizedQuery {
+        QuantizedQuery {
+            values: self.vector(node).to_vec(),
+            factor: 1.0 / (self.scale * self.scale),
+        }
+    }
+
+    fn max_links(&self, layer: usize) -> usize {
+        if layer == 0 { self.config.m * 2 } else { self.config.m }
+    }
+
+    fn random_level(&self, node: u32) -> usize {
+        let h = splitmix64(node as u64 ^ 0x5eed_4e5f_0000_0000);
+        let unit = ((h >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        let ml = 1.0 / (self.config.m as f64).ln();
+        ((-unit.ln() * ml).floor() as usize).min(MAX_LEVEL)
+    }
+
+    /// Greedy walk from the entry point through layers `from_level` down to
+    /// `to_level`, returning the closest node found.
+    fn greedy_descent(&self, query: &QuantizedQuery, from_level: usize, to_level: usize) -> Scored {
+        let mut best = (self.similarity(query, self.entry_point), self.entry_point);
+        for layer in (to_level..=from_level).rev() {
+            loop {
+                let mut improved = false;
+                for &nb in &self.links[best.1 as usize][layer] {
+                    let s = self.similarity(query, nb);
+                    if s > best.0 {
+                        best = (s, nb);
+                        improved = true;
+                    }
+                }
+                if !improved {
+                    break;
+                }
+            }
+        }
+        best
+    }
+
+    /// Beam search on one layer. Returns up to `ef` nodes accepted by
+    /// `accept`, best first; rejected nodes are still walked through.
+    fn search_layer(
+        &self,
+        query: &QuantizedQuery,
+        entry: &[Scored],
+        ef: usize,
+        layer: usize,
+        accept: &dyn Fn(u32) -> bool,
+    ) -> Vec<Scored> {
+        let mut visited = vec![0u64; self.links.len().div_ceil(64)];
+        let mut candidates: BinaryHeap<Scored> = BinaryHeap::new();
+        let mut results: BinaryHeap<Reverse<Scored>> = BinaryHeap::new();
+        for &(s, id) in entry {
+            visited[id as usize / 64] |= 1 << (id % 64);
+            candidates.push((s, id));
+            if accept(id) {
+                results.push(Reverse((s, id)));
+            }
+        }
+        while let Some((s, id)) = candidates.pop() {
+            let worst = results.peek().map(|r| r.0 .0).unwrap_or(OrderedFloat(f32::MIN));
+            if s < worst && results.len() >= ef {
+                break;
+            }
+            for &nb in &self.links[id as usize][layer] {
+                let (word, bit) = (nb as usize / 64, 1u64 << (nb % 64));
+                if visited[word] & bit != 0 {
+                    continue;
+                }
+                visited[word] |= bit;
+                let sim = self.similarity(query, nb);
+                let worst = results.peek().map(|r| r.0 .0).unwrap_or(OrderedFloat(f32::MIN));
+                if results.len() < ef || sim > worst {
+                    candidates.push((sim, nb));
+                    if accept(nb) {
+                        results.push(Reverse((sim, nb)));
+                        if results.len() > ef {
+                            results.pop();
+                        }
+                    }
+                }
+            }
+        }
+        let mut out: Vec<Scored> = results.into_iter().map(|Reverse(s)| s).collect();
+        out.sort_by(|a, b| b.cmp(a));
+        out
+    }
+
+    /// Neighbour selection heuristic from the HNSW paper: keep a candidate only
+    /// if it is closer to the base than to every neighbour already kept, then
+    /// top up with the pruned ones so sparse regions stay connected.
+    fn select_neighbors(&self, candidates: &[Scored], limit: usize) -> Vec<u32> {
+        let mut kept: Vec<u32> = Vec::with_capacity(limit);
+        let mut pruned: Vec<u32> = Vec::new();
+        for &(sim, id) in candidates {
+            if kept.len() >= limit {
+                break;
+            }
+            let query = self.node_query(id);
+            let diverse = kept.iter().all(|&k| self.similarity(&query, k) < sim);
+            if diverse {
+                kept.push(id);
+            } else {
+                pruned.push(id);
+            }
+        }
+        for id in pruned {
+            if kept.len() >= limit {
+                break;
+            }
+            kept.push(id);
+        }
+        kept
+    }
+
+    fn insert(&mut self, node: u32) {
+        let level = self.random_level(node);
+        self.links.push(vec![Vec::new(); level + 1]);
+        if self.entry_point == NO_ENTRY {
+            self.entry_point = node;
+            self.max_level = level;
+            return;
+        }
+        let query = self.node_query(node);
+        let mut entry = vec![self.greedy_descent(&query, self.max_level, level + 1)];
+        for layer in (0..=level.min(self.max_level)).rev() {
+            let candidates = self.search_layer(&query, &entry, self.config.ef_construction, layer, &|_| true);
+            let neighbors = self.select_neighbors(&candidates, self.config.m);
+            for &nb in &neighbors {
+                self.links[nb as usize][layer].push(node);
+                if self.links[nb as usize][layer].len() > self.max_links(layer) {
+                    self.shrink(nb, layer);
+                }
+            }
+            self.links[node as usize][layer] = neighbors;
+            entry = candidates;
+        }
+        if level > self.max_level {
+            self.entry_point = node;
+            self.max_level = level;
+        }
+    }
+
+    fn shrink(&mut self, node: u32, layer: usize) {
+        let query = self.node_query(node);
+        let mut scored: Vec<Scored> = self.links[node as usize][layer]
+            .iter()
+            .map(|&nb| (self.similarity(&query, nb), nb))
+            .collect();
+        scored.sort_by(|a, b| b.cmp(a));
+        self.links[node as usize][layer] = self.select_neighbors(&scored, self.max_links(layer));
+    }
+
+    fn fingerprint(&self, nodes: usize) -> u64 {
+        let bytes: &[u8] = bytemuck::cast_slice(&self.vectors[..nodes * self.dim]);
+        fnv1a(bytes)
+    }
+
+    fn save_graph(&mut self) -> Result<()> {
+        let tmp = self.graph_path.with_extension("hnsw.tmp");
+        {
+            let file = File::create(&tmp)
+                .map_err(|e| anyhow::anyhow!("Failed to create HNSW graph file: {}", e))?;
+            let mut w = BufWriter::new(file);
+            w.write_all(GRAPH_MAGIC)?;
+            for v in [GRAPH_VERSION, self.dim as u32, self.config.m as u32, self.entry_point, self.max_level as u32] {
+                w.write_all(&v.to_le_bytes())?;
+            }
+            w.write_all(&(self.links.len() as u64).to_le_bytes())?;
+            w.write_all(&self.fingerprint(self.links.len()).to_le_bytes())?;
+            for layers in &self.links {
+                w.write_all(&[layers.len() as u8])?;
+                for neighbors in layers {
+                    w.write_all(&(neighbors.len() as u32).to_le_bytes())?;
+                    for nb in neighbors {
+                        w.write_all(&nb.to_le_bytes())?;
+                    }
+                }
+            }
+            w.flush()?;
+            w.get_ref().sync_all()
+                .map_err(|e| anyhow::anyhow!("Failed to sync HNSW graph to disk: {}", e))?;
+        }
+        std::fs::rename(&tmp, &self.graph_path)
+            .map_err(|e| anyhow::anyhow!("Failed to replace HNSW graph file: {}", e))?;
+        self.unsaved = 0;
+        Ok(())
+    }
+
+    /// Loads the graph snapshot. Returns `Ok(false)` when it is missing, was
+    /// built with a different `m`, no longer matches the vector file, or
+    /// names an entry point it does not hold at its top level.
+    fn load_graph(&mut self) -> Result<bool> {
+        if !self.graph_path.exists() {
+            return Ok(false);
+        }
+        let mut r = BufReader::new(File::open(&self.graph_path)?);
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != GRAPH_MAGIC {
+            anyhow::bail!("bad magic in {:?}", self.graph_path);
+        }
+        let version = read_u32(&mut r)?;
+        let dim = read_u32(&mut r)? as usize;
+        let m = read_u32(&mut r)? as usize;
+        let entry_point = read_u32(&mut r)?;
+        let max_level = read_u32(&mut r)? as usize;
+        let nodes = read_u64(&mut r)? as usize;
+        let fingerprint = read_u64(&mut r)?;
+        if version != GRAPH_VERSION || dim != self.dim || m != self.config.m {
+            return Ok(false);
+        }
+        if nodes > self.vectors.len() / self.dim || fingerprint != self.fingerprint(nodes) {
+            return Ok(false);
+        }
+        let mut links = Vec::with_capacity(nodes);
+        for _ in 0..nodes {
+            let mut level = [0u8; 1];
+            r.read_exact(&mut level)?;
+            let mut layers = Vec::with_capacity(level[0] as usize);
+            for _ in 0..level[0] {
+                let count = read_u32(&mut r)? as usize;
+                let mut neighbors = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let nb = read_u32(&mut r)?;
+                    if nb as usize >= nodes {
+                        anyhow::bail!("neighbour {} out of range", nb);
+                    }
+                    neighbors.push(nb);
+                }
+                layers.push(neighbors);
+            }
+            links.push(layers);
+        }
+        if nodes > 0 {
+            // A graph whose entry point it does not hold is rebuilt.
+            let entry = links.get(entry_point as usize).map_or(0, Vec::len);
+            if max_level >= entry {
+                return Ok(false);
+            }
+        }
+        self.links = links;
+        self.entry_point = if nodes == 0 { NO_ENTRY } else { entry_point };
+        self.max_level = max_level;
+        Ok(true)
+    }
+}
+
+impl Drop for HnswIndex {
+    fn drop(&mut self) {
+        if self.unsaved > 0 {
+            if let Err(e) = self.save_graph() {
+                tracing::error!("Failed to save HNSW graph: {}", e);
+            }
+        }
+    }
+}
+
+fn graph_path_for(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".hnsw");
+    PathBuf::from(name)
+}
+
+fn read_u32(r: &mut impl Read) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(r: &mut impl Read) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for chunk in bytes.chunks(8) {
+        let mut word = [0u8; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        hash ^= u64::from_le_bytes(word);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// `n` vectors with components drawn uniformly from `[-1, 1)`, for the
+/// index tests.
+#[cfg(test)]
+pub(crate) fn random_vectors(n: usize, dim: usize, seed: u64) -> Vec<Vec<f32>> {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..n)
+        .map(|_| (0..dim).map(|_| rng.gen_range(-1.0f32..1.0)).collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec() -> VectorSpec {
+        VectorSpec::new(128, "test-model")
+    }
+
+    fn brute_force(index: &HnswIndex, query: &[f32], top_k: usize) -> Vec<usize> {
+        let query = QuantizedQuery::new(query, index.scale);
+        let mut scored: Vec<Scored> = (0..index.links.len() as u32)
+            .map(|id| (index.similarity(&query, id), id))
+            .collect();
+        scored.sort_by(|a, b| b.cmp(a));
+        scored.into_iter().take(top_k).map(|(_, id)| id as usize).collect()
+    }
+
+    #[test]
+    fn recall_against_brute_force() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = HnswConfig { m: 12, ef_construction: 64, ef_search: 128 };
+        let mut index = HnswIndex::open_with_config(dir.path().join("reviews.vectors"), &spec(), config).unwrap();
+        for v in random_vectors(1000, 128, 7) {
+            index.append(&v).unwrap();
+        }
+        let mut hits = 0;
+        let queries = random_vectors(50, 128, 11);
+        for q in &queries {
+            let expected = brute_force(&index, q, 10);
+            let found: Vec<usize> = index.search(q, 10).unwrap().into_iter().map(|(id, _)| id).collect();
+            hits += found.iter().filter(|id| expected.contains(id)).count();
+        }
+        let recall = hits as f32 / (queries.len() * 10) as f32;
+        assert!(recall > 0.9, "recall@10 too low: {}", recall);
+    }
+
+    #[test]
+    fn deleted_nodes_are_skipped_but_still_route() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reviews.vectors");
+        let vectors = random_vectors(500, 128, 5);
+        let mut index = HnswIndex::open_with_config(path.clone(), &spec(), HnswConfig::default()).unwrap();
+        for v in &vectors {
+            index.append(v).unwrap();
+        }
+        for node in (0..500).step_by(2) {
+            index.delete(node).unwrap();
+        }
+        assert!(index.delete(500).is_err());
+        drop(index);
+
+        let index = HnswIndex::open_with_config(path, &spec(), HnswConfig::default()).unwrap();
+        for q in [101, 251, 499] {
+            let results = index.search(&vectors[q], 10).unwrap();
+            assert_eq!(results[0].0, q);
+            assert!(results.iter().all(|(id, _)| id % 2 == 1));
+        }
+    }
+
+    #[test]
+    fn reopen_restores_graph_and_covers_new_vectors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reviews.vectors");
+        let vectors = random_vectors(300, 128, 3);
+        {
+            let mut index = HnswIndex::open_with_config(path.clone(), &spec(), HnswConfig::default()).unwrap();
+            for v in &vectors[..200] {
+                index.append(v).unwrap();
+            }
+        }
+        // Vectors written without going through the graph (e.g. by an older build).
+        {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            for v in &vectors[200..] {
+                let q: Vec<i8> = v.iter().map(|x| (x.clamp(-1.0, 1.0) * 127.0).round() as i8).collect();
+                file.write_all(bytemuck::cast_slice(&q)).unwrap();
+            }
+        }
+        let index = HnswIndex::open_with_config(path, &spec(), HnswConfig::default()).unwrap();
+        assert_eq!(index.len().unwrap(), 300);
+        let results = index.search(&vectors[250], 1).unwrap();
+        assert_eq!(results[0].0, 250);
+    }
+
+    #[test]
+    fn graph_with_a_bad_entry_point_is_rebuilt() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reviews.vectors");
+        let vectors = random_vectors(200, 128, 13);
+        {
+            let mut index = HnswIndex::open_with_config(path.clone(), &spec(), HnswConfig::default()).unwrap();
+            for v in &vectors {
+                index.append(v).unwrap();
+            }
+        }
+        // Entry point and max level sit right after the magic, version, dim and m.
+        let graph = graph_path_for(&path);
+        let mut data = std::fs::read(&graph).unwrap();
+        for (offset, value) in [(16, 0u32), (20, 40)] {
+            data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        std::fs::write(&graph, &data).unwrap();
+
+        let index = HnswIndex::open_with_config(path, &spec(), HnswConfig::default()).unwrap();
+        assert!(!index.is_empty().unwrap());
+        assert!(index.max_level < index.links[index.entry_point as usize].len());
+        assert_eq!(index.search(&vectors[150], 1).unwrap()[0].0, 150);
+    }
+}
@@ -0,0 +1,111 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Row reserved for "no id": deleted placeholders and rows whose line could
+/// not be read when the map was rebuilt.
+pub const NO_ID: u64 = 0;
+
+/// Stable review ids by row, kept as an append-only log of little-endian
+/// `u64`s (one per row) with an in-memory reverse index. An id can own
+/// several rows over its life, e.g. when an update re-appends it; lookups
+/// resolve to the newest one.
+#[derive(Debug)]
+pub struct IdMap {
+    path: PathBuf,
+    file: File,
+    ids: Vec<u64>,
+    rows: HashMap<u64, usize>,
+    next: u64,
+}
+
+impl IdMap {
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let data = if path.exists() {
+            std::fs::read(&path)
+                .map_err(|e| anyhow::anyhow!("Failed to read id map {:?}: {}", path, e))?
+        } else {
+            Vec::new()
+        };
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to open id map {:?}: {}", path, e))?;
+        let mut map = Self { path, file, ids: Vec::new(), rows: HashMap::new(), next: NO_ID + 1 };
+        // A torn trailing entry is ignored and rebuilt from the metadata line.
+        for chunk in data.chunks_exact(8) {
+            map.insert(u64::from_le_bytes(chunk.try_into().expect("chunk of 8 bytes")));
+        }
+        if data.len() % 8 != 0 {
+            map.truncate(map.ids.len())?;
+        }
+        Ok(map)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of rows with a recorded id.
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// The id the next new review should get.
+    pub fn next_id(&self) -> u64 {
+        self.next
+    }
+
+    /// Records the id of the next row. Not synced on its own; the map is
+    /// rebuilt from the metadata lines if it falls behind.
+    pub fn push(&mut self, id: u64) -> Result<()> {
+        self.file.write_all(&id.to_le_bytes())
+            .map_err(|e| anyhow::anyhow!("Failed to write id map: {}", e))?;
+        self.insert(id);
+        Ok(())
+    }
+
+    /// Forgets every row from `len` on.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        self.file.set_len(len as u64 * 8)
+            .map_err(|e| anyhow::anyhow!("Failed to truncate id map: {}", e))?;
+        let ids = std::mem::take(&mut self.ids);
+        self.rows.clear();
+        self.next = NO_ID + 1;
+        for id in ids.into_iter().take(len) {
+            self.insert(id);
+        }
+        Ok(())
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.file.sync_data()
+            .map_err(|e| anyhow::anyhow!("Failed to sync id map: {}", e))
+    }
+
+    /// Id stored for `row`, if it has one.
+    pub fn id(&self, row: usize) -> Option<u64> {
+        self.ids.get(row).copied().filter(|id| *id != NO_ID)
+    }
+
+    /// Newest row holding `id`, deleted or not.
+    pub fn row(&self, id: u64) -> Option<usize> {
+        self.rows.get(&id).copied()
+    }
+
+    fn insert(&mut self, id: u64) {
+        let row = self.ids.len();
+        self.ids.push(id);
+        if id != NO_ID {
+            self.rows.insert(id, row);
+            self.next = self.next.max(id + 1);
+        }
+    }
+}
@@ -0,0 +1,551 @@
+use anyhow::Result;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::header::VectorSpec;
+#[cfg(feature = "spfresh")]
+use super::header::{self, ElementType, Metric, VectorHeader};
+use super::hnsw::HnswIndex;
+use super::segments::{Compacted, CompactionJob};
+#[cfg(feature = "spfresh")]
+use super::tombstones::Tombstones;
+use super::vector_store::VectorStore;
+
+/// Row predicate for [`VectorIndex::search_filtered`].
+pub type RowFilter<'a> = dyn Fn(usize) -> bool + Sync + 'a;
+
+/// Common surface of every vector backend. Rows are addressed by the
+/// position they were appended at.
+pub trait VectorIndex: Send + Sync {
+    fn append(&mut self, vector: &[f32]) -> Result<()>;
+
+    fn append_batch(&mut self, vectors: &[Vec<f32>]) -> Result<()> {
+        for vector in vectors {
+            self.append(vector)?;
+        }
+        Ok(())
+    }
+
+    /// Returns up to `top_k` `(row, score)` pairs, best first.
+    fn search(&self, query: &[f32], top_k: usize) -> Result<Vec<(usize, f32)>>;
+
+    /// Like [`VectorIndex::search`], but only rows `accept` returns `true`
+    /// for are candidates. Backends that scan apply it before top-k
+    /// selection; the default keeps widening a plain search until it has
+    /// `top_k` accepted rows or has seen the whole index.
+    fn search_filtered(&self, query: &[f32], top_k: usize, accept: &RowFilter) -> Result<Vec<(usize, f32)>> {
+        widen_search(self.len()?, top_k, accept, |fetch| self.search(query, fetch))
+    }
+
+    fn len(&self) -> Result<usize>;
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The vector stored at `id`, as the index keeps it (normalized and
+    /// dequantized for the quantized backends). `None` if the row is out of
+    /// range or the backend cannot read vectors back.
+    fn get_vector(&self, id: usize) -> Result<Option<Vec<f32>>> {
+        let _ = id;
+        Ok(None)
+    }
+
+    /// Tombstones a row so searches stop returning it. Row numbers are never
+    /// reused, so later appends keep lining up with the metadata store.
+    fn delete(&mut self, id: usize) -> Result<()> {
+        anyhow::bail!("Deleting row {} is not supported by this vector index", id)
+    }
+
+    /// Persists anything the backend still holds in memory.
+    fn flush(&mut self) -> Result<()>;
+
+    /// Returns a job merging sealed segments, if the backend is segmented and
+    /// has segments worth merging. The job runs without the index lock.
+    fn plan_compaction(&mut self) -> Result<Option<Box<dyn CompactionJob>>> {
+        Ok(None)
+    }
+
+    /// Installs the output of a job from [`VectorIndex::plan_compaction`].
+    fn apply_compaction(&mut self, done: Compacted) -> Result<()> {
+        anyhow::bail!("Compaction of {} segments is not supported by this vector index", done.replaces.len())
+    }
+}
+
+/// Runs `search` for four times `top_k` rows, then four times as many again,
+/// until `top_k` of them pass `accept` or all `len` rows have been seen.
+fn widen_search(
+    len: usize,
+    top_k: usize,
+    accept: &RowFilter,
+    search: impl Fn(usize) -> Result<Vec<(usize, f32)>>,
+) -> Result<Vec<(usize, f32)>> {
+    let mut fetch = top_k.saturating_mul(4).min(len.max(1));
+    loop {
+        let results = search(fetch)?;
+        let seen = results.len();
+        let mut accepted: Vec<(usize, f32)> = results.into_iter().filter(|(row, _)| accept(*row)).collect();
+        if accepted.len() >= top_k || seen < fetch || fetch >= len {
+            accepted.truncate(top_k);
+            return Ok(accepted);
+        }
+        fetch = fetch.saturating_mul(4).min(len);
+    }
+}
+
+/// Vector backend picked at startup through `VECTOR_BACKEND`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexBackend {
+    Flat,
+    Hnsw,
+    Spfresh,
+}
+
+impl Default for IndexBackend {
+    fn default() -> Self {
+        if cfg!(feature = "spfresh") {
+            IndexBackend::Spfresh
+        } else {
+            IndexBackend::Flat
+        }
+    }
+}
+
+impl FromStr for IndexBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "flat" => Ok(IndexBackend::Flat),
+            "hnsw" => Ok(IndexBackend::Hnsw),
+            "spfresh" => Ok(IndexBackend::Spfresh),
+            other => anyhow::bail!("Unknown vector backend '{}', expected flat, hnsw or spfresh", other),
+        }
+    }
+}
+
+impl fmt::Display for IndexBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            IndexBackend::Flat => "flat",
+            IndexBackend::Hnsw => "hnsw",
+            IndexBackend::Spfresh => "spfresh",
+        };
+        f.write_str(name)
+    }
+}
+
+impl IndexBackend {
+    pub fn from_env() -> Result<Self> {
+        Self::select(std::env::var("VECTOR_BACKEND").ok().as_deref())
+    }
+
+    /// The backend named by `value`, or the default when it is unset or blank.
+    fn select(value: Option<&str>) -> Result<Self> {
+        match value {
+            Some(value) if !value.trim().is_empty() => value.parse(),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    /// Path handed to the backend's `open_or_create`.
+    pub fn index_path(&self, data_dir: &Path) -> PathBuf {
+        match self {
+            IndexBackend::Flat => data_dir.join("segments").join("vectors"),
+            IndexBackend::Hnsw => data_dir.join("reviews.vectors"),
+            IndexBackend::Spfresh => data_dir.join("reviews"),
+        }
+    }
+
+    /// Every file or directory the backend keeps in `data_dir`.
+    pub fn files(&self, data_dir: &Path) -> Vec<PathBuf> {
+        match self {
+            IndexBackend::Flat => vec![
+                data_dir.join("segments").join("vectors"),
+                // Pre-segment layout, moved into the first segment on open.
+                data_dir.join("reviews.vectors"),
+                data_dir.join("reviews.vectors.deleted"),
+            ],
+            IndexBackend::Hnsw => vec![
+                data_dir.join("reviews.vectors"),
+                data_dir.join("reviews.vectors.deleted"),
+                data_dir.join("reviews.vectors.hnsw"),
+            ],
+            IndexBackend::Spfresh => vec![
+                data_dir.join("reviews.vectors"),
+                data_dir.join("reviews.metadata"),
+                data_dir.join("reviews.centroids"),
+                data_dir.join("reviews.postings"),
+                data_dir.join("reviews.deleted"),
+            ],
+        }
+    }
+}
+
+/// Opens the backend's index in `data_dir`. Fails if its files were written
+/// for vectors other than `spec` describes.
+pub fn open_index(backend: IndexBackend, data_dir: &Path, spec: &VectorSpec) -> Result<Box<dyn VectorIndex>> {
+    let path = backend.index_path(data_dir);
+    match backend {
+        IndexBackend::Flat => {
+            if VectorStore::migrate_legacy(&data_dir.join("reviews.vectors"), &path, spec)? {
+                tracing::info!("Moved reviews.vectors into the first vector segment");
+            }
+            Ok(Box::new(VectorStore::open_or_create(path, spec)?))
+        }
+        IndexBackend::Hnsw => Ok(Box::new(HnswIndex::open_or_create(path, spec)?)),
+        #[cfg(feature = "spfresh")]
+        IndexBackend::Spfresh => Ok(Box::new(SpfreshIndex::open_or_create(path, spec)?)),
+        #[cfg(not(feature = "spfresh"))]
+        IndexBackend::Spfresh => anyhow::bail!("The spfresh backend requires building with `--features spfresh`"),
+    }
+}
+
+/// Removes the backend's files so the index can be rebuilt from scratch.
+pub fn remove_index(backend: IndexBackend, data_dir: &Path) -> Result<()> {
+    for path in backend.files(data_dir) {
+        if path.is_dir() {
+            std::fs::remove_dir_all(&path)?;
+        } else if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+impl VectorIndex for HnswIndex {
+    fn append(&mut self, vector: &[f32]) -> Result<()> {
+        HnswIndex::append(self, vector)
+    }
+
+    fn search(&self, query: &[f32], top_k: usize) -> Result<Vec<(usize, f32)>> {
+        HnswIndex::search(self, query, top_k)
+    }
+
+    fn len(&self) -> Result<usize> {
+        HnswIndex::len(self)
+    }
+
+    fn search_filtered(&self, query: &[f32], top_k: usize, accept: &RowFilter) -> Result<Vec<(usize, f32)>> {
+        HnswIndex::search_filtered(self, query, top_k, accept)
+    }
+
+    fn get_vector(&self, id: usize) -> Result<Option<Vec<f32>>> {
+        Ok(HnswIndex::get_vector(self, id))
+    }
+
+    fn delete(&mut self, id: usize) -> Result<()> {
+        HnswIndex::delete(self, id)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        HnswIndex::flush(self)
+    }
+}
+
+/// The SPFresh index plus a `reviews.deleted` tombstone log. The C++ index
+/// has no delete, so searches over-fetch by the number of deleted rows and
+/// filter them out.
+#[cfg(feature = "spfresh")]
+pub struct SpfreshIndex {
+    inner: spfresh::Index,
+    dim: usize,
+    tombstones: Tombstones,
+}
+
+#[cfg(feature = "spfresh")]
+impl SpfreshIndex {
+    pub fn open_or_create(path: PathBuf, spec: &VectorSpec) -> Result<Self> {
+        // The C++ index skips this header when it reads its vector log.
+        let header = VectorHeader {
+            dim: spec.dim,
+            element: ElementType::U8,
+            scale: 127.5,
+            offset: -1.0,
+            metric: Metric::Cosine,
+            model_id: spec.model_id.clone(),
+        };
+        let mut log = path.as_os_str().to_owned();
+        log.push(".vectors");
+        header::prepare(Path::new(&log), &header)?;
+        let tombstones = Tombstones::open(Tombstones::path_for(&path))?;
+        let inner = spfresh::Index::open_or_create(path, spec.dim)?;
+        Ok(Self { inner, dim: spec.dim, tombstones })
+    }
+}
+
+#[cfg(feature = "spfresh")]
+impl VectorIndex for SpfreshIndex {
+    fn append(&mut self, vector: &[f32]) -> Result<()> {
+        header::check_dim(self.dim, vector.len())?;
+        self.inner.append(vector)
+    }
+
+    fn search(&self, query: &[f32], top_k: usize) -> Result<Vec<(usize, f32)>> {
+        self.search_filtered(query, top_k, &|_| true)
+    }
+
+    /// Widens like the default, counting tombstoned rows as rejected.
+    fn search_filtered(&self, query: &[f32], top_k: usize, accept: &RowFilter) -> Result<Vec<(usize, f32)>> {
+        header::check_dim(self.dim, query.len())?;
+        let live = |row: usize| !self.tombstones.contains(row) && accept(row);
+        widen_search(self.inner.len(), top_k, &live, |fetch| self.inner.search(query, fetch))
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.inner.len())
+    }
+
+    fn delete(&mut self, id: usize) -> Result<()> {
+        if id >= self.inner.len() {
+            anyhow::bail!("Vector row {} does not exist", id);
+        }
+        self.tombstones.mark(id)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    #[cfg(feature = "spfresh")]
+    use crate::storage::hnsw::random_vectors;
+
+    /// Scores row `r` of `len` rows as `-r`, so searches return rows in order.
+    struct Ordered {
+        len: usize,
+        searches: AtomicUsize,
+    }
+
+    impl VectorIndex for Ordered {
+        fn append(&mut self, _: &[f32]) -> Result<()> {
+            anyhow::bail!("Ordered is read-only")
+        }
+
+        fn search(&self, _: &[f32], top_k: usize) -> Result<Vec<(usize, f32)>> {
+            self.searches.fetch_add(1, Ordering::Relaxed);
+            Ok((0..top_k.min(self.len)).map(|row| (row, -(row as f32))).collect())
+        }
+
+        fn len(&self) -> Result<usize> {
+            Ok(self.len)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn backend_selection() {
+        assert_eq!(IndexBackend::select(None).unwrap(), IndexBackend::default());
+        assert_eq!(IndexBackend::select(Some("  ")).unwrap(), IndexBackend::default());
+        assert_eq!(IndexBackend::select(Some(" HNSW ")).unwrap(), IndexBackend::Hnsw);
+        for backend in [IndexBackend::Flat, IndexBackend::Hnsw, IndexBackend::Spfresh] {
+            assert_eq!(IndexBackend::select(Some(&backend.to_string())).unwrap(), backend);
+        }
+        let err = IndexBackend::select(Some("faiss")).unwrap_err();
+        assert!(err.to_string().contains("Unknown vector backend 'faiss'"));
+    }
+
+    #[test]
+    fn default_search_filtered_widens_until_it_has_top_k() {
+        let index = Ordered { len: 1000, searches: AtomicUsize::new(0) };
+        // One row in 50 passes: 20, then 80 candidates hold too few.
+        let rows: Vec<usize> = index
+            .search_filtered(&[], 5, &|row| row % 50 == 0)
+            .unwrap()
+            .into_iter()
+            .map(|(row, _)| row)
+            .collect();
+        assert_eq!(rows, vec![0, 50, 100, 150, 200]);
+        assert_eq!(index.searches.load(Ordering::Relaxed), 3);
+
+        // Fewer matches than asked for: stops once the whole index was seen.
+        let index = Ordered { len: 1000, searches: AtomicUsize::new(0) };
+        let rows = index.search_filtered(&[], 5, &|row| row == 999).unwrap();
+        assert_eq!(rows, vec![(999, -999.0)]);
+        assert_eq!(index.searches.load(Ordering::Relaxed), 4);
+    }
+
+    /// Exact top `k` rows of `vectors` by cosine similarity.
+    #[cfg(feature = "spfresh")]
+    fn flat_scan(vectors: &[Vec<f32>], query: &[f32], k: usize) -> Vec<usize> {
+        let cosine = |a: &[f32], b: &[f32]| {
+            let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+            dot / (a.iter().map(|x| x * x).sum::<f32>().sqrt() * b.iter().map(|x| x * x).sum::<f32>().sqrt())
+        };
+        let mut scored: Vec<(usize, f32)> = vectors.iter().enumerate().map(|(i, v)| (i, cosine(v, query))).collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.into_iter().take(k).map(|(i, _)| i).collect()
+    }
+
+    #[cfg(feature = "spfresh")]
+    #[test]
+    fn spfresh_recall_against_flat_scan_after_splits() {
+        let dir = tempfile::tempdir().unwrap();
+        // Far more than one posting holds, so the index has split many times.
+        let vectors = random_vectors(5000, 32, 21);
+        let mut index = spfresh::Index::open_or_create(dir.path().join("reviews"), 32).unwrap();
+        for v in &vectors {
+            index.append(v).unwrap();
+        }
+        index.flush().unwrap();
+        assert_eq!(index.len(), 5000);
+
+        let queries = random_vectors(50, 32, 22);
+        let mut hits = 0;
+        for q in &queries {
+            let expected = flat_scan(&vectors, q, 10);
+            let found = index.search(q, 10).unwrap();
+            assert_eq!(found.len(), 10);
+            hits += found.iter().filter(|(id, _)| expected.contains(id)).count();
+        }
+        let recall = hits as f32 / (queries.len() * 10) as f32;
+        assert!(recall > 0.9, "recall@10 too low: {}", recall);
+        // More postings than a search probes (SPFRESH_NPROBE, 32), so the
+        // recall above does not come from scanning everything.
+        let postings = std::fs::read_dir(dir.path().join("reviews.postings")).unwrap().count();
+        assert!(postings > 32, "only {} postings", postings);
+    }
+
+    #[cfg(feature = "spfresh")]
+    #[test]
+    fn spfresh_reopen_returns_the_same_results() {
+        let dir = tempfile::tempdir().unwrap();
+        let vectors = random_vectors(2000, 32, 31);
+        let queries = random_vectors(20, 32, 32);
+        let before: Vec<Vec<(usize, f32)>> = {
+            let mut index = spfresh::Index::open_or_create(dir.path().join("reviews"), 32).unwrap();
+            for v in &vectors {
+                index.append(v).unwrap();
+            }
+            index.flush().unwrap();
+            queries.iter().map(|q| index.search(q, 10).unwrap()).collect()
+        };
+        let index = spfresh::Index::open_or_create(dir.path().join("reviews"), 32).unwrap();
+        assert_eq!(index.len(), 2000);
+        for (q, expected) in queries.iter().zip(&before) {
+            assert_eq!(&index.search(q, 10).unwrap(), expected);
+        }
+    }
+
+    /// Total records across the posting files of an index at `dir/reviews`.
+    #[cfg(feature = "spfresh")]
+    fn posting_records(dir: &Path, dim: usize) -> u64 {
+        std::fs::read_dir(dir.join("reviews.postings"))
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum::<u64>()
+            / (8 + dim as u64)
+    }
+
+    #[cfg(feature = "spfresh")]
+    #[test]
+    fn spfresh_rebuilds_postings_without_stale_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reviews");
+        let vectors = random_vectors(1500, 32, 51);
+        let centroids = dir.path().join("reviews.centroids");
+        let saved = dir.path().join("saved.centroids");
+        {
+            let mut index = spfresh::Index::open_or_create(&path, 32).unwrap();
+            for v in &vectors[..1000] {
+                index.append(v).unwrap();
+            }
+            index.flush().unwrap();
+            std::fs::copy(&centroids, &saved).unwrap();
+            for v in &vectors[1000..] {
+                index.append(v).unwrap();
+            }
+        }
+        // A crash after the postings were written but before the centroids
+        // covering them were saved.
+        std::fs::copy(&saved, &centroids).unwrap();
+        let index = spfresh::Index::open_or_create(&path, 32).unwrap();
+        assert_eq!(index.len(), 1500);
+        let replicated = posting_records(dir.path(), 32);
+        let results = index.search(&vectors[1200], 10).unwrap();
+        assert_eq!(results.iter().filter(|(id, _)| *id == 1200).count(), 1);
+        drop(index);
+
+        // Without centroids everything is partitioned again, into the same
+        // posting ids the old files used.
+        std::fs::remove_file(&centroids).unwrap();
+        let index = spfresh::Index::open_or_create(&path, 32).unwrap();
+        assert_eq!(index.len(), 1500);
+        assert!(posting_records(dir.path(), 32) <= replicated + 1500);
+        for q in [0, 999, 1499] {
+            let results = index.search(&vectors[q], 20).unwrap();
+            assert_eq!(results[0].0, q);
+            assert!(results.iter().all(|(id, _)| *id < 1500));
+            let mut ids: Vec<usize> = results.iter().map(|(id, _)| *id).collect();
+            ids.dedup();
+            assert_eq!(ids.len(), results.len());
+        }
+    }
+
+    #[cfg(feature = "spfresh")]
+    #[test]
+    fn spfresh_deleted_rows_are_not_returned() {
+        let dir = tempfile::tempdir().unwrap();
+        let spec = VectorSpec::new(32, "test-model");
+        let path = IndexBackend::Spfresh.index_path(dir.path());
+        let vectors = random_vectors(1000, 32, 41);
+        {
+            let mut index = SpfreshIndex::open_or_create(path.clone(), &spec).unwrap();
+            for v in &vectors {
+                index.append(v).unwrap();
+            }
+            for row in (0..1000).step_by(2) {
+                index.delete(row).unwrap();
+            }
+            assert!(index.delete(1000).is_err());
+        }
+        let index = SpfreshIndex::open_or_create(path, &spec).unwrap();
+        for q in [101, 501, 999] {
+            let results = index.search(&vectors[q], 10).unwrap();
+            assert_eq!(results.len(), 10);
+            assert_eq!(results[0].0, q);
+            assert!(results.iter().all(|(id, _)| id % 2 == 1));
+        }
+        assert!(index.search(&vectors[100], 10).unwrap().iter().all(|(id, _)| *id != 100));
+    }
+
+    #[cfg(feature = "spfresh")]
+    #[test]
+    fn spfresh_cuts_a_torn_vector_off_its_log() {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let spec = VectorSpec::new(32, "test-model");
+        let path = IndexBackend::Spfresh.index_path(dir.path());
+        let vectors = random_vectors(301, 32, 9);
+        {
+            let mut index = SpfreshIndex::open_or_create(path.clone(), &spec).unwrap();
+            for v in &vectors[..300] {
+                index.append(v).unwrap();
+            }
+            index.flush().unwrap();
+        }
+        let mut log = std::fs::OpenOptions::new().append(true).open(dir.path().join("reviews.vectors")).unwrap();
+        log.write_all(&[7; 5]).unwrap();
+        drop(log);
+
+        {
+            let mut index = SpfreshIndex::open_or_create(path.clone(), &spec).unwrap();
+            assert_eq!(index.len().unwrap(), 300);
+            index.append(&vectors[300]).unwrap();
+        }
+        let index = SpfreshIndex::open_or_create(path, &spec).unwrap();
+        assert_eq!(index.len().unwrap(), 301);
+        assert_eq!(index.search(&vectors[300], 1).unwrap()[0].0, 300);
+        assert_eq!(index.search(&vectors[150], 1).unwrap()[0].0, 150);
+    }
+}
@@ -0,0 +1,287 @@
+//! Integer scoring kernels for `i8`-quantized vectors.
+//!
+//! Queries are quantized to `i8` as well, so every kernel computes the exact
+//! same `i32` dot product and scores are identical whichever kernel runs.
+
+use ordered_float::OrderedFloat;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::OnceLock;
+
+/// Proof that the CPU supports a SIMD kernel. Only [`Kernel::available`]
+/// makes one, after checking, so [`Kernel::dot`] need not check again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checked(());
+
+/// Dot-product implementation picked at runtime from the CPU's features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kernel {
+    Scalar,
+    Avx2(Checked),
+    Avx512Vnni(Checked),
+    Neon(Checked),
+}
+
+impl Kernel {
+    /// Best kernel supported by this CPU, detected once.
+    pub fn detect() -> Kernel {
+        static KERNEL: OnceLock<Kernel> = OnceLock::new();
+        *KERNEL.get_or_init(|| Self::available()[0])
+    }
+
+    /// Every kernel this CPU supports, best first.
+    pub fn available() -> Vec<Kernel> {
+        let mut kernels = Vec::new();
+        #[cfg(target_arch = "x86_64")]
+        {
+            if std::is_x86_feature_detected!("avx512f")
+                && std::is_x86_feature_detected!("avx512bw")
+                && std::is_x86_feature_detected!("avx512vnni")
+            {
+                kernels.push(Kernel::Avx512Vnni(Checked(())));
+            }
+            if std::is_x86_feature_detected!("avx2") {
+                kernels.push(Kernel::Avx2(Checked(())));
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            if std::arch::is_aarch64_feature_detected!("neon") {
+                kernels.push(Kernel::Neon(Checked(())));
+            }
+        }
+        kernels.push(Kernel::Scalar);
+        kernels
+    }
+
+    /// Whether this kernel can run on the current CPU; for debug checks.
+    fn is_supported(self) -> bool {
+        match self {
+            Kernel::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2(_) => std::is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx512Vnni(_) => {
+                std::is_x86_feature_detected!("avx512f")
+                    && std::is_x86_feature_detected!("avx512bw")
+                    && std::is_x86_feature_detected!("avx512vnni")
+            }
+            #[cfg(target_arch = "aarch64")]
+            Kernel::Neon(_) => std::arch::is_aarch64_feature_detected!("neon"),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    /// Exact `i32` dot product of two equally long `i8` slices.
+    pub fn dot(self, a: &[i8], b: &[i8]) -> i32 {
+        assert_eq!(a.len(), b.len(), "dot product of slices with different lengths");
+        debug_assert!(self.is_supported(), "{:?} is not available on this CPU", self);
+        match self {
+            Kernel::Scalar => dot_scalar(a, b),
+            #[cfg(target_arch = "x86_64")]
+            // SAFETY: `Checked` is only made once AVX2 support was detected.
+            Kernel::Avx2(_) => unsafe { x86::dot_avx2(a, b) },
+            #[cfg(target_arch = "x86_64")]
+            // SAFETY: `Checked` is only made once AVX-512F/BW/VNNI support
+            // was detected.
+            Kernel::Avx512Vnni(_) => unsafe { x86::dot_avx512_vnni(a, b) },
+            #[cfg(target_arch = "aarch64")]
+            // SAFETY: `Checked` is only made once NEON support was detected.
+            Kernel::Neon(_) => unsafe { neon::dot_neon(a, b) },
+            #[allow(unreachable_patterns)]
+            other => unreachable!("{:?} kernel on the wrong architecture", other),
+        }
+    }
+}
+
+/// Reference kernel every SIMD kernel must agree with.
+pub fn dot_scalar(a: &[i8], b: &[i8]) -> i32 {
+    a.iter().zip(b).map(|(&x, &y)| x as i32 * y as i32).sum()
+}
+
+/// A query quantized for the integer kernels.
+#[derive(Debug, Clone)]
+pub struct QuantizedQuery {
+    pub values: Vec<i8>,
+    /// Multiplies an integer dot product into a similarity score.
+    pub factor: f32,
+}
+
+impl QuantizedQuery {
+    /// Quantizes `query` symmetrically to `[-127, 127]`. `store_scale` is the
+    /// scale the stored vectors were quantized with.
+    pub fn new(query: &[f32], store_scale: f32) -> Self {
+        let max = query
+            .iter()
+            .filter(|v| v.is_finite())
+            .fold(0.0f32, |m, v| m.max(v.abs()));
+        if max == 0.0 {
+            return Self { values: vec![0; query.len()], factor: 0.0 };
+        }
+        let values = query
+            .iter()
+            .map(|v| if v.is_finite() { (v / max * 127.0).round() as i8 } else { 0 })
+            .collect();
+        Self { values, factor: max / 127.0 / store_scale }
+    }
+
+    pub fn score(&self, kernel: Kernel, vector: &[i8]) -> f32 {
+        kernel.dot(&self.values, vector) as f32 * self.factor
+    }
+}
+
+/// Bounded top-k collector. Ties are broken towards the lower row id so the
+/// kept set does not depend on the order rows are offered in.
+#[derive(Debug, Clone)]
+pub struct TopK {
+    k: usize,
+    heap: BinaryHeap<Reverse<(OrderedFloat<f32>, Reverse<usize>)>>,
+}
+
+impl TopK {
+    pub fn new(k: usize) -> Self {
+        Self { k, heap: BinaryHeap::with_capacity(k + 1) }
+    }
+
+    pub fn push(&mut self, score: f32, id: usize) {
+        let entry = Reverse((OrderedFloat(score), Reverse(id)));
+        if self.heap.len() < self.k {
+            self.heap.push(entry);
+        } else if let Some(worst) = self.heap.peek() {
+            if entry < *worst {
+                self.heap.pop();
+                self.heap.push(entry);
+            }
+        }
+    }
+
+    pub fn merge(mut self, other: TopK) -> TopK {
+        for Reverse((score, Reverse(id))) in other.heap {
+            self.push(score.into_inner(), id);
+        }
+        self
+    }
+
+    /// Results sorted by score descending, then by id ascending.
+    pub fn into_sorted_vec(self) -> Vec<(usize, f32)> {
+        let mut results: Vec<(usize, f32)> = self
+            .heap
+            .into_iter()
+            .map(|Reverse((score, Reverse(id)))| (id, score.into_inner()))
+            .collect();
+        results.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        results
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn dot_avx2(a: &[i8], b: &[i8]) -> i32 {
+        let chunks = a.len() / 32;
+        let mut acc = _mm256_setzero_si256();
+        for i in 0..chunks {
+            let va = _mm256_loadu_si256(a.as_ptr().add(i * 32) as *const __m256i);
+            let vb = _mm256_loadu_si256(b.as_ptr().add(i * 32) as *const __m256i);
+            // Widen to i16 and multiply-add pairs into i32 lanes; exact for i8 inputs.
+            let a_lo = _mm256_cvtepi8_epi16(_mm256_castsi256_si128(va));
+            let a_hi = _mm256_cvtepi8_epi16(_mm256_extracti128_si256::<1>(va));
+            let b_lo = _mm256_cvtepi8_epi16(_mm256_castsi256_si128(vb));
+            let b_hi = _mm256_cvtepi8_epi16(_mm256_extracti128_si256::<1>(vb));
+            acc = _mm256_add_epi32(acc, _mm256_madd_epi16(a_lo, b_lo));
+            acc = _mm256_add_epi32(acc, _mm256_madd_epi16(a_hi, b_hi));
+        }
+        let mut lanes = [0i32; 8];
+        _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, acc);
+        let tail = chunks * 32;
+        lanes.iter().sum::<i32>() + super::dot_scalar(&a[tail..], &b[tail..])
+    }
+
+    /// `vpdpbusd` multiplies unsigned by signed bytes, so `a` is shifted by
+    /// 128 into the unsigned range and `128 * sum(b)` is subtracted again.
+    #[target_feature(enable = "avx512f,avx512bw,avx512vnni")]
+    pub unsafe fn dot_avx512_vnni(a: &[i8], b: &[i8]) -> i32 {
+        let chunks = a.len() / 64;
+        let bias = _mm512_set1_epi8(-128);
+        let ones = _mm512_set1_epi8(1);
+        let mut acc = _mm512_setzero_si512();
+        let mut sum_b = _mm512_setzero_si512();
+        for i in 0..chunks {
+            let va = _mm512_loadu_si512(a.as_ptr().add(i * 64) as *const _);
+            let vb = _mm512_loadu_si512(b.as_ptr().add(i * 64) as *const _);
+            let ua = _mm512_xor_si512(va, bias);
+            acc = _mm512_dpbusd_epi32(acc, ua, vb);
+            sum_b = _mm512_dpbusd_epi32(sum_b, ones, vb);
+        }
+        let dot = _mm512_reduce_add_epi32(acc) - 128 * _mm512_reduce_add_epi32(sum_b);
+        let tail = chunks * 64;
+        dot + super::dot_scalar(&a[tail..], &b[tail..])
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn dot_neon(a: &[i8], b: &[i8]) -> i32 {
+        let chunks = a.len() / 16;
+        let mut acc = vdupq_n_s32(0);
+        for i in 0..chunks {
+            let va = vld1q_s8(a.as_ptr().add(i * 16));
+            let vb = vld1q_s8(b.as_ptr().add(i * 16));
+            // i8 * i8 fits in i16; pairwise-accumulate into i32 lanes.
+            let lo = vmull_s8(vget_low_s8(va), vget_low_s8(vb));
+            let hi = vmull_high_s8(va, vb);
+            acc = vpadalq_s16(acc, lo);
+            acc = vpadalq_s16(acc, hi);
+        }
+        let tail = chunks * 16;
+        vaddvq_s32(acc) + super::dot_scalar(&a[tail..], &b[tail..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn simd_kernels_match_scalar_exactly() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for len in [0, 1, 15, 16, 31, 32, 63, 64, 100, 128, 384, 768, 1000] {
+            let a: Vec<i8> = (0..len).map(|_| rng.gen()).collect();
+            let b: Vec<i8> = (0..len).map(|_| rng.gen()).collect();
+            let expected = dot_scalar(&a, &b);
+            for kernel in Kernel::available() {
+                assert_eq!(kernel.dot(&a, &b), expected, "{:?} len {}", kernel, len);
+            }
+        }
+        // Extremes, including -128 which has no positive counterpart.
+        let a = vec![-128i8; 128];
+        let b = vec![-128i8; 128];
+        for kernel in Kernel::available() {
+            assert_eq!(kernel.dot(&a, &b), 128 * 128 * 128, "{:?}", kernel);
+        }
+    }
+
+    #[test]
+    fn top_k_merge_is_order_independent() {
+        let scores = [0.5, 0.9, 0.5, 0.1, 0.9, 0.7, 0.5];
+        let mut sequential = TopK::new(4);
+        for (id, s) in scores.iter().enumerate() {
+            sequential.push(*s, id);
+        }
+        let mut left = TopK::new(4);
+        let mut right = TopK::new(4);
+        for (id, s) in scores.iter().enumerate().rev() {
+            if id % 2 == 0 { left.push(*s, id) } else { right.push(*s, id) }
+        }
+        let merged = right.merge(left).into_sorted_vec();
+        assert_eq!(merged, sequential.into_sorted_vec());
+        assert_eq!(merged, vec![(1, 0.9), (4, 0.9), (5, 0.7), (0, 0.5)]);
+    }
+}
@@ -0,0 +1,338 @@
+//! BM25 inverted index over review titles and bodies.
+//!
+//! The metadata store feeds every appended line into a [`LexicalIndex`] and
+//! tells it about deletes, so lexical search always covers the whole corpus.
+//! Postings live in memory and are written to a `lexical` snapshot when the
+//! store is flushed:
+//!
+//! ```text
+//! "BM25" | version u32 | rows u64 | rows * doc_len u32
+//!        | terms u64 | terms * (len u16 | term | n u32 | n * (row u32 | tf u32))
+//! ```
+//!
+//! On open the snapshot is loaded and rows appended after it was written are
+//! indexed again from their metadata lines, the same catch-up the HNSW graph
+//! does against its vector file.
+
+use anyhow::Result;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"BM25";
+const VERSION: u32 = 1;
+
+/// Fields whose text is indexed.
+const FIELDS: &[&str] = &["review_title", "review_body"];
+
+/// Tokens longer than this are dropped; they are almost always noise such as
+/// URLs or base64 and would only bloat the term dictionary.
+const MAX_TOKEN_BYTES: usize = 64;
+
+/// Term frequency saturation.
+const K1: f32 = 1.2;
+/// Document length normalization.
+const B: f32 = 0.75;
+
+/// Lowercased alphanumeric runs of `text`.
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty() && t.len() <= MAX_TOKEN_BYTES)
+        .map(|t| t.to_lowercase())
+}
+
+/// Distinct query terms that occur in `text`, in query order.
+pub fn matched_terms(query: &str, text: &str) -> Vec<String> {
+    let tokens: std::collections::HashSet<String> = tokenize(text).collect();
+    let mut matched: Vec<String> = Vec::new();
+    for term in tokenize(query) {
+        if tokens.contains(&term) && !matched.contains(&term) {
+            matched.push(term);
+        }
+    }
+    matched
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Posting {
+    row: u32,
+    tf: u32,
+}
+
+#[derive(Debug)]
+pub struct LexicalIndex {
+    path: PathBuf,
+    postings: HashMap<Box<str>, Vec<Posting>>,
+    /// Token count per row; 0 for rows without text and removed rows.
+    doc_lens: Vec<u32>,
+    total_len: u64,
+    docs: usize,
+    /// Whether anything changed since the snapshot was written.
+    dirty: bool,
+}
+
+impl LexicalIndex {
+    /// Loads the snapshot at `path`. A missing or unreadable snapshot gives an
+    /// empty index, which the metadata store then fills from its lines.
+    pub fn open(path: PathBuf) -> Self {
+        let mut index = Self::new(path);
+        if index.path.exists() {
+            if let Err(e) = index.load() {
+                tracing::warn!("Rebuilding lexical index, snapshot {:?} is unusable: {}", index.path, e);
+                index = Self::new(index.path);
+            }
+        }
+        index
+    }
+
+    /// An empty index that snapshots to `path`.
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, postings: HashMap::new(), doc_lens: Vec::new(), total_len: 0, docs: 0, dirty: true }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of rows indexed, removed ones included.
+    pub fn len(&self) -> usize {
+        self.doc_lens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.doc_lens.is_empty()
+    }
+
+    /// Drops every row, e.g. when the snapshot is ahead of the metadata store.
+    pub fn clear(&mut self) {
+        *self = Self::new(std::mem::take(&mut self.path));
+    }
+
+    /// Indexes the next row from its JSON line.
+    pub fn push_line(&mut self, line: &str) {
+        let row = self.doc_lens.len() as u32;
+        let value = serde_json::from_str::<Value>(line).unwrap_or(Value::Null);
+        let mut counts: HashMap<String, u32> = HashMap::new();
+        for field in FIELDS {
+            if let Some(text) = value.get(*field).and_then(Value::as_str) {
+                for token in tokenize(text) {
+                    *counts.entry(token).or_insert(0) += 1;
+                }
+            }
+        }
+        let len: u32 = counts.values().sum();
+        for (term, tf) in counts {
+            self.postings.entry(term.into_boxed_str()).or_default().push(Posting { row, tf });
+        }
+        self.doc_lens.push(len);
+        if len > 0 {
+            self.total_len += len as u64;
+            self.docs += 1;
+        }
+        self.dirty = true;
+    }
+
+    /// Records a row without text.
+    pub fn push_missing(&mut self) {
+        self.doc_lens.push(0);
+        self.dirty = true;
+    }
+
+    /// Takes a row out of search results and corpus statistics. Its postings
+    /// are dropped with the next snapshot.
+    pub fn remove(&mut self, row: usize) {
+        let Some(len) = self.doc_lens.get_mut(row) else {
+            return;
+        };
+        if *len > 0 {
+            self.total_len -= *len as u64;
+            self.docs -= 1;
+            *len = 0;
+            self.dirty = true;
+        }
+    }
+
+    /// Returns up to `top_k` `(row, score)` pairs by BM25 over the whole
+    /// index, best first. Only rows `accept` returns `true` for are scored.
+    pub fn search(&self, query: &str, top_k: usize, accept: &dyn Fn(usize) -> bool) -> Vec<(usize, f32)> {
+        if self.docs == 0 || top_k == 0 {
+            return Vec::new();
+        }
+        let mut terms: Vec<String> = tokenize(query).collect();
+        terms.sort_unstable();
+        terms.dedup();
+
+        let n = self.docs as f32;
+        let avgdl = self.total_len as f32 / n;
+        let mut scores: HashMap<u32, f32> = HashMap::new();
+        for term in &terms {
+            let Some(postings) = self.postings.get(term.as_str()) else {
+                continue;
+            };
+            // Postings of removed rows count towards df until the next
+            // snapshot prunes them, which only lowers idf slightly.
+            let df = postings.len() as f32;
+            let idf = (1.0 + (n - df + 0.5) / (df + 0.5)).ln();
+            for posting in postings {
+                let dl = self.doc_lens[posting.row as usize];
+                if dl == 0 || !accept(posting.row as usize) {
+                    continue;
+                }
+                let tf = posting.tf as f32;
+                let norm = K1 * (1.0 - B + B * dl as f32 / avgdl);
+                *scores.entry(posting.row).or_insert(0.0) += idf * tf * (K1 + 1.0) / (tf + norm);
+            }
+        }
+
+        let mut results: Vec<(usize, f32)> = scores.into_iter().map(|(row, s)| (row as usize, s)).collect();
+        let by_score = |a: &(usize, f32), b: &(usize, f32)| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0));
+        if results.len() > top_k {
+            results.select_nth_unstable_by(top_k - 1, by_score);
+            results.truncate(top_k);
+        }
+        results.sort_unstable_by(by_score);
+        results
+    }
+
+    /// Writes the snapshot if anything changed since the last one, pruning
+    /// postings of removed rows on the way.
+    pub fn save(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let doc_lens = &self.doc_lens;
+        self.postings.retain(|_, postings| {
+            postings.retain(|p| doc_lens[p.row as usize] > 0);
+            !postings.is_empty()
+        });
+
+        let tmp = self.path.with_extension("tmp");
+        {
+            let file = File::create(&tmp)
+                .map_err(|e| anyhow::anyhow!("Failed to create lexical index file: {}", e))?;
+            let mut w = BufWriter::new(file);
+            w.write_all(MAGIC)?;
+            w.write_all(&VERSION.to_le_bytes())?;
+            w.write_all(&(self.doc_lens.len() as u64).to_le_bytes())?;
+            for len in &self.doc_lens {
+                w.write_all(&len.to_le_bytes())?;
+            }
+            w.write_all(&(self.postings.len() as u64).to_le_bytes())?;
+            for (term, postings) in &self.postings {
+                w.write_all(&(term.len() as u16).to_le_bytes())?;
+                w.write_all(term.as_bytes())?;
+                w.write_all(&(postings.len() as u32).to_le_bytes())?;
+                for p in postings {
+                    w.write_all(&p.row.to_le_bytes())?;
+                    w.write_all(&p.tf.to_le_bytes())?;
+                }
+            }
+            w.flush()?;
+            w.get_ref().sync_all()
+                .map_err(|e| anyhow::anyhow!("Failed to sync lexical index to disk: {}", e))?;
+        }
+        std::fs::rename(&tmp, &self.path)
+            .map_err(|e| anyhow::anyhow!("Failed to replace lexical index file: {}", e))?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<()> {
+        let file = File::open(&self.path)
+            .map_err(|e| anyhow::anyhow!("Failed to open lexical index file: {}", e))?;
+        let mut r = BufReader::new(file);
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            anyhow::bail!("bad magic");
+        }
+        let version = read_u32(&mut r)?;
+        if version != VERSION {
+            anyhow::bail!("unsupported version {}", version);
+        }
+        let rows = read_u64(&mut r)? as usize;
+        let mut doc_lens = Vec::with_capacity(rows);
+        for _ in 0..rows {
+            doc_lens.push(read_u32(&mut r)?);
+        }
+        let terms = read_u64(&mut r)? as usize;
+        let mut postings = HashMap::with_capacity(terms);
+        for _ in 0..terms {
+            let mut term = vec![0u8; read_u16(&mut r)? as usize];
+            r.read_exact(&mut term)?;
+            let term = String::from_utf8(term)?;
+            let n = read_u32(&mut r)? as usize;
+            let mut list = Vec::with_capacity(n);
+            for _ in 0..n {
+                let row = read_u32(&mut r)?;
+                let tf = read_u32(&mut r)?;
+                if row as usize >= rows {
+                    anyhow::bail!("posting for row {} past the {} indexed rows", row, rows);
+                }
+                list.push(Posting { row, tf });
+            }
+            postings.insert(term.into_boxed_str(), list);
+        }
+        self.total_len = doc_lens.iter().map(|&l| l as u64).sum();
+        self.docs = doc_lens.iter().filter(|&&l| l > 0).count();
+        self.doc_lens = doc_lens;
+        self.postings = postings;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+fn read_u16(r: &mut impl Read) -> std::io::Result<u16> {
+    let mut buf = [0u8; 2];
+    r.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32(r: &mut impl Read) -> std::io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(r: &mut impl Read) -> std::io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(title: &str, body: &str) -> String {
+        serde_json::json!({ "review_title": title, "review_body": body }).to_string()
+    }
+
+    #[test]
+    fn bm25_ranks_rare_terms_and_survives_a_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lexical");
+        let mut index = LexicalIndex::open(path.clone());
+        index.push_line(&line("Great battery", "The battery lasts for days."));
+        index.push_line(&line("Okay", "Battery is fine, screen is dim."));
+        index.push_missing();
+        index.push_line(&line("Broken hinge", "The hinge snapped after a week."));
+        index.push_line(&line("Hinge", "hinge hinge hinge"));
+
+        let all = |_: usize| true;
+        let hits = index.search("HINGE snapped", 10, &all);
+        assert_eq!(hits.iter().map(|h| h.0).collect::<Vec<_>>(), vec![3, 4]);
+        assert!(hits[0].1 > hits[1].1);
+        assert_eq!(index.search("battery", 1, &all)[0].0, 0);
+        assert!(index.search("battery", 10, &|row| row != 0).iter().all(|h| h.0 == 1));
+
+        index.remove(3);
+        index.save().unwrap();
+        let reopened = LexicalIndex::open(path);
+        assert_eq!(reopened.len(), 5);
+        assert_eq!(reopened.search("hinge", 10, &all), index.search("hinge", 10, &all));
+        assert_eq!(reopened.search("snapped", 10, &all), vec![]);
+    }
+}
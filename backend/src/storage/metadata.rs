@@ -1,54 +1,841 @@
 use anyhow::Result;
 use serde::Serialize;
+use std::collections::HashSet;
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use super::filter::Attributes;
+use super::ids::{IdMap, NO_ID};
+use super::lexical::LexicalIndex;
+use super::segments::{self, Compacted, CompactionJob, Manifest, RowIds, SegmentConfig, SegmentMeta};
+use super::tombstones::Tombstones;
+
+/// Marker the pre-segment store overwrote deleted lines with.
+const DELETED_MARKER: &str = r#"{"_deleted":true}"#;
+
+fn is_deleted_line(line: &str) -> bool {
+    line.trim_end() == DELETED_MARKER
+}
+
+/// The `id` field of a stored line.
+#[derive(serde::Deserialize)]
+struct LineId {
+    id: Option<u64>,
+}
+
+/// Id a line is recorded under: its own `id` field, `None` if it is an object
+/// without one (it gets the next free id), or [`NO_ID`] if it is not an object.
+fn line_id(line: &str) -> Option<u64> {
+    match serde_json::from_str::<LineId>(line) {
+        Ok(LineId { id }) => id,
+        Err(_) => Some(NO_ID),
+    }
+}
+
+/// Opens the metadata store under `data_dir`, moving a pre-segment
+/// `reviews.jsonl` into it first.
+pub fn open_metadata(data_dir: &Path) -> Result<MetadataStore> {
+    let dir = data_dir.join("segments").join("metadata");
+    if MetadataStore::migrate_legacy(&data_dir.join("reviews.jsonl"), &dir)? {
+        tracing::info!("Moved reviews.jsonl into the first metadata segment");
+    }
+    MetadataStore::open_or_create(dir)
+}
+
+/// One JSON-lines segment file with the byte offset of every line. The
+/// offsets are kept in a `<id>.offsets` sidecar of little-endian `u64` line
+/// ends, so opening a segment does not rescan it.
 #[derive(Debug)]
-pub struct MetadataStore {
+struct Segment {
+    meta: SegmentMeta,
+    rows: RowIds,
     path: PathBuf,
+    /// Start of each line, followed by the end of the last one.
+    offsets: Vec<u64>,
 }
 
-impl MetadataStore {
-    pub fn open_or_create(path: PathBuf) -> Result<Self> {
-        if !path.exists() {
+impl Segment {
+    fn open(dir: &Path, meta: SegmentMeta) -> Result<Self> {
+        let path = segments::segment_file(dir, meta.id, "jsonl");
+        if !path.exists() && !meta.sealed {
             File::create(&path)
-                .map_err(|e| anyhow::anyhow!("Failed to create metadata store file: {}", e))?;
+                .map_err(|e| anyhow::anyhow!("Failed to create metadata segment: {}", e))?;
         }
-        Ok(Self { path })
+        let offsets = load_offsets(&path, &segments::segment_file(dir, meta.id, "offsets"))?;
+        let rows = RowIds::load(dir, &meta)?;
+        let mut segment = Self { meta, rows, path, offsets };
+        let count = segment.offsets.len() - 1;
+        if segment.meta.sealed {
+            if count != segment.rows.len() {
+                anyhow::bail!("Metadata segment {:?} holds {} lines, manifest says {}", segment.path, count, segment.rows.len());
+            }
+        } else {
+            // Drop a torn trailing line so the next append starts on its own line.
+            OpenOptions::new()
+                .write(true)
+                .open(&segment.path)
+                .and_then(|f| f.set_len(*segment.offsets.last().unwrap()))
+                .map_err(|e| anyhow::anyhow!("Failed to truncate metadata segment: {}", e))?;
+            segment.rows = RowIds::Dense { first: segment.meta.first_row, len: count };
+        }
+        Ok(segment)
     }
 
-    pub fn append<T: Serialize>(&mut self, item: &T) -> Result<()> {
+    fn offsets_path(&self) -> PathBuf {
+        self.path.with_extension("offsets")
+    }
+
+    fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    fn read_line(&self, position: usize) -> Result<String> {
+        let start = self.offsets[position];
+        let len = self.offsets[position + 1] - start - 1;
+        let mut file = File::open(&self.path)
+            .map_err(|e| anyhow::anyhow!("Failed to open metadata store file: {}", e))?;
+        file.seek(SeekFrom::Start(start))
+            .map_err(|e| anyhow::anyhow!("Failed to seek in metadata store: {}", e))?;
+        let mut line = String::with_capacity(len as usize);
+        file.take(len).read_to_string(&mut line)
+            .map_err(|e| anyhow::anyhow!("Failed to read line from metadata store: {}", e))?;
+        Ok(line)
+    }
+
+    /// Appends `lines` with one write to the segment and one to its sidecar.
+    fn append(&mut self, lines: &[&str]) -> Result<()> {
         let file = OpenOptions::new()
             .append(true)
-            .create(true)
             .open(&self.path)
             .map_err(|e| anyhow::anyhow!("Failed to open metadata store file: {}", e))?;
         let mut writer = BufWriter::new(file);
-        
-        serde_json::to_writer(&mut writer, item)
-            .map_err(|e| anyhow::anyhow!("Failed to serialize metadata item: {}", e))?;
-        writer.write_all(b"\n")
-            .map_err(|e| anyhow::anyhow!("Failed to write newline to metadata store: {}", e))?;
+        let mut end = *self.offsets.last().unwrap();
+        let mut ends = Vec::with_capacity(lines.len() * 8);
+        for line in lines {
+            writer.write_all(line.as_bytes())
+                .map_err(|e| anyhow::anyhow!("Failed to write metadata item: {}", e))?;
+            writer.write_all(b"\n")
+                .map_err(|e| anyhow::anyhow!("Failed to write newline to metadata store: {}", e))?;
+            end += line.len() as u64 + 1;
+            ends.extend_from_slice(&end.to_le_bytes());
+        }
         writer.flush()
             .map_err(|e| anyhow::anyhow!("Failed to flush metadata store writer: {}", e))?;
+        // The sidecar is rebuilt from the segment if this write is lost.
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.offsets_path())
+            .and_then(|mut f| f.write_all(&ends))
+            .map_err(|e| anyhow::anyhow!("Failed to write metadata offset: {}", e))?;
+        self.offsets.extend(ends.chunks_exact(8).map(|b| u64::from_le_bytes(b.try_into().unwrap())));
+        self.rows.extend(lines.len());
         Ok(())
     }
+}
 
-    pub fn get_by_index<T: for<'de> serde::Deserialize<'de>>(&self, index: usize) -> Result<T> {
-        let file = File::open(&self.path)
-            .map_err(|e| anyhow::anyhow!("Failed to open metadata store file: {}", e))?;
-        let reader = BufReader::new(file);
-        
-        let line = reader
-            .lines()
-            .nth(index)
-            .ok_or_else(|| anyhow::anyhow!("index out of bounds"))?
-            .map_err(|e| anyhow::anyhow!("Failed to read line from metadata store: {}", e))?;
-            
+/// Reads the line offsets of a segment from its sidecar, then scans the
+/// segment past the last recorded line for lines the sidecar missed. The
+/// sidecar is rewritten whenever it did not match the segment.
+fn load_offsets(path: &Path, offsets_path: &Path) -> Result<Vec<u64>> {
+    let mut file = File::open(path)
+        .map_err(|e| anyhow::anyhow!("Failed to open metadata segment {:?}: {}", path, e))?;
+    let file_len = file.metadata()
+        .map_err(|e| anyhow::anyhow!("Failed to stat metadata segment {:?}: {}", path, e))?
+        .len();
+    let stored: Vec<u64> = match std::fs::read(offsets_path) {
+        Ok(data) => data
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().expect("chunk of 8 bytes")))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    let mut offsets = vec![0u64];
+    for end in &stored {
+        if *end <= *offsets.last().unwrap() || *end > file_len {
+            break;
+        }
+        offsets.push(*end);
+    }
+    // Every recorded line must end in a newline; otherwise start over.
+    let last = *offsets.last().unwrap();
+    if last > 0 {
+        let mut byte = [0u8; 1];
+        file.seek(SeekFrom::Start(last - 1))
+            .and_then(|_| file.read_exact(&mut byte))
+            .map_err(|e| anyhow::anyhow!("Failed to read metadata segment: {}", e))?;
+        if byte[0] != b'\n' {
+            offsets.truncate(1);
+        }
+    }
+    let recorded = offsets.len() - 1;
+
+    let start = *offsets.last().unwrap();
+    file.seek(SeekFrom::Start(start))
+        .map_err(|e| anyhow::anyhow!("Failed to seek in metadata segment: {}", e))?;
+    let mut reader = BufReader::new(file);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)
+            .map_err(|e| anyhow::anyhow!("Failed to read metadata segment: {}", e))?;
+        if read == 0 || line.last() != Some(&b'\n') {
+            break;
+        }
+        offsets.push(offsets.last().unwrap() + read as u64);
+    }
+
+    if recorded != stored.len() || offsets.len() - 1 != recorded {
+        write_offsets(offsets_path, &offsets)?;
+    }
+    Ok(offsets)
+}
+
+/// Writes the line ends in `offsets` (everything after the leading zero).
+fn write_offsets(path: &Path, offsets: &[u64]) -> Result<()> {
+    let bytes: Vec<u8> = offsets[1..].iter().flat_map(|end| end.to_le_bytes()).collect();
+    std::fs::write(path, bytes)
+        .map_err(|e| anyhow::anyhow!("Failed to write metadata offsets {:?}: {}", path, e))
+}
+
+/// JSON-lines store of review metadata, split into segments under one
+/// directory (see [`segments`]). Row numbers line up with the vector index.
+/// Each row also carries a stable id, taken from the line's `id` field and
+/// indexed in an `ids` file next to the segments. The filterable fields of
+/// every row are kept in memory as [`Attributes`], rebuilt on open, and the
+/// review text feeds a BM25 [`LexicalIndex`].
+#[derive(Debug)]
+pub struct MetadataStore {
+    dir: PathBuf,
+    config: SegmentConfig,
+    manifest: Manifest,
+    sealed: Vec<Arc<Segment>>,
+    active: Segment,
+    tombstones: Tombstones,
+    ids: IdMap,
+    attributes: Attributes,
+    lexical: LexicalIndex,
+}
+
+impl MetadataStore {
+    pub fn open_or_create(dir: PathBuf) -> Result<Self> {
+        Self::open_with_config(dir, SegmentConfig::from_env())
+    }
+
+    pub fn open_with_config(dir: PathBuf, config: SegmentConfig) -> Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| anyhow::anyhow!("Failed to create metadata segment directory: {}", e))?;
+        let manifest = match Manifest::load(&dir)? {
+            Some(manifest) => manifest,
+            None => {
+                let manifest = Manifest::new(vec![SegmentMeta::active(1, 0)]);
+                manifest.save(&dir)?;
+                manifest
+            }
+        };
+        segments::remove_orphans(&dir, &manifest)?;
+        let (active, sealed) = manifest
+            .segments
+            .split_last()
+            .ok_or_else(|| anyhow::anyhow!("Metadata segment manifest lists no segments"))?;
+        let sealed = sealed
+            .iter()
+            .map(|meta| Segment::open(&dir, meta.clone()).map(Arc::new))
+            .collect::<Result<Vec<_>>>()?;
+        let active = Segment::open(&dir, active.clone())?;
+        let tombstones = Tombstones::open(dir.join("deleted"))?;
+        let ids = IdMap::open(dir.join("ids"))?;
+        let attributes = Attributes::from_env();
+        let lexical = LexicalIndex::open(dir.join("lexical"));
+        let mut store = Self { dir, config, manifest, sealed, active, tombstones, ids, attributes, lexical };
+        store.sync_ids()?;
+        store.load_attributes()?;
+        store.sync_lexical()?;
+        Ok(store)
+    }
+
+    /// Brings the lexical index in line with the segments: rows appended
+    /// since its snapshot are indexed and rows deleted since are removed. A
+    /// snapshot ahead of the segments (rows lost in a crash) is rebuilt.
+    fn sync_lexical(&mut self) -> Result<()> {
+        if self.lexical.len() > self.len() {
+            self.lexical.clear();
+        }
+        let start = self.lexical.len();
+        for row in 0..start {
+            if self.tombstones.contains(row) {
+                self.lexical.remove(row);
+            }
+        }
+        if start == self.len() {
+            return Ok(());
+        }
+        tracing::info!("Indexing text of metadata rows {}..{}", start, self.len());
+        let placeholder = LexicalIndex::new(self.lexical.path().to_path_buf());
+        let mut lexical = std::mem::replace(&mut self.lexical, placeholder);
+        for item in self.iter_from(start) {
+            let (row, line) = item?;
+            while lexical.len() < row {
+                lexical.push_missing();
+            }
+            lexical.push_line(&line);
+        }
+        while lexical.len() < self.len() {
+            lexical.push_missing();
+        }
+        self.lexical = lexical;
+        Ok(())
+    }
+
+    fn load_attributes(&mut self) -> Result<()> {
+        let mut attributes = Attributes::new(self.attributes.fields().to_vec());
+        for item in self.iter() {
+            let (row, line) = item?;
+            while attributes.len() < row {
+                attributes.push_missing();
+            }
+            attributes.push_line(&line);
+        }
+        while attributes.len() < self.len() {
+            attributes.push_missing();
+        }
+        self.attributes = attributes;
+        Ok(())
+    }
+
+    /// Brings the id map in line with the segments: ids of rows lost in a
+    /// crash are dropped, and rows the map has not seen yet (all of them for a
+    /// store written before ids existed) are read back to find theirs.
+    fn sync_ids(&mut self) -> Result<()> {
+        let start = self.ids.len();
+        let len = self.len();
+        if start >= len {
+            return self.ids.truncate(len);
+        }
+        tracing::info!("Indexing ids of metadata rows {}..{}", start, len);
+        let mut found: Vec<Option<u64>> = vec![Some(NO_ID); len - start];
+        for item in self.iter_from(start) {
+            let (row, line) = item?;
+            found[row - start] = line_id(&line);
+        }
+        let mut next = found.iter().flatten().fold(self.ids.next_id(), |next, id| next.max(id + 1));
+        for id in found {
+            let id = id.unwrap_or_else(|| {
+                next += 1;
+                next - 1
+            });
+            self.ids.push(id)?;
+        }
+        self.ids.sync()
+    }
+
+    /// Moves a pre-segment `reviews.jsonl` into `dir` as the first sealed
+    /// segment, turning its in-place deletion markers into tombstones. Does
+    /// nothing if `dir` already has a manifest or there is no legacy file.
+    pub fn migrate_legacy(legacy: &Path, dir: &Path) -> Result<bool> {
+        if !legacy.exists() || Manifest::load(dir)?.is_some() {
+            return Ok(false);
+        }
+        std::fs::create_dir_all(dir)
+            .map_err(|e| anyhow::anyhow!("Failed to create metadata segment directory: {}", e))?;
+        let file = File::open(legacy)
+            .map_err(|e| anyhow::anyhow!("Failed to open legacy metadata file: {}", e))?;
+        let mut reader = BufReader::new(file);
+        let mut rows = 0;
+        let mut complete = 0u64;
+        let mut deleted = Vec::new();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)
+                .map_err(|e| anyhow::anyhow!("Failed to read legacy metadata file: {}", e))?;
+            if read == 0 || !line.ends_with('\n') {
+                break;
+            }
+            if is_deleted_line(&line) {
+                deleted.push(rows);
+            }
+            rows += 1;
+            complete += read as u64;
+        }
+        OpenOptions::new()
+            .write(true)
+            .open(legacy)
+            .and_then(|f| f.set_len(complete))
+            .map_err(|e| anyhow::anyhow!("Failed to truncate legacy metadata file: {}", e))?;
+        let mut tombstones = Tombstones::open(dir.join("deleted"))?;
+        for row in deleted {
+            tombstones.mark(row)?;
+        }
+        std::fs::rename(legacy, segments::segment_file(dir, 1, "jsonl"))
+            .map_err(|e| anyhow::anyhow!("Failed to move legacy metadata file: {}", e))?;
+        let first = SegmentMeta { id: 1, first_row: 0, rows, sealed: true, sparse: false };
+        Manifest::new(vec![first, SegmentMeta::active(2, rows)]).save(dir)?;
+        Ok(true)
+    }
+
+    fn segments(&self) -> impl Iterator<Item = &Segment> {
+        self.sealed.iter().map(|s| s.as_ref()).chain(std::iter::once(&self.active))
+    }
+
+    /// Number of rows ever appended, deleted ones included.
+    pub fn len(&self) -> usize {
+        self.active.meta.first_row + self.active.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Grows with every append and delete, so two equal values mean no row
+    /// changed in between.
+    pub fn generation(&self) -> u64 {
+        (self.len() + self.tombstones.len()) as u64
+    }
+
+    pub fn append<T: Serialize>(&mut self, item: &T) -> Result<()> {
+        let line = serde_json::to_string(item)
+            .map_err(|e| anyhow::anyhow!("Failed to serialize metadata item: {}", e))?;
+        self.append_line(&line)
+    }
+
+    /// Appends an already serialized item; `line` must not contain a newline.
+    /// An object without an `id` field is recorded under [`Self::next_id`].
+    pub fn append_line(&mut self, line: &str) -> Result<()> {
+        self.append_lines(&[line])
+    }
+
+    /// Like [`Self::append_line`] for several items, writing each segment
+    /// they land in once.
+    pub fn append_lines(&mut self, lines: &[&str]) -> Result<()> {
+        if lines.iter().any(|line| line.contains('\n')) {
+            anyhow::bail!("Metadata line contains a newline");
+        }
+        let mut rest = lines;
+        while !rest.is_empty() {
+            if self.active.len() >= self.config.max_rows {
+                self.seal()?;
+            }
+            let (batch, next) = rest.split_at(rest.len().min(self.config.max_rows - self.active.len()));
+            self.active.append(batch)?;
+            for line in batch {
+                let id = line_id(line).unwrap_or_else(|| self.ids.next_id());
+                self.attributes.push_line(line);
+                self.lexical.push_line(line);
+                self.ids.push(id)?;
+            }
+            rest = next;
+        }
+        Ok(())
+    }
+
+    /// Filterable fields of every row, for [`super::filter::Filter`].
+    pub fn attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+
+    /// BM25 index over the review text of every live row.
+    pub fn lexical(&self) -> &LexicalIndex {
+        &self.lexical
+    }
+
+    /// Syncs appended lines and their ids to disk and snapshots the lexical
+    /// index. Sealed segments and tombstones are already synced when they
+    /// are written.
+    pub fn flush(&mut self) -> Result<()> {
+        File::open(&self.active.path)
+            .and_then(|f| f.sync_data())
+            .map_err(|e| anyhow::anyhow!("Failed to sync metadata segment: {}", e))?;
+        self.ids.sync()?;
+        self.lexical.save()
+    }
+
+    /// The id the next new review should be stored under. Ids start at 1 and
+    /// are never handed out twice.
+    pub fn next_id(&self) -> u64 {
+        self.ids.next_id()
+    }
+
+    /// Id of the review stored at `row`.
+    pub fn id_of(&self, row: usize) -> Option<u64> {
+        self.ids.id(row)
+    }
+
+    /// Row currently holding review `id`, or `None` if there is none or it
+    /// has been deleted.
+    pub fn row_of(&self, id: u64) -> Option<usize> {
+        self.ids.row(id).filter(|row| !self.tombstones.contains(*row))
+    }
+
+    fn seal(&mut self) -> Result<()> {
+        self.flush()?;
+        let mut manifest = self.manifest.clone();
+        let next = manifest.seal_active(self.active.len());
+        let next = Segment::open(&self.dir, next)?;
+        manifest.save(&self.dir)?;
+        self.manifest = manifest;
+        let mut sealed = std::mem::replace(&mut self.active, next);
+        sealed.meta = self.manifest.segments[self.sealed.len()].clone();
+        self.sealed.push(Arc::new(sealed));
+        Ok(())
+    }
+
+    /// The stored line for `index`, or `None` if it has been deleted.
+    fn line(&self, index: usize) -> Result<Option<String>> {
+        if index >= self.len() {
+            anyhow::bail!("index out of bounds");
+        }
+        if self.tombstones.contains(index) {
+            return Ok(None);
+        }
+        let first_rows = self.segments().map(|s| s.meta.first_row);
+        let Some(segment) = segments::locate(first_rows, index).and_then(|i| self.segments().nth(i)) else {
+            return Ok(None);
+        };
+        match segment.rows.position(index) {
+            Some(position) => segment.read_line(position).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the item at `index`, or `None` if it has been deleted.
+    pub fn get_by_index<T: for<'de> serde::Deserialize<'de>>(&self, index: usize) -> Result<Option<T>> {
+        let Some(line) = self.line(index)? else {
+            return Ok(None);
+        };
         let value = serde_json::from_str::<T>(&line)
             .map_err(|e| anyhow::anyhow!("Failed to parse JSON from metadata store: {}", e))?;
-            
-        Ok(value)
+        Ok(Some(value))
+    }
+
+    /// Fetches several rows in one sorted pass: each segment is opened once
+    /// and its lines are read in file order, seeking only over gaps. Results
+    /// follow the order of `indices`; deleted rows come back as `None`.
+    pub fn get_many<T: for<'de> serde::Deserialize<'de>>(&self, indices: &[usize]) -> Result<Vec<Option<T>>> {
+        let segments: Vec<&Segment> = self.segments().collect();
+        let first_rows: Vec<usize> = segments.iter().map(|s| s.meta.first_row).collect();
+        let mut wanted = Vec::with_capacity(indices.len());
+        for (slot, &index) in indices.iter().enumerate() {
+            if index >= self.len() {
+                anyhow::bail!("index out of bounds");
+            }
+            if self.tombstones.contains(index) {
+                continue;
+            }
+            let Some(at) = segments::locate(first_rows.iter().copied(), index) else {
+                continue;
+            };
+            if let Some(position) = segments[at].rows.position(index) {
+                wanted.push((at, position, slot));
+            }
+        }
+        wanted.sort_unstable();
+
+        let mut out: Vec<Option<T>> = indices.iter().map(|_| None).collect();
+        let mut open: Option<(usize, BufReader<File>, u64)> = None;
+        for (at, position, slot) in wanted {
+            let segment = segments[at];
+            if open.as_ref().map(|(i, _, _)| *i) != Some(at) {
+                let file = File::open(&segment.path)
+                    .map_err(|e| anyhow::anyhow!("Failed to open metadata store file: {}", e))?;
+                open = Some((at, BufReader::new(file), 0));
+            }
+            let (_, reader, cursor) = open.as_mut().unwrap();
+            let start = segment.offsets[position];
+            let end = segment.offsets[position + 1];
+            if *cursor != start {
+                reader.seek(SeekFrom::Start(start))
+                    .map_err(|e| anyhow::anyhow!("Failed to seek in metadata store: {}", e))?;
+            }
+            let mut line = vec![0u8; (end - start) as usize];
+            reader.read_exact(&mut line)
+                .map_err(|e| anyhow::anyhow!("Failed to read line from metadata store: {}", e))?;
+            *cursor = end;
+            let value = serde_json::from_slice::<T>(&line[..line.len() - 1])
+                .map_err(|e| anyhow::anyhow!("Failed to parse JSON from metadata store: {}", e))?;
+            out[slot] = Some(value);
+        }
+        Ok(out)
+    }
+
+    pub fn is_deleted(&self, index: usize) -> Result<bool> {
+        Ok(self.line(index)?.is_none())
+    }
+
+    /// Tombstones the row at `index`. Returns `false` if it already was deleted.
+    pub fn delete(&mut self, index: usize) -> Result<bool> {
+        if self.is_deleted(index)? {
+            return Ok(false);
+        }
+        self.lexical.remove(index);
+        self.tombstones.mark(index)
+    }
+
+    /// Every live row with its raw JSON line, in row order.
+    pub fn iter(&self) -> MetadataLines<'_> {
+        MetadataLines { store: self, segment: 0, position: 0, reader: None, from: 0 }
+    }
+
+    /// Like [`Self::iter`], but only rows from `row` on.
+    fn iter_from(&self, row: usize) -> MetadataLines<'_> {
+        let first_rows = self.segments().map(|s| s.meta.first_row);
+        let segment = segments::locate(first_rows, row).unwrap_or(0);
+        MetadataLines { store: self, segment, position: 0, reader: None, from: row }
+    }
+
+    pub fn plan_compaction(&mut self) -> Result<Option<Box<dyn CompactionJob>>> {
+        let stats: Vec<(usize, usize)> = self
+            .sealed
+            .iter()
+            .map(|s| (s.len(), segments::live_rows(&s.rows, &self.tombstones)))
+            .collect();
+        let Some(range) = segments::pick_compaction(&stats, self.config.max_rows) else {
+            return Ok(None);
+        };
+        let inputs: Vec<Arc<Segment>> = self.sealed[range].to_vec();
+        let deleted = inputs
+            .iter()
+            .flat_map(|s| s.rows.iter())
+            .filter(|row| self.tombstones.contains(*row))
+            .collect();
+        Ok(Some(Box::new(MetadataCompaction {
+            dir: self.dir.clone(),
+            id: self.manifest.allocate_id(),
+            inputs,
+            deleted,
+        })))
+    }
+
+    pub fn apply_compaction(&mut self, done: Compacted) -> Result<()> {
+        let mut manifest = self.manifest.clone();
+        let at = manifest.replace(&done)?;
+        let merged = match &done.segment {
+            Some(meta) => Some(Arc::new(Segment::open(&self.dir, meta.clone())?)),
+            None => None,
+        };
+        manifest.save(&self.dir)?;
+        self.manifest = manifest;
+        self.sealed.splice(at..at + done.replaces.len(), merged);
+        for id in done.replaces {
+            segments::remove_segment_files(&self.dir, id, &["jsonl", "offsets"]);
+        }
+        Ok(())
+    }
+}
+
+/// Iterator returned by [`MetadataStore::iter`].
+pub struct MetadataLines<'a> {
+    store: &'a MetadataStore,
+    segment: usize,
+    position: usize,
+    reader: Option<BufReader<File>>,
+    from: usize,
+}
+
+impl Iterator for MetadataLines<'_> {
+    type Item = Result<(usize, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let segment = self.store.segments().nth(self.segment)?;
+            if self.position >= segment.len() {
+                self.segment += 1;
+                self.position = 0;
+                self.reader = None;
+                continue;
+            }
+            if self.reader.is_none() {
+                match File::open(&segment.path) {
+                    Ok(file) => self.reader = Some(BufReader::new(file)),
+                    Err(e) => return Some(Err(anyhow::anyhow!("Failed to open metadata segment: {}", e))),
+                }
+            }
+            let mut line = String::new();
+            if let Err(e) = self.reader.as_mut().unwrap().read_line(&mut line) {
+                return Some(Err(anyhow::anyhow!("Failed to read line from metadata store: {}", e)));
+            }
+            let row = segment.rows.row(self.position);
+            self.position += 1;
+            if row >= self.from && !self.store.tombstones.contains(row) {
+                line.truncate(line.trim_end_matches('\n').len());
+                return Some(Ok((row, line)));
+            }
+        }
+    }
+}
+
+/// Copies the live lines of adjacent sealed segments into one new segment.
+struct MetadataCompaction {
+    dir: PathBuf,
+    id: u64,
+    inputs: Vec<Arc<Segment>>,
+    deleted: HashSet<usize>,
+}
+
+impl CompactionJob for MetadataCompaction {
+    fn run(self: Box<Self>) -> Result<Compacted> {
+        let replaces = self.inputs.iter().map(|s| s.meta.id).collect();
+        let path = segments::segment_file(&self.dir, self.id, "jsonl");
+        let file = File::create(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to create metadata segment {:?}: {}", path, e))?;
+        let mut w = BufWriter::new(file);
+        let mut rows = Vec::new();
+        let mut offsets = vec![0u64];
+        for segment in &self.inputs {
+            let reader = BufReader::new(File::open(&segment.path)?);
+            for (i, line) in reader.lines().enumerate().take(segment.len()) {
+                let line = line?;
+                let row = segment.rows.row(i);
+                if !self.deleted.contains(&row) {
+                    w.write_all(line.as_bytes())?;
+                    w.write_all(b"\n")?;
+                    rows.push(row);
+                    offsets.push(offsets.last().unwrap() + line.len() as u64 + 1);
+                }
+            }
+        }
+        w.flush()?;
+        w.get_ref().sync_all()
+            .map_err(|e| anyhow::anyhow!("Failed to sync metadata segment: {}", e))?;
+        if rows.is_empty() {
+            std::fs::remove_file(&path)?;
+            return Ok(Compacted { replaces, segment: None });
+        }
+        write_offsets(&segments::segment_file(&self.dir, self.id, "offsets"), &offsets)?;
+        let first_row = self.inputs[0].meta.first_row;
+        let segment = segments::finish_segment(&self.dir, self.id, first_row, &rows)?;
+        Ok(Compacted { replaces, segment: Some(segment) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn title(store: &MetadataStore, index: usize) -> Option<String> {
+        store
+            .get_by_index::<serde_json::Value>(index)
+            .unwrap()
+            .map(|v| v["review_title"].as_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn deletes_and_compaction_keep_row_numbers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metadata");
+        let config = SegmentConfig { max_rows: 3 };
+        let mut store = MetadataStore::open_with_config(path.clone(), config).unwrap();
+        for i in 0..8 {
+            store.append(&serde_json::json!({ "review_title": format!("review {}", i) })).unwrap();
+        }
+        assert!(store.delete(1).unwrap());
+        assert!(!store.delete(1).unwrap());
+        assert!(store.delete(8).is_err());
+        store.delete(4).unwrap();
+
+        while let Some(job) = store.plan_compaction().unwrap() {
+            let done = job.run().unwrap();
+            store.apply_compaction(done).unwrap();
+        }
+        drop(store);
+
+        let store = MetadataStore::open_with_config(path, config).unwrap();
+        assert_eq!(store.len(), 8);
+        assert!(store.is_deleted(1).unwrap());
+        assert_eq!(title(&store, 4), None);
+        assert_eq!(title(&store, 5).as_deref(), Some("review 5"));
+        let rows: Vec<usize> = store.iter().map(|r| r.unwrap().0).collect();
+        assert_eq!(rows, vec![0, 2, 3, 5, 6, 7]);
+    }
+
+    #[test]
+    fn offsets_sidecar_is_repaired_and_get_many_keeps_request_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metadata");
+        let config = SegmentConfig { max_rows: 4 };
+        let mut store = MetadataStore::open_with_config(path.clone(), config).unwrap();
+        store.append(&serde_json::json!({ "review_title": "review 0" })).unwrap();
+        // One batch spilling over two segment boundaries.
+        let lines: Vec<String> = (1..10).map(|i| format!(r#"{{"review_title":"review {}"}}"#, i)).collect();
+        store.append_lines(&lines.iter().map(String::as_str).collect::<Vec<_>>()).unwrap();
+        store.delete(5).unwrap();
+        drop(store);
+
+        // Lose the last two offsets of the active segment and corrupt a sealed one.
+        let active = segments::segment_file(&path, 3, "offsets");
+        let data = std::fs::read(&active).unwrap();
+        std::fs::write(&active, &data[..data.len() - 16]).unwrap();
+        std::fs::write(segments::segment_file(&path, 1, "offsets"), [7u8; 24]).unwrap();
+
+        let store = MetadataStore::open_with_config(path, config).unwrap();
+        assert_eq!(std::fs::read(&active).unwrap(), data);
+        let got = store.get_many::<serde_json::Value>(&[9, 0, 5, 6, 2]).unwrap();
+        let titles: Vec<Option<&str>> = got.iter().map(|v| v.as_ref().map(|v| v["review_title"].as_str().unwrap())).collect();
+        assert_eq!(titles, vec![Some("review 9"), Some("review 0"), None, Some("review 6"), Some("review 2")]);
+        assert_eq!(title(&store, 3).as_deref(), Some("review 3"));
+        assert!(store.get_many::<serde_json::Value>(&[10]).is_err());
+    }
+
+    #[test]
+    fn ids_survive_reopen_and_are_rebuilt_from_the_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metadata");
+        let mut store = MetadataStore::open_or_create(path.clone()).unwrap();
+        store.append(&serde_json::json!({ "review_title": "no id" })).unwrap();
+        store.append(&serde_json::json!({ "id": 7, "review_title": "seven" })).unwrap();
+        store.append_line("null").unwrap();
+        assert_eq!(store.next_id(), 8);
+        // An update re-appends the id; lookups follow the newest row.
+        store.append(&serde_json::json!({ "id": 7, "review_title": "seven again" })).unwrap();
+        store.delete(1).unwrap();
+        assert_eq!(store.row_of(7), Some(3));
+        assert_eq!((store.id_of(0), store.id_of(2)), (Some(1), None));
+        drop(store);
+
+        std::fs::remove_file(path.join("ids")).unwrap();
+        let mut store = MetadataStore::open_or_create(path).unwrap();
+        assert_eq!(store.row_of(7), Some(3));
+        assert_eq!(store.id_of(0), Some(8));
+        store.delete(3).unwrap();
+        assert_eq!(store.row_of(7), None);
+        assert_eq!(store.next_id(), 9);
+    }
+
+    #[test]
+    fn lexical_index_catches_up_after_its_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metadata");
+        let mut store = MetadataStore::open_or_create(path.clone()).unwrap();
+        store.append(&serde_json::json!({ "review_title": "loud fan", "review_body": "fan is loud" })).unwrap();
+        store.append(&serde_json::json!({ "review_title": "quiet", "review_body": "no fan noise" })).unwrap();
+        store.flush().unwrap();
+        // Written after the snapshot: one more row and a delete.
+        store.append(&serde_json::json!({ "review_title": "fan died", "review_body": "" })).unwrap();
+        store.delete(0).unwrap();
+        drop(store);
+
+        let store = MetadataStore::open_or_create(path).unwrap();
+        let rows: Vec<usize> = store.lexical().search("fan", 10, &|_| true).into_iter().map(|(row, _)| row).collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows.contains(&1) && rows.contains(&2));
+    }
+
+    #[test]
+    fn legacy_file_markers_become_tombstones() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy = dir.path().join("reviews.jsonl");
+        std::fs::write(
+            &legacy,
+            format!("{}\n{:<30}\n{}\n", r#"{"review_title":"a"}"#, DELETED_MARKER, r#"{"review_title":"c"}"#),
+        )
+        .unwrap();
+
+        let store = open_metadata(dir.path()).unwrap();
+        assert!(!legacy.exists());
+        assert_eq!(store.len(), 3);
+        assert!(store.is_deleted(1).unwrap());
+        assert_eq!(title(&store, 2).as_deref(), Some("c"));
     }
 }
@@ -1,2 +1,13 @@
+pub mod error;
+pub mod filter;
+pub mod header;
+pub mod hnsw;
+pub mod ids;
+pub mod index;
+pub mod kernels;
+pub mod lexical;
 pub mod metadata;
+pub mod segments;
+pub mod tombstones;
 pub mod vector_store;
+pub mod wal;
@@ -0,0 +1,360 @@
+//! Immutable storage segments shared by the flat vector store and the
+//! metadata store.
+//!
+//! A store directory holds a `manifest.json` plus the files of each segment.
+//! Rows keep the number they were appended under for their whole life: fresh
+//! segments cover a dense range starting at `first_row`, compacted ones list
+//! their surviving rows in a `<id>.rows` file. Only the last segment takes
+//! appends; once it holds `SEGMENT_MAX_ROWS` rows it is sealed and never
+//! written again, which is what lets compaction read it without a lock.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use super::tombstones::Tombstones;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const MANIFEST_VERSION: u32 = 1;
+
+/// Share of deleted rows at which a sealed segment is rewritten on its own.
+const REWRITE_DELETED_RATIO: f32 = 0.2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentConfig {
+    /// Rows after which the active segment is sealed.
+    pub max_rows: usize,
+}
+
+impl Default for SegmentConfig {
+    fn default() -> Self {
+        Self { max_rows: 100_000 }
+    }
+}
+
+impl SegmentConfig {
+    /// Reads `SEGMENT_MAX_ROWS`, falling back to the default if unset or invalid.
+    pub fn from_env() -> Self {
+        let max_rows = std::env::var("SEGMENT_MAX_ROWS")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|v| *v > 0)
+            .unwrap_or(Self::default().max_rows);
+        Self { max_rows }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentMeta {
+    pub id: u64,
+    /// Lowest row number the segment can hold.
+    pub first_row: usize,
+    /// Number of records. Only kept up to date once the segment is sealed.
+    pub rows: usize,
+    pub sealed: bool,
+    /// Whether row numbers are listed in a `.rows` file instead of being dense.
+    #[serde(default)]
+    pub sparse: bool,
+}
+
+impl SegmentMeta {
+    pub fn active(id: u64, first_row: usize) -> Self {
+        Self { id, first_row, rows: 0, sealed: false, sparse: false }
+    }
+}
+
+/// Segment list of one store. Segments are ordered by `first_row` and the
+/// last one is the active segment.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub next_segment: u64,
+    pub segments: Vec<SegmentMeta>,
+}
+
+impl Manifest {
+    pub fn new(segments: Vec<SegmentMeta>) -> Self {
+        let next_segment = segments.iter().map(|s| s.id + 1).max().unwrap_or(1);
+        Self { version: MANIFEST_VERSION, next_segment, segments }
+    }
+
+    pub fn load(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = std::fs::read(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to read segment manifest {:?}: {}", path, e))?;
+        let manifest: Manifest = serde_json::from_slice(&data)
+            .map_err(|e| anyhow::anyhow!("Failed to parse segment manifest {:?}: {}", path, e))?;
+        if manifest.version != MANIFEST_VERSION {
+            anyhow::bail!("Unsupported segment manifest version {} in {:?}", manifest.version, path);
+        }
+        Ok(Some(manifest))
+    }
+
+    /// Atomically replaces the manifest: readers of the directory see either
+    /// the old segment list or the new one, never a mix.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(MANIFEST_FILE);
+        let tmp = dir.join(format!("{}.tmp", MANIFEST_FILE));
+        {
+            let mut file = File::create(&tmp)
+                .map_err(|e| anyhow::anyhow!("Failed to create segment manifest: {}", e))?;
+            serde_json::to_writer_pretty(&mut file, self)
+                .map_err(|e| anyhow::anyhow!("Failed to serialize segment manifest: {}", e))?;
+            file.sync_all()
+                .map_err(|e| anyhow::anyhow!("Failed to sync segment manifest to disk: {}", e))?;
+        }
+        std::fs::rename(&tmp, &path)
+            .map_err(|e| anyhow::anyhow!("Failed to replace segment manifest: {}", e))?;
+        File::open(dir)
+            .and_then(|d| d.sync_all())
+            .map_err(|e| anyhow::anyhow!("Failed to sync segment directory {:?}: {}", dir, e))
+    }
+
+    /// Reserves an id for a new segment.
+    pub fn allocate_id(&mut self) -> u64 {
+        let id = self.next_segment;
+        self.next_segment += 1;
+        id
+    }
+
+    /// Marks the active segment sealed with `rows` records and appends a new
+    /// active segment after it.
+    pub fn seal_active(&mut self, rows: usize) -> SegmentMeta {
+        let id = self.allocate_id();
+        let active = self.segments.last_mut().expect("manifest always has an active segment");
+        active.rows = rows;
+        active.sealed = true;
+        let next = SegmentMeta::active(id, active.first_row + rows);
+        self.segments.push(next.clone());
+        next
+    }
+
+    /// Swaps the output of a compaction in for the segments it merged and
+    /// returns the position they occupied. Fails if the manifest changed
+    /// underneath the job.
+    pub fn replace(&mut self, done: &Compacted) -> Result<usize> {
+        let start = self
+            .segments
+            .iter()
+            .position(|s| Some(&s.id) == done.replaces.first())
+            .ok_or_else(|| anyhow::anyhow!("Compacted segments are no longer in the manifest"))?;
+        let end = start + done.replaces.len();
+        let matches = self.segments.get(start..end).is_some_and(|range| {
+            range.iter().zip(&done.replaces).all(|(s, id)| s.id == *id && s.sealed)
+        });
+        if !matches || end == self.segments.len() {
+            anyhow::bail!("Compacted segments are no longer in the manifest");
+        }
+        self.segments.splice(start..end, done.segment.clone());
+        Ok(start)
+    }
+}
+
+pub fn segment_file(dir: &Path, id: u64, ext: &str) -> PathBuf {
+    dir.join(format!("{:08}.{}", id, ext))
+}
+
+/// Removes segment files the manifest does not reference, left behind by a
+/// compaction that stopped before its manifest swap.
+pub fn remove_orphans(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let live: HashSet<u64> = manifest.segments.iter().map(|s| s.id).collect();
+    let entries = std::fs::read_dir(dir)
+        .map_err(|e| anyhow::anyhow!("Failed to list segment directory {:?}: {}", dir, e))?;
+    for entry in entries {
+        let path = entry?.path();
+        let id = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u64>().ok());
+        if let Some(id) = id {
+            if !live.contains(&id) {
+                tracing::info!("Removing orphaned segment file {:?}", path);
+                std::fs::remove_file(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes every file of a segment that is no longer in the manifest.
+pub fn remove_segment_files(dir: &Path, id: u64, exts: &[&str]) {
+    for ext in exts.iter().chain(["rows"].iter()) {
+        let path = segment_file(dir, id, ext);
+        if path.exists() {
+            if let Err(e) = std::fs::remove_file(&path) {
+                tracing::warn!("Failed to remove segment file {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Row numbers of the records in a segment, by position.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RowIds {
+    Dense { first: usize, len: usize },
+    Sparse(Vec<usize>),
+}
+
+impl RowIds {
+    pub fn load(dir: &Path, meta: &SegmentMeta) -> Result<Self> {
+        if !meta.sparse {
+            return Ok(RowIds::Dense { first: meta.first_row, len: meta.rows });
+        }
+        let path = segment_file(dir, meta.id, "rows");
+        let data = std::fs::read(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to read segment rows {:?}: {}", path, e))?;
+        let rows: Vec<usize> = data
+            .chunks_exact(8)
+            .map(|c| u64::from_le_bytes(c.try_into().expect("chunk of 8 bytes")) as usize)
+            .collect();
+        if rows.len() != meta.rows {
+            anyhow::bail!("Segment {} lists {} rows, manifest says {}", meta.id, rows.len(), meta.rows);
+        }
+        Ok(RowIds::Sparse(rows))
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            RowIds::Dense { len, .. } => *len,
+            RowIds::Sparse(rows) => rows.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn row(&self, position: usize) -> usize {
+        match self {
+            RowIds::Dense { first, .. } => first + position,
+            RowIds::Sparse(rows) => rows[position],
+        }
+    }
+
+    pub fn position(&self, row: usize) -> Option<usize> {
+        match self {
+            RowIds::Dense { first, len } => (row >= *first && row < first + len).then(|| row - first),
+            RowIds::Sparse(rows) => rows.binary_search(&row).ok(),
+        }
+    }
+
+    /// Grows a dense range after an append to the active segment.
+    pub fn extend(&mut self, added: usize) {
+        match self {
+            RowIds::Dense { len, .. } => *len += added,
+            RowIds::Sparse(_) => panic!("sparse segments are sealed and never appended to"),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len()).map(|position| self.row(position))
+    }
+}
+
+pub fn write_rows(path: &Path, rows: &[usize]) -> Result<()> {
+    let file = File::create(path)
+        .map_err(|e| anyhow::anyhow!("Failed to create segment rows file: {}", e))?;
+    let mut w = BufWriter::new(file);
+    for row in rows {
+        w.write_all(&(*row as u64).to_le_bytes())?;
+    }
+    w.flush()?;
+    w.get_ref().sync_all()
+        .map_err(|e| anyhow::anyhow!("Failed to sync segment rows file: {}", e))
+}
+
+/// Index of the segment whose range holds `row`: the last one starting at or before it.
+pub fn locate(first_rows: impl Iterator<Item = usize>, row: usize) -> Option<usize> {
+    first_rows.take_while(|first| *first <= row).count().checked_sub(1)
+}
+
+pub fn live_rows(rows: &RowIds, tombstones: &Tombstones) -> usize {
+    rows.iter().filter(|row| !tombstones.contains(*row)).count()
+}
+
+/// Picks adjacent sealed segments worth merging, given `(rows, live rows)`
+/// per sealed segment: a run of small segments that fits in one, or a single
+/// segment with enough deleted rows to be worth rewriting.
+pub fn pick_compaction(sealed: &[(usize, usize)], max_rows: usize) -> Option<Range<usize>> {
+    let small = |live: usize| live <= max_rows / 2;
+    let dirty = |rows: usize, live: usize| rows > 0 && (rows - live) as f32 >= rows as f32 * REWRITE_DELETED_RATIO;
+    for start in 0..sealed.len() {
+        let (rows, live) = sealed[start];
+        if !small(live) && !dirty(rows, live) {
+            continue;
+        }
+        let mut end = start + 1;
+        let mut total = live;
+        while end < sealed.len() && small(sealed[end].1) && total + sealed[end].1 <= max_rows {
+            total += sealed[end].1;
+            end += 1;
+        }
+        let any_dirty = sealed[start..end].iter().any(|&(r, l)| dirty(r, l));
+        if end - start > 1 || any_dirty {
+            return Some(start..end);
+        }
+    }
+    None
+}
+
+/// Output of a compaction job, installed under the store lock.
+#[derive(Debug, Clone)]
+pub struct Compacted {
+    /// Ids of the merged segments, in manifest order.
+    pub replaces: Vec<u64>,
+    /// The merged segment, or `None` if every row in it was deleted.
+    pub segment: Option<SegmentMeta>,
+}
+
+/// Merges sealed segments into a new one. Sealed segments are immutable, so
+/// a job runs without holding the store lock while searches keep reading
+/// the old segments.
+pub trait CompactionJob: Send {
+    fn run(self: Box<Self>) -> Result<Compacted>;
+}
+
+/// Describes the merged segment for rows `rows` (ascending) starting at
+/// `first_row`, writing a `.rows` file when they are not a dense range.
+pub fn finish_segment(dir: &Path, id: u64, first_row: usize, rows: &[usize]) -> Result<SegmentMeta> {
+    let dense = rows.iter().enumerate().all(|(i, row)| *row == first_row + i);
+    if !dense {
+        write_rows(&segment_file(dir, id, "rows"), rows)?;
+    }
+    Ok(SegmentMeta { id, first_row, rows: rows.len(), sealed: true, sparse: !dense })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pick_compaction_merges_small_runs_and_rewrites_dirty_segments() {
+        // Full, clean segments are left alone.
+        assert_eq!(pick_compaction(&[(100, 100), (100, 100)], 100), None);
+        // Small neighbours are merged while they fit in one segment.
+        assert_eq!(pick_compaction(&[(100, 100), (40, 40), (30, 30), (50, 50)], 100), Some(1..3));
+        // A single clean small segment has nothing to merge with.
+        assert_eq!(pick_compaction(&[(100, 100), (40, 40)], 100), None);
+        // A segment with many deleted rows is rewritten on its own.
+        assert_eq!(pick_compaction(&[(100, 70), (100, 100)], 100), Some(0..1));
+    }
+
+    #[test]
+    fn row_ids_locate_dense_and_sparse_rows() {
+        let dense = RowIds::Dense { first: 10, len: 5 };
+        assert_eq!(dense.position(12), Some(2));
+        assert_eq!(dense.position(15), None);
+        let sparse = RowIds::Sparse(vec![0, 3, 7]);
+        assert_eq!(sparse.position(3), Some(1));
+        assert_eq!(sparse.position(4), None);
+        assert_eq!(locate([0, 8, 20].into_iter(), 7), Some(0));
+        assert_eq!(locate([0, 8, 20].into_iter(), 25), Some(2));
+    }
+}
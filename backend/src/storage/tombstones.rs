@@ -0,0 +1,116 @@
+use anyhow::Result;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Deleted rows of a store, kept as an append-only log of little-endian `u64`
+/// row numbers and mirrored in an in-memory bitmap for the search loops.
+#[derive(Debug)]
+pub struct Tombstones {
+    path: PathBuf,
+    file: File,
+    bits: Vec<u64>,
+    count: usize,
+}
+
+impl Tombstones {
+    /// Sidecar log next to a store file, e.g. `reviews.vectors.deleted`.
+    pub fn path_for(store_path: &Path) -> PathBuf {
+        let mut name = store_path.as_os_str().to_owned();
+        name.push(".deleted");
+        PathBuf::from(name)
+    }
+
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let data = if path.exists() {
+            std::fs::read(&path)
+                .map_err(|e| anyhow::anyhow!("Failed to read tombstone log {:?}: {}", path, e))?
+        } else {
+            Vec::new()
+        };
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to open tombstone log {:?}: {}", path, e))?;
+        let mut tombstones = Self { path, file, bits: Vec::new(), count: 0 };
+        // A torn trailing entry is dropped; the delete it belonged to never
+        // returned. Cutting it off keeps later entries 8-byte aligned.
+        for chunk in data.chunks_exact(8) {
+            let row = u64::from_le_bytes(chunk.try_into().expect("chunk of 8 bytes")) as usize;
+            tombstones.set(row);
+        }
+        if data.len() % 8 != 0 {
+            tombstones.file.set_len((data.len() - data.len() % 8) as u64)
+                .map_err(|e| anyhow::anyhow!("Failed to truncate tombstone log: {}", e))?;
+        }
+        Ok(tombstones)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Marks `row` deleted. Returns `false` if it already was.
+    pub fn mark(&mut self, row: usize) -> Result<bool> {
+        if self.contains(row) {
+            return Ok(false);
+        }
+        self.file.write_all(&(row as u64).to_le_bytes())
+            .map_err(|e| anyhow::anyhow!("Failed to write tombstone: {}", e))?;
+        self.file.sync_data()
+            .map_err(|e| anyhow::anyhow!("Failed to sync tombstone log: {}", e))?;
+        self.set(row);
+        Ok(true)
+    }
+
+    pub fn contains(&self, row: usize) -> bool {
+        self.bits
+            .get(row / 64)
+            .is_some_and(|word| word & (1 << (row % 64)) != 0)
+    }
+
+    /// Number of deleted rows.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn set(&mut self, row: usize) {
+        let word = row / 64;
+        if word >= self.bits.len() {
+            self.bits.resize(word + 1, 0);
+        }
+        if self.bits[word] & (1 << (row % 64)) == 0 {
+            self.bits[word] |= 1 << (row % 64);
+            self.count += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn torn_entry_is_cut_off_before_later_deletes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reviews.vectors.deleted");
+        let mut log = 3u64.to_le_bytes().to_vec();
+        log.extend_from_slice(&7u64.to_le_bytes()[..5]);
+        std::fs::write(&path, log).unwrap();
+
+        let mut tombstones = Tombstones::open(path.clone()).unwrap();
+        assert!(tombstones.contains(3) && !tombstones.contains(7));
+        assert!(tombstones.mark(9).unwrap());
+        drop(tombstones);
+
+        let tombstones = Tombstones::open(path.clone()).unwrap();
+        assert_eq!(tombstones.len(), 2);
+        assert!(tombstones.contains(3) && tombstones.contains(9));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 16);
+    }
+}
@@ -1,103 +1,544 @@
-#[cfg(feature = "spfresh")]
-pub use spfresh::Index as VectorStore;
-
-#[cfg(not(feature = "spfresh"))]
-mod implementation {
-    use anyhow::Result;
-    use std::fs::{File, OpenOptions};
-    use std::io::{Seek, SeekFrom, Write};
-    use std::f32;
-    use std::mem;
-    use std::path::PathBuf;
-    use bytemuck;
-    use std::collections::BinaryHeap;
-use std::cmp::Reverse;
-    use ordered_float::NotNan;
-
-    #[derive(Debug)]
-    pub struct VectorStore {
-        path: PathBuf,
-        dim: usize,
-        scale: f32,
-    }
-
-    impl VectorStore {
-        pub fn open_or_create(path: PathBuf) -> Result<Self> {
-            if !path.exists() {
-                File::create(&path)?;
+use anyhow::Result;
+use memmap2::Mmap;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use rayon::prelude::*;
+
+use super::header::{self, VectorHeader, VectorSpec};
+use super::index::{RowFilter, VectorIndex};
+use super::kernels::{Kernel, QuantizedQuery, TopK};
+use super::segments::{self, Compacted, CompactionJob, Manifest, RowIds, SegmentConfig, SegmentMeta};
+use super::tombstones::Tombstones;
+
+/// Rows scored by one rayon task; smaller stores are scanned on one thread.
+const ROWS_PER_TASK: usize = 4096;
+
+/// One segment file of normalized vectors quantized to `i8`, memory-mapped
+/// and remapped after appends grow it. Vectors start after the file header.
+#[derive(Debug)]
+struct Segment {
+    meta: SegmentMeta,
+    rows: RowIds,
+    path: PathBuf,
+    dim: usize,
+    data_offset: usize,
+    file: File,
+    map: Option<Mmap>,
+}
+
+impl Segment {
+    fn open(dir: &Path, meta: SegmentMeta, header: &VectorHeader) -> Result<Self> {
+        let path = segments::segment_file(dir, meta.id, "vectors");
+        let data_offset = header::prepare(&path, header)?;
+        let dim = header.dim;
+        let file = OpenOptions::new()
+            .read(true)
+            .append(!meta.sealed)
+            .create(!meta.sealed)
+            .open(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to open vector segment {:?}: {}", path, e))?;
+        let rows = RowIds::load(dir, &meta)?;
+        let mut segment = Self { meta, rows, path, dim, data_offset, file, map: None };
+        segment.remap()?;
+        let count = segment.vectors().len() / dim;
+        if segment.meta.sealed {
+            if count != segment.rows.len() {
+                anyhow::bail!("Vector segment {:?} holds {} vectors, manifest says {}", segment.path, count, segment.rows.len());
             }
-            Ok(Self { path, dim: 128, scale: 127.0 })
+        } else {
+            // Drop a torn trailing vector so later appends stay aligned.
+            segment.file.set_len((data_offset + count * dim) as u64)
+                .map_err(|e| anyhow::anyhow!("Failed to truncate vector segment: {}", e))?;
+            segment.remap()?;
+            segment.rows = RowIds::Dense { first: segment.meta.first_row, len: count };
         }
+        Ok(segment)
+    }
 
-        pub fn append(&mut self, vector: &[f32]) -> Result<()> {
-            assert_eq!(vector.len(), self.dim, "vector dim mismatch");
-            let norm: f32 = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
-            let norm = if norm == 0.0 { 1.0 } else { norm };
-            let mut quantized: Vec<i8> = Vec::with_capacity(self.dim);
-            for &v in vector {
-                let val = (v / norm).clamp(-1.0, 1.0) * self.scale;
-                quantized.push(val.round() as i8);
-            }
-            let mut file = OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(&self.path)
-                .map_err(|e| anyhow::anyhow!("Failed to open vector store file: {}", e))?;
-            let bytes: &[u8] = bytemuck::cast_slice(&quantized);
-            file.write_all(bytes)
-                .map_err(|e| anyhow::anyhow!("Failed to write vector to file: {}", e))?;
-            file.sync_all()
-                .map_err(|e| anyhow::anyhow!("Failed to sync vector store to disk: {}", e))?;
-            Ok(())
-        }
-
-        #[allow(dead_code)]
-        pub fn len(&self) -> Result<usize> {
-            let mut file = File::open(&self.path)
-                .map_err(|e| anyhow::anyhow!("Failed to open vector store file: {}", e))?;
-            let size = file.seek(SeekFrom::End(0))
-                .map_err(|e| anyhow::anyhow!("Failed to seek to end of vector store file: {}", e))? as usize;
-            Ok(size / (self.dim * mem::size_of::<i8>()))
-        }
-
-        pub fn search(&self, query: &[f32], top_k: usize) -> Result<Vec<(usize, f32)>> {
-            assert!(top_k > 0, "top_k must be > 0");
-            assert_eq!(query.len(), self.dim, "query dim mismatch");
-            
-            let data = std::fs::read(&self.path)
-                .map_err(|e| anyhow::anyhow!("Failed to read vector store file: {}", e))?;
-            if data.is_empty() {
-                return Ok(Vec::new());
+    /// Maps the whole file again after it has grown.
+    fn remap(&mut self) -> Result<()> {
+        let size = self.file.metadata()
+            .map_err(|e| anyhow::anyhow!("Failed to stat vector segment: {}", e))?
+            .len();
+        if size == 0 {
+            self.map = None;
+            return Ok(());
+        }
+        // SAFETY: segment files are only ever appended to while active, and
+        // only through this store, so the mapped range is never truncated.
+        let map = unsafe { Mmap::map(&self.file) }
+            .map_err(|e| anyhow::anyhow!("Failed to map vector segment {:?}: {}", self.path, e))?;
+        self.map = Some(map);
+        Ok(())
+    }
+
+    /// The mapped vectors, ignoring a torn trailing vector if any.
+    fn vectors(&self) -> &[i8] {
+        match &self.map {
+            Some(map) if map.len() > self.data_offset => {
+                let data = &map[self.data_offset..];
+                bytemuck::cast_slice(&data[..data.len() - data.len() % self.dim])
             }
-            let total_i8: &[i8] = bytemuck::cast_slice(&data);
-            let num_vecs = total_i8.len() / self.dim;
-            let mut heap: BinaryHeap<Reverse<(NotNan<f32>, usize)>> =
-                BinaryHeap::with_capacity(top_k + 1);
-            for i in 0..num_vecs {
-                let start = i * self.dim;
-                let end = start + self.dim;
-                let vec_slice = &total_i8[start..end];
-                let mut score = 0.0f32;
-                for (a, &b_q) in query.iter().zip(vec_slice.iter()) {
-                    let b = (b_q as f32) / self.scale;
-                    score += a * b;
-                }
-                if let Ok(not_nan) = NotNan::new(score) {
-                    heap.push(Reverse((not_nan, i)));
-                    if heap.len() > top_k {
-                        heap.pop();
+            _ => &[],
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    fn write(&mut self, quantized: &[i8]) -> Result<()> {
+        let bytes: &[u8] = bytemuck::cast_slice(quantized);
+        self.file.write_all(bytes)
+            .map_err(|e| anyhow::anyhow!("Failed to write vector to file: {}", e))?;
+        self.file.sync_data()
+            .map_err(|e| anyhow::anyhow!("Failed to sync vector store to disk: {}", e))?;
+        self.rows.extend(quantized.len() / self.dim);
+        self.remap()
+    }
+
+    fn scan(&self, query: &QuantizedQuery, kernel: Kernel, top_k: usize, tombstones: &Tombstones, accept: &RowFilter) -> TopK {
+        self.vectors()
+            .par_chunks(self.dim)
+            .enumerate()
+            .with_min_len(ROWS_PER_TASK)
+            .fold(
+                || TopK::new(top_k),
+                |mut top, (i, vector)| {
+                    let row = self.rows.row(i);
+                    if !tombstones.contains(row) && accept(row) {
+                        top.push(query.score(kernel, vector), row);
                     }
-                }
+                    top
+                },
+            )
+            .reduce(|| TopK::new(top_k), TopK::merge)
+    }
+}
+
+/// Brute-force store: normalized vectors quantized to `i8`, split into
+/// segments under one directory (see [`segments`]).
+///
+/// Every segment is memory-mapped, so a search scans the mapped bytes in
+/// place without any per-query I/O. Deleted rows are tracked in a `deleted`
+/// tombstone log, skipped by search and dropped when segments are compacted.
+#[derive(Debug)]
+pub struct VectorStore {
+    dir: PathBuf,
+    dim: usize,
+    scale: f32,
+    header: VectorHeader,
+    config: SegmentConfig,
+    manifest: Manifest,
+    /// Shared with running compaction jobs, which read them without the lock.
+    sealed: Vec<Arc<Segment>>,
+    active: Segment,
+    tombstones: Tombstones,
+}
+
+impl VectorStore {
+    pub fn open_or_create(dir: PathBuf, spec: &VectorSpec) -> Result<Self> {
+        Self::open_with_config(dir, spec, SegmentConfig::from_env())
+    }
+
+    pub fn open_with_config(dir: PathBuf, spec: &VectorSpec, config: SegmentConfig) -> Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| anyhow::anyhow!("Failed to create vector segment directory: {}", e))?;
+        let manifest = match Manifest::load(&dir)? {
+            Some(manifest) => manifest,
+            None => {
+                let manifest = Manifest::new(vec![SegmentMeta::active(1, 0)]);
+                manifest.save(&dir)?;
+                manifest
             }
-            let mut results: Vec<(usize, f32)> = heap.into_iter()
-                .map(|Reverse((s, i))| (i, s.into_inner()))
-                .collect();
-            results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
-            Ok(results)
+        };
+        segments::remove_orphans(&dir, &manifest)?;
+        let scale = 127.0;
+        let header = VectorHeader::quantized_i8(spec, scale);
+        let (active, sealed) = manifest
+            .segments
+            .split_last()
+            .ok_or_else(|| anyhow::anyhow!("Vector segment manifest lists no segments"))?;
+        let sealed = sealed
+            .iter()
+            .map(|meta| Segment::open(&dir, meta.clone(), &header).map(Arc::new))
+            .collect::<Result<Vec<_>>>()?;
+        let active = Segment::open(&dir, active.clone(), &header)?;
+        let tombstones = Tombstones::open(dir.join("deleted"))?;
+        Ok(Self { dir, dim: spec.dim, scale, header, config, manifest, sealed, active, tombstones })
+    }
+
+    /// Moves a pre-segment `reviews.vectors` file and its tombstones into
+    /// `dir` as the first sealed segment. Does nothing if `dir` already has a
+    /// manifest or there is no legacy file.
+    pub fn migrate_legacy(legacy: &Path, dir: &Path, spec: &VectorSpec) -> Result<bool> {
+        if !legacy.exists() || Manifest::load(dir)?.is_some() {
+            return Ok(false);
+        }
+        std::fs::create_dir_all(dir)
+            .map_err(|e| anyhow::anyhow!("Failed to create vector segment directory: {}", e))?;
+        let data_offset = header::prepare(legacy, &VectorHeader::quantized_i8(spec, 127.0))?;
+        let size = std::fs::metadata(legacy)
+            .map_err(|e| anyhow::anyhow!("Failed to stat legacy vector file: {}", e))?
+            .len() as usize;
+        let rows = (size - data_offset) / spec.dim;
+        OpenOptions::new()
+            .write(true)
+            .open(legacy)
+            .and_then(|f| f.set_len((data_offset + rows * spec.dim) as u64))
+            .map_err(|e| anyhow::anyhow!("Failed to truncate legacy vector file: {}", e))?;
+        std::fs::rename(legacy, segments::segment_file(dir, 1, "vectors"))
+            .map_err(|e| anyhow::anyhow!("Failed to move legacy vector file: {}", e))?;
+        let legacy_deleted = Tombstones::path_for(legacy);
+        if legacy_deleted.exists() {
+            std::fs::rename(&legacy_deleted, dir.join("deleted"))
+                .map_err(|e| anyhow::anyhow!("Failed to move legacy tombstone log: {}", e))?;
+        }
+        let first = SegmentMeta { id: 1, first_row: 0, rows, sealed: true, sparse: false };
+        Manifest::new(vec![first, SegmentMeta::active(2, rows)]).save(dir)?;
+        Ok(true)
+    }
+
+    fn segments(&self) -> impl Iterator<Item = &Segment> {
+        self.sealed.iter().map(|s| s.as_ref()).chain(std::iter::once(&self.active))
+    }
+
+    fn quantize(&self, vector: &[f32]) -> Vec<i8> {
+        let norm: f32 = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+        let norm = if norm == 0.0 { 1.0 } else { norm };
+        let mut quantized: Vec<i8> = Vec::with_capacity(self.dim);
+        for &v in vector {
+            let val = (v / norm).clamp(-1.0, 1.0) * self.scale;
+            quantized.push(val.round() as i8);
         }
+        quantized
     }
 
+    /// Appends to the active segment, sealing it whenever it fills up.
+    fn write(&mut self, quantized: &[i8]) -> Result<()> {
+        let mut rest = quantized;
+        while !rest.is_empty() {
+            if self.active.len() >= self.config.max_rows {
+                self.seal()?;
+            }
+            let room = self.config.max_rows - self.active.len();
+            let take = room.min(rest.len() / self.dim) * self.dim;
+            self.active.write(&rest[..take])?;
+            rest = &rest[take..];
+        }
+        Ok(())
+    }
+
+    fn seal(&mut self) -> Result<()> {
+        self.active.file.sync_all()
+            .map_err(|e| anyhow::anyhow!("Failed to sync vector segment: {}", e))?;
+        let mut manifest = self.manifest.clone();
+        let next = manifest.seal_active(self.active.len());
+        let next = Segment::open(&self.dir, next, &self.header)?;
+        manifest.save(&self.dir)?;
+        self.manifest = manifest;
+        let mut sealed = std::mem::replace(&mut self.active, next);
+        sealed.meta = self.manifest.segments[self.sealed.len()].clone();
+        self.sealed.push(Arc::new(sealed));
+        Ok(())
+    }
 }
 
-#[cfg(not(feature = "spfresh"))]
-pub use implementation::VectorStore;
+impl VectorIndex for VectorStore {
+    fn append(&mut self, vector: &[f32]) -> Result<()> {
+        header::check_dim(self.dim, vector.len())?;
+        let quantized = self.quantize(vector);
+        self.write(&quantized)
+    }
+
+    fn append_batch(&mut self, vectors: &[Vec<f32>]) -> Result<()> {
+        let mut quantized: Vec<i8> = Vec::with_capacity(vectors.len() * self.dim);
+        for vector in vectors {
+            header::check_dim(self.dim, vector.len())?;
+            quantized.extend(self.quantize(vector));
+        }
+        self.write(&quantized)
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.active.meta.first_row + self.active.len())
+    }
+
+    fn search(&self, query: &[f32], top_k: usize) -> Result<Vec<(usize, f32)>> {
+        self.search_filtered(query, top_k, &|_| true)
+    }
+
+    fn search_filtered(&self, query: &[f32], top_k: usize, accept: &RowFilter) -> Result<Vec<(usize, f32)>> {
+        assert!(top_k > 0, "top_k must be > 0");
+        header::check_dim(self.dim, query.len())?;
+
+        let query = QuantizedQuery::new(query, self.scale);
+        let kernel = Kernel::detect();
+        let top = self
+            .segments()
+            .map(|segment| segment.scan(&query, kernel, top_k, &self.tombstones, accept))
+            .fold(TopK::new(top_k), TopK::merge);
+        Ok(top.into_sorted_vec())
+    }
+
+    fn get_vector(&self, id: usize) -> Result<Option<Vec<f32>>> {
+        let first_rows = self.segments().map(|s| s.meta.first_row);
+        let Some(segment) = segments::locate(first_rows, id).and_then(|i| self.segments().nth(i)) else {
+            return Ok(None);
+        };
+        let Some(position) = segment.rows.position(id) else {
+            return Ok(None);
+        };
+        let vector = &segment.vectors()[position * self.dim..(position + 1) * self.dim];
+        Ok(Some(vector.iter().map(|&q| q as f32 / self.scale).collect()))
+    }
+
+    fn delete(&mut self, id: usize) -> Result<()> {
+        if id >= self.len()? {
+            anyhow::bail!("Vector row {} does not exist", id);
+        }
+        self.tombstones.mark(id)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        // Every append and delete is synced before it returns.
+        Ok(())
+    }
+
+    fn plan_compaction(&mut self) -> Result<Option<Box<dyn CompactionJob>>> {
+        let stats: Vec<(usize, usize)> = self
+            .sealed
+            .iter()
+            .map(|s| (s.len(), segments::live_rows(&s.rows, &self.tombstones)))
+            .collect();
+        let Some(range) = segments::pick_compaction(&stats, self.config.max_rows) else {
+            return Ok(None);
+        };
+        let inputs: Vec<Arc<Segment>> = self.sealed[range].to_vec();
+        let deleted = inputs
+            .iter()
+            .flat_map(|s| s.rows.iter())
+            .filter(|row| self.tombstones.contains(*row))
+            .collect();
+        Ok(Some(Box::new(VectorCompaction {
+            dir: self.dir.clone(),
+            header: self.header.clone(),
+            id: self.manifest.allocate_id(),
+            inputs,
+            deleted,
+        })))
+    }
+
+    fn apply_compaction(&mut self, done: Compacted) -> Result<()> {
+        let mut manifest = self.manifest.clone();
+        let at = manifest.replace(&done)?;
+        let merged = match &done.segment {
+            Some(meta) => Some(Arc::new(Segment::open(&self.dir, meta.clone(), &self.header)?)),
+            None => None,
+        };
+        manifest.save(&self.dir)?;
+        self.manifest = manifest;
+        self.sealed.splice(at..at + done.replaces.len(), merged);
+        for id in done.replaces {
+            segments::remove_segment_files(&self.dir, id, &["vectors"]);
+        }
+        Ok(())
+    }
+}
+
+/// Copies the live rows of adjacent sealed segments into one new segment.
+struct VectorCompaction {
+    dir: PathBuf,
+    header: VectorHeader,
+    id: u64,
+    inputs: Vec<Arc<Segment>>,
+    deleted: HashSet<usize>,
+}
+
+impl CompactionJob for VectorCompaction {
+    fn run(self: Box<Self>) -> Result<Compacted> {
+        let replaces = self.inputs.iter().map(|s| s.meta.id).collect();
+        let path = segments::segment_file(&self.dir, self.id, "vectors");
+        let file = File::create(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to create vector segment {:?}: {}", path, e))?;
+        let mut w = BufWriter::new(file);
+        w.write_all(&self.header.encode())?;
+        let mut rows = Vec::new();
+        for segment in &self.inputs {
+            for (i, vector) in segment.vectors().chunks(segment.dim).enumerate() {
+                let row = segment.rows.row(i);
+                if !self.deleted.contains(&row) {
+                    w.write_all(bytemuck::cast_slice(vector))?;
+                    rows.push(row);
+                }
+            }
+        }
+        w.flush()?;
+        w.get_ref().sync_all()
+            .map_err(|e| anyhow::anyhow!("Failed to sync vector segment: {}", e))?;
+        if rows.is_empty() {
+            std::fs::remove_file(&path)?;
+            return Ok(Compacted { replaces, segment: None });
+        }
+        let first_row = self.inputs[0].meta.first_row;
+        let segment = segments::finish_segment(&self.dir, self.id, first_row, &rows)?;
+        Ok(Compacted { replaces, segment: Some(segment) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::error::StorageError;
+
+    fn spec() -> VectorSpec {
+        VectorSpec::new(128, "test-model")
+    }
+
+    fn unit(dim: usize, hot: usize) -> Vec<f32> {
+        let mut v = vec![0.0; dim];
+        v[hot] = 1.0;
+        v
+    }
+
+    #[test]
+    fn appends_are_visible_to_search_and_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vectors");
+        let mut store = VectorStore::open_or_create(path.clone(), &spec()).unwrap();
+        assert!(store.search(&unit(128, 0), 1).unwrap().is_empty());
+
+        store.append(&unit(128, 3)).unwrap();
+        store.append_batch(&[unit(128, 5), unit(128, 7)]).unwrap();
+        assert_eq!(store.len().unwrap(), 3);
+        assert_eq!(store.search(&unit(128, 7), 1).unwrap()[0].0, 2);
+
+        drop(store);
+        let store = VectorStore::open_or_create(path, &spec()).unwrap();
+        assert_eq!(store.len().unwrap(), 3);
+        assert_eq!(store.search(&unit(128, 5), 1).unwrap()[0].0, 1);
+    }
+
+    #[test]
+    fn dimension_is_a_property_of_the_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vectors");
+        let wide = VectorSpec::new(384, "test-model");
+        let mut store = VectorStore::open_or_create(path.clone(), &wide).unwrap();
+        store.append_batch(&[unit(384, 300), unit(384, 10)]).unwrap();
+
+        let err = store.append(&unit(128, 3)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::DimensionMismatch { expected: 384, found: 128 })
+        ));
+        assert!(store.search(&unit(768, 0), 1).is_err());
+        assert_eq!(store.len().unwrap(), 2);
+
+        drop(store);
+        let store = VectorStore::open_or_create(path.clone(), &wide).unwrap();
+        assert_eq!(store.search(&unit(384, 300), 1).unwrap()[0].0, 0);
+        drop(store);
+        assert!(VectorStore::open_or_create(path, &spec()).is_err());
+    }
+
+    #[test]
+    fn deleted_rows_are_never_returned() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vectors");
+        let mut store = VectorStore::open_or_create(path.clone(), &spec()).unwrap();
+        store.append_batch(&[unit(128, 1), unit(128, 1), unit(128, 2)]).unwrap();
+        store.delete(0).unwrap();
+        assert!(store.delete(3).is_err());
+
+        let ids: Vec<usize> = store.search(&unit(128, 1), 3).unwrap().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![1, 2]);
+
+        drop(store);
+        let store = VectorStore::open_or_create(path, &spec()).unwrap();
+        assert_eq!(store.search(&unit(128, 1), 1).unwrap()[0].0, 1);
+        // Filters apply before top-k, so the weaker match still fills the result.
+        let filtered = store.search_filtered(&unit(128, 1), 1, &|row| row != 1).unwrap();
+        assert_eq!(filtered.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn parallel_scan_matches_sequential_scalar_scan() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let dir = tempfile::tempdir().unwrap();
+        let config = SegmentConfig { max_rows: ROWS_PER_TASK + 100 };
+        let mut store = VectorStore::open_with_config(dir.path().join("vectors"), &spec(), config).unwrap();
+        let mut rng = StdRng::seed_from_u64(9);
+        let vectors: Vec<Vec<f32>> = (0..3 * ROWS_PER_TASK)
+            .map(|_| (0..128).map(|_| rng.gen_range(-1.0f32..1.0)).collect())
+            .collect();
+        store.append_batch(&vectors).unwrap();
+
+        let query: Vec<f32> = (0..128).map(|_| rng.gen_range(-1.0f32..1.0)).collect();
+        let quantized = QuantizedQuery::new(&query, store.scale);
+        let mut expected = TopK::new(25);
+        for (i, vector) in vectors.iter().enumerate() {
+            expected.push(quantized.score(Kernel::Scalar, &store.quantize(vector)), i);
+        }
+        assert_eq!(store.search(&query, 25).unwrap(), expected.into_sorted_vec());
+    }
+
+    #[test]
+    fn compaction_drops_deleted_rows_and_keeps_row_numbers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vectors");
+        let config = SegmentConfig { max_rows: 4 };
+        let mut store = VectorStore::open_with_config(path.clone(), &spec(), config).unwrap();
+        store.append_batch(&(0..10).map(|i| unit(128, i)).collect::<Vec<_>>()).unwrap();
+        assert_eq!(store.sealed.len(), 2);
+        for row in [1, 2, 5] {
+            store.delete(row).unwrap();
+        }
+
+        let mut jobs = 0;
+        while let Some(job) = store.plan_compaction().unwrap() {
+            // The job reads sealed segments only; appends may continue meanwhile.
+            store.append(&unit(128, 10 + jobs)).unwrap();
+            let done = job.run().unwrap();
+            store.apply_compaction(done).unwrap();
+            jobs += 1;
+        }
+        assert!(jobs > 0);
+        let files = std::fs::read_dir(&path).unwrap().count();
+
+        drop(store);
+        let store = VectorStore::open_with_config(path.clone(), &spec(), config).unwrap();
+        assert_eq!(std::fs::read_dir(&path).unwrap().count(), files);
+        assert_eq!(store.len().unwrap(), 10 + jobs);
+        for row in [0, 3, 4, 6, 7, 9, 10] {
+            assert_eq!(store.search(&unit(128, row), 1).unwrap()[0].0, row);
+        }
+        let rows: Vec<usize> = store.search(&unit(128, 1), 20).unwrap().into_iter().map(|(id, _)| id).collect();
+        assert!(!rows.contains(&1) && !rows.contains(&2) && !rows.contains(&5));
+    }
+
+    #[test]
+    fn legacy_vector_file_becomes_first_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy = dir.path().join("reviews.vectors");
+        let q: Vec<i8> = (0..3).flat_map(|i| { let mut v = vec![0i8; 128]; v[i] = 127; v }).collect();
+        std::fs::write(&legacy, bytemuck::cast_slice(&q)).unwrap();
+
+        let path = dir.path().join("vectors");
+        assert!(VectorStore::migrate_legacy(&legacy, &path, &spec()).unwrap());
+        assert!(!legacy.exists());
+        let mut store = VectorStore::open_or_create(path, &spec()).unwrap();
+        assert_eq!(store.len().unwrap(), 3);
+        store.append(&unit(128, 9)).unwrap();
+        assert_eq!(store.search(&unit(128, 2), 1).unwrap()[0].0, 2);
+        assert_eq!(store.search(&unit(128, 9), 1).unwrap()[0].0, 3);
+        drop(store);
+
+        let err = VectorStore::open_or_create(dir.path().join("vectors"), &VectorSpec::new(128, "other-model")).unwrap_err();
+        assert!(err.to_string().contains("embedding model"), "{}", err);
+    }
+}
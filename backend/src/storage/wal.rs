@@ -0,0 +1,355 @@
+//! Write-ahead log that makes inserts and deletes atomic across the vector
+//! index and the metadata store.
+//!
+//! Every insert and delete is written to `reviews.wal` as one entry before
+//! either store is touched:
+//!
+//! ```text
+//! len u32 | crc32 u32 | row u64 | dim u32 | dim * f32 | JSON line
+//! len u32 | crc32 u32 | row u64 | 0xffffffff
+//! ```
+//!
+//! The second form is a delete. `len` covers everything after the checksum
+//! and all integers are little endian. On startup the entries are replayed
+//! into whichever store is behind, so a crash between the two writes neither
+//! shifts every later row nor leaves a row live in only one store. A torn or
+//! corrupt tail belongs to a write that never returned and is cut off. Once
+//! both stores are flushed the log is truncated.
+
+use anyhow::Result;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::index::VectorIndex;
+use super::metadata::MetadataStore;
+
+/// Size of the `len` and `crc32` fields in front of each entry.
+const FRAME_LEN: usize = 8;
+
+/// Log size after which the stores are flushed and the log truncated.
+pub const CHECKPOINT_BYTES: u64 = 64 << 20;
+
+/// `dim` value marking a delete entry.
+const DELETE: u32 = u32::MAX;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WalEntry {
+    /// The row a new review was assigned, its vector and its metadata line.
+    Insert { row: usize, vector: Vec<f32>, json: String },
+    /// A row deleted from both stores.
+    Delete { row: usize },
+}
+
+impl WalEntry {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(&[0; FRAME_LEN]);
+        match self {
+            WalEntry::Insert { row, vector, json } => {
+                out.extend_from_slice(&(*row as u64).to_le_bytes());
+                out.extend_from_slice(&(vector.len() as u32).to_le_bytes());
+                for v in vector {
+                    out.extend_from_slice(&v.to_le_bytes());
+                }
+                out.extend_from_slice(json.as_bytes());
+            }
+            WalEntry::Delete { row } => {
+                out.extend_from_slice(&(*row as u64).to_le_bytes());
+                out.extend_from_slice(&DELETE.to_le_bytes());
+            }
+        }
+        let len = (out.len() - start - FRAME_LEN) as u32;
+        out[start..start + 4].copy_from_slice(&len.to_le_bytes());
+        let crc = crc32fast::hash(&out[start + FRAME_LEN..]);
+        out[start + 4..start + FRAME_LEN].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// Decodes the entry at the start of `data` and returns it with its
+    /// encoded length, or `None` if it is torn or fails its checksum.
+    fn decode(data: &[u8]) -> Option<(Self, usize)> {
+        let len = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?) as usize;
+        let crc = u32::from_le_bytes(data.get(4..8)?.try_into().ok()?);
+        let payload = data.get(FRAME_LEN..FRAME_LEN + len)?;
+        if crc32fast::hash(payload) != crc {
+            return None;
+        }
+        let row = u64::from_le_bytes(payload.get(0..8)?.try_into().ok()?) as usize;
+        let dim = u32::from_le_bytes(payload.get(8..12)?.try_into().ok()?);
+        if dim == DELETE {
+            return Some((WalEntry::Delete { row }, FRAME_LEN + len));
+        }
+        let dim = dim as usize;
+        let vector_end = 12usize.checked_add(dim.checked_mul(4)?)?;
+        let vector = payload
+            .get(12..vector_end)?
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().expect("chunk of 4 bytes")))
+            .collect();
+        let json = String::from_utf8(payload[vector_end..].to_vec()).ok()?;
+        Some((WalEntry::Insert { row, vector, json }, FRAME_LEN + len))
+    }
+}
+
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl Wal {
+    /// The log of a data directory, `reviews.wal`.
+    pub fn path_for(data_dir: &Path) -> PathBuf {
+        data_dir.join("reviews.wal")
+    }
+
+    /// Opens the log and returns the entries it still holds. A torn or
+    /// corrupt tail is truncated away.
+    pub fn open(path: PathBuf) -> Result<(Self, Vec<WalEntry>)> {
+        let data = if path.exists() {
+            std::fs::read(&path)
+                .map_err(|e| anyhow::anyhow!("Failed to read write-ahead log {:?}: {}", path, e))?
+        } else {
+            Vec::new()
+        };
+        let mut entries = Vec::new();
+        let mut valid = 0;
+        while let Some((entry, len)) = WalEntry::decode(&data[valid..]) {
+            entries.push(entry);
+            valid += len;
+        }
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to open write-ahead log {:?}: {}", path, e))?;
+        if valid < data.len() {
+            tracing::warn!("Dropping {} bytes of torn write-ahead log entries", data.len() - valid);
+            file.set_len(valid as u64)
+                .and_then(|_| file.sync_all())
+                .map_err(|e| anyhow::anyhow!("Failed to truncate write-ahead log: {}", e))?;
+        }
+        Ok((Self { path, file, size: valid as u64 }, entries))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Bytes currently in the log.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Writes `entries` with a single write and sync, so a batch costs one
+    /// fsync however many rows it holds.
+    pub fn append(&mut self, entries: &[WalEntry]) -> Result<()> {
+        let mut buf = Vec::new();
+        for entry in entries {
+            entry.encode(&mut buf);
+        }
+        self.file.write_all(&buf)
+            .map_err(|e| anyhow::anyhow!("Failed to write to write-ahead log: {}", e))?;
+        self.file.sync_data()
+            .map_err(|e| anyhow::anyhow!("Failed to sync write-ahead log: {}", e))?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Empties the log. Only call this once both stores have been flushed.
+    pub fn reset(&mut self) -> Result<()> {
+        self.file.set_len(0)
+            .and_then(|_| self.file.sync_all())
+            .map_err(|e| anyhow::anyhow!("Failed to truncate write-ahead log: {}", e))?;
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Flushes both stores and truncates the log.
+    pub fn checkpoint(&mut self, vectors: &mut dyn VectorIndex, metadata: &mut MetadataStore) -> Result<()> {
+        vectors.flush()?;
+        metadata.flush()?;
+        self.reset()
+    }
+}
+
+/// Writes each insert into the stores that do not hold its row yet, and
+/// deletes each deleted row from both. Rows a store already has and rows
+/// already deleted are skipped, so applying the same entries twice is
+/// harmless. Pass `None` for `vectors` to bring only the metadata up to date.
+pub fn apply(entries: &[WalEntry], mut vectors: Option<&mut dyn VectorIndex>, metadata: &mut MetadataStore) -> Result<()> {
+    let mut rest = entries;
+    while let Some(first) = rest.first() {
+        if let WalEntry::Delete { row } = first {
+            if let Some(vectors) = vectors.as_deref_mut() {
+                vectors.delete(*row)?;
+            }
+            metadata.delete(*row)?;
+            rest = &rest[1..];
+            continue;
+        }
+        // Consecutive inserts go into each store as one batch.
+        let run = rest.iter().position(|entry| matches!(entry, WalEntry::Delete { .. })).unwrap_or(rest.len());
+        let (inserts, next) = rest.split_at(run);
+        if let Some(vectors) = vectors.as_deref_mut() {
+            let missing = missing_inserts(inserts, vectors.len()?, "Vector index")?;
+            if !missing.is_empty() {
+                vectors.append_batch(&missing.iter().map(|(vector, _)| vector.to_vec()).collect::<Vec<_>>())?;
+            }
+        }
+        let missing = missing_inserts(inserts, metadata.len(), "Metadata store")?;
+        metadata.append_lines(&missing.iter().map(|(_, json)| *json).collect::<Vec<_>>())?;
+        rest = next;
+    }
+    Ok(())
+}
+
+/// The vectors and lines of `inserts` a store holding `len` rows lacks.
+fn missing_inserts<'a>(inserts: &'a [WalEntry], len: usize, store: &str) -> Result<Vec<(&'a [f32], &'a str)>> {
+    let mut missing = Vec::new();
+    for entry in inserts {
+        if let WalEntry::Insert { row, vector, json } = entry {
+            let next = len + missing.len();
+            if next < *row {
+                anyhow::bail!("{} has {} rows, write-ahead log continues at row {}", store, next, row);
+            }
+            if next == *row {
+                missing.push((vector.as_slice(), json.as_str()));
+            }
+        }
+    }
+    Ok(missing)
+}
+
+/// Replays the log into both stores, then pads whichever store is still
+/// behind with deleted placeholder rows so their row counts agree. That only
+/// happens for data written before the log existed. Returns the number of
+/// rows recovered from the log.
+pub fn recover(
+    wal: &mut Wal,
+    entries: &[WalEntry],
+    vectors: &mut dyn VectorIndex,
+    metadata: &mut MetadataStore,
+    dim: usize,
+) -> Result<usize> {
+    let before = vectors.len()?.min(metadata.len());
+    apply(entries, Some(&mut *vectors), metadata)?;
+    let recovered = vectors.len()?.min(metadata.len()) - before;
+
+    let mut vector_rows = vectors.len()?;
+    let mut metadata_rows = metadata.len();
+    if vector_rows != metadata_rows {
+        tracing::warn!(
+            "Vector index has {} rows but metadata store has {}; padding with deleted rows",
+            vector_rows,
+            metadata_rows
+        );
+    }
+    while vector_rows < metadata_rows {
+        vectors.append(&vec![0.0; dim])?;
+        vectors.delete(vector_rows)?;
+        vector_rows += 1;
+    }
+    while metadata_rows < vector_rows {
+        metadata.append_line("null")?;
+        metadata.delete(metadata_rows)?;
+        vectors.delete(metadata_rows)?;
+        metadata_rows += 1;
+    }
+    wal.checkpoint(vectors, metadata)?;
+    Ok(recovered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::header::VectorSpec;
+    use crate::storage::vector_store::VectorStore;
+
+    fn vector(row: usize) -> Vec<f32> {
+        let mut vector = vec![0.0; 16];
+        vector[row % 16] = 1.0;
+        vector
+    }
+
+    fn json(row: usize) -> String {
+        format!("{{\"n\":{}}}", row)
+    }
+
+    fn entry(row: usize) -> WalEntry {
+        WalEntry::Insert { row, vector: vector(row), json: json(row) }
+    }
+
+    #[test]
+    fn torn_tail_is_dropped_and_replay_fills_the_lagging_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = Wal::path_for(dir.path());
+        let (mut wal, entries) = Wal::open(path.clone()).unwrap();
+        assert!(entries.is_empty());
+        wal.append(&[entry(0), entry(1)]).unwrap();
+        wal.append(&[entry(2)]).unwrap();
+        drop(wal);
+        // Cut the last entry short, as a crash in the middle of its write would.
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+
+        let (mut wal, entries) = Wal::open(path.clone()).unwrap();
+        assert_eq!(entries, vec![entry(0), entry(1)]);
+
+        // The crash happened after the vector append but before the metadata one.
+        let mut vectors = VectorStore::open_or_create(dir.path().join("vectors"), &VectorSpec::new(16, "test")).unwrap();
+        let mut metadata = MetadataStore::open_or_create(dir.path().join("metadata")).unwrap();
+        vectors.append(&vector(0)).unwrap();
+        vectors.append(&vector(1)).unwrap();
+        metadata.append_line(&json(0)).unwrap();
+
+        assert_eq!(recover(&mut wal, &entries, &mut vectors, &mut metadata, 16).unwrap(), 1);
+        assert_eq!(vectors.len().unwrap(), 2);
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(metadata.get_by_index::<serde_json::Value>(1).unwrap().unwrap()["n"], 1);
+        assert_eq!(wal.size(), 0);
+        assert!(Wal::open(path).unwrap().1.is_empty());
+    }
+
+    #[test]
+    fn stores_without_log_entries_are_padded_to_the_same_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut wal, _) = Wal::open(Wal::path_for(dir.path())).unwrap();
+        let mut vectors = VectorStore::open_or_create(dir.path().join("vectors"), &VectorSpec::new(16, "test")).unwrap();
+        let mut metadata = MetadataStore::open_or_create(dir.path().join("metadata")).unwrap();
+        vectors.append_batch(&[vector(0), vector(1), vector(2)]).unwrap();
+        metadata.append_line(&json(0)).unwrap();
+
+        recover(&mut wal, &[], &mut vectors, &mut metadata, 16).unwrap();
+        assert_eq!(metadata.len(), 3);
+        assert!(metadata.is_deleted(2).unwrap());
+        let hits: Vec<usize> = vectors.search(&vector(2), 3).unwrap().into_iter().map(|(row, _)| row).collect();
+        assert!(!hits.contains(&2));
+    }
+
+    #[test]
+    fn replayed_deletes_reach_both_stores() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = Wal::path_for(dir.path());
+        let (mut wal, _) = Wal::open(path.clone()).unwrap();
+        wal.append(&[entry(0), entry(1)]).unwrap();
+        wal.append(&[WalEntry::Delete { row: 0 }]).unwrap();
+        drop(wal);
+
+        // The crash happened after the vector was tombstoned but before the
+        // metadata row was.
+        let (mut wal, entries) = Wal::open(path).unwrap();
+        assert_eq!(entries[2], WalEntry::Delete { row: 0 });
+        let mut vectors = VectorStore::open_or_create(dir.path().join("vectors"), &VectorSpec::new(16, "test")).unwrap();
+        let mut metadata = MetadataStore::open_or_create(dir.path().join("metadata")).unwrap();
+        apply(&entries[..2], Some(&mut vectors), &mut metadata).unwrap();
+        vectors.delete(0).unwrap();
+
+        recover(&mut wal, &entries, &mut vectors, &mut metadata, 16).unwrap();
+        assert!(metadata.is_deleted(0).unwrap());
+        assert!(!metadata.is_deleted(1).unwrap());
+        let hits: Vec<usize> = vectors.search(&vector(0), 2).unwrap().into_iter().map(|(row, _)| row).collect();
+        assert_eq!(hits, vec![1]);
+    }
+}
@@ -5,7 +5,7 @@ use wasm_bindgen_futures::spawn_local;
 use gloo_file::{File, futures::read_as_text};
 use wasm_bindgen::JsCast;
 
-use crate::models::{Review, SearchQuery, SearchResult};
+use crate::models::{Explanation, Review, SearchQuery, SearchResult};
 
 const BACKEND_URL: &str = "/api";
 
@@ -262,31 +262,23 @@ fn InsertForm() -> impl IntoView {
 fn SearchPage() -> impl IntoView {
     let (query, set_query) = create_signal(String::new());
     let (topk, set_topk) = create_signal(5_usize);
+    let (explain, set_explain) = create_signal(false);
     let (results, set_results) = create_signal(Vec::<SearchResult>::new());
     let (is_loading, set_loading) = create_signal(false);
     let (error, set_error) = create_signal(String::new());
 
-    let is_valid = move || !query.get().trim().is_empty() && topk.get() > 0;
+    let (last_search, set_last_search) = create_signal(None::<SearchQuery>);
+    let (next_cursor, set_next_cursor) = create_signal(None::<String>);
 
-    let do_search = move |_| {
-        if !is_valid() {
-            set_error.set("Please enter a query and valid number of results".into());
-            return;
-        }
+    let is_valid = move || !query.get().trim().is_empty() && topk.get() > 0;
 
-        let q = query.get();
-        let k = topk.get();
-        
+    // Fetches one page; follow-up pages are appended to the results.
+    let fetch_page = move |payload: SearchQuery| {
+        let append = payload.cursor.is_some();
         set_loading.set(true);
         set_error.set(String::new());
-        set_results.set(Vec::new());
 
         spawn_local(async move {
-            let payload = SearchQuery {
-                query: q.trim().to_string(),
-                top_k: Some(k)
-            };
-            
             let request = match Request::post(&format!("{}/search", BACKEND_URL))
                 .header("Content-Type", "application/json")
                 .body(serde_json::to_string(&payload).unwrap()) {
@@ -302,9 +294,15 @@ fn SearchPage() -> impl IntoView {
                 Ok(response) => {
                     match response.status() {
                         200 => {
+                            let cursor = response.headers().get("x-next-cursor");
                             match response.json::<Vec<SearchResult>>().await {
                                 Ok(data) => {
-                                    set_results.set(data);
+                                    if append {
+                                        set_results.update(|results| results.extend(data));
+                                    } else {
+                                        set_results.set(data);
+                                    }
+                                    set_next_cursor.set(cursor);
                                 }
                                 Err(e) => {
                                     set_error.set(format!("Failed to parse response: {}", e).into());
@@ -333,6 +331,31 @@ fn SearchPage() -> impl IntoView {
         });
     };
 
+    let do_search = move |_| {
+        if !is_valid() {
+            set_error.set("Please enter a query and valid number of results".into());
+            return;
+        }
+
+        let payload = SearchQuery {
+            query: query.get().trim().to_string(),
+            top_k: Some(topk.get()),
+            explain: explain.get(),
+            cursor: None,
+        };
+        set_results.set(Vec::new());
+        set_next_cursor.set(None);
+        set_last_search.set(Some(payload.clone()));
+        fetch_page(payload);
+    };
+
+    // Continues the last search, not whatever is in the form by now.
+    let load_more = move |_| {
+        if let (Some(search), Some(cursor)) = (last_search.get(), next_cursor.get()) {
+            fetch_page(SearchQuery { cursor: Some(cursor), ..search });
+        }
+    };
+
     view! {
         <h2>"Semantic Search"</h2>
         <div class="card">
@@ -370,6 +393,18 @@ fn SearchPage() -> impl IntoView {
                         <label for="top-k">"Number of Results"</label>
                     </div>
                 </div>
+                <div class="form-group">
+                    <label class="checkbox">
+                        <input
+                            id="explain"
+                            type="checkbox"
+                            prop:checked=explain
+                            on:change=move |e| set_explain.set(event_target_checked(&e))
+                            disabled=is_loading
+                        />
+                        "Explain scores"
+                    </label>
+                </div>
                 <div>
                     <button
                         type="submit"
@@ -410,7 +445,7 @@ fn SearchPage() -> impl IntoView {
                 } else {
                     view! {
                         <ul>
-                            <For each=move || results.get() key=|r| r.review.review_title.clone() let:res>
+                            <For each=move || results.get() key=|r| r.id let:res>
                                 <li>
                                     <div class="result-header">
                                         <span class="score">"Score: " {format!("{:.3}", res.score)}</span>
@@ -421,9 +456,15 @@ fn SearchPage() -> impl IntoView {
                                         <small>"Product ID: " {res.review.product_id.clone()}</small>
                                         <small>"Rating: " {res.review.review_rating} "/5"</small>
                                     </div>
+                                    {res.explanation.clone().map(|e| view! { <ScoreBreakdown explanation=e /> })}
                                 </li>
                             </For>
                         </ul>
+                        <Show when=move || next_cursor.get().is_some()>
+                            <button class="load-more" on:click=load_more disabled=is_loading>
+                                {move || if is_loading.get() { "Loading..." } else { "Load more" }}
+                            </button>
+                        </Show>
                     }.into_view()
                 }
             }}
@@ -431,6 +472,50 @@ fn SearchPage() -> impl IntoView {
     }
 }
 
+/// Per-signal table for a result of an `explain` search.
+#[component]
+fn ScoreBreakdown(explanation: Explanation) -> impl IntoView {
+    let optional = |value: Option<f32>| value.map_or("–".to_string(), |v| format!("{:.3}", v));
+    let matched = if explanation.matched_terms.is_empty() {
+        "none".to_string()
+    } else {
+        explanation.matched_terms.join(", ")
+    };
+    view! {
+        <details class="explanation">
+            <summary>"Why this result (" {explanation.mode.clone()} ")"</summary>
+            <table>
+                <thead>
+                    <tr>
+                        <th>"Signal"</th>
+                        <th>"Raw"</th>
+                        <th>"Normalized"</th>
+                        <th>"Weight"</th>
+                        <th>"Rank"</th>
+                        <th>"Contribution"</th>
+                    </tr>
+                </thead>
+                <tbody>
+                    {explanation.components.into_iter().map(|c| view! {
+                        <tr>
+                            <td>{c.signal}</td>
+                            <td>{optional(c.raw)}</td>
+                            <td>{format!("{:.3}", c.normalized)}</td>
+                            <td>{format!("{:.2}", c.weight)}</td>
+                            <td>{c.rank.map_or("–".to_string(), |r| r.to_string())}</td>
+                            <td>{format!("{:.3}", c.contribution)}</td>
+                        </tr>
+                    }).collect_view()}
+                </tbody>
+            </table>
+            <small>"Matched terms: " {matched}</small>
+            {explanation.rerank_score.map(|score| view! {
+                <small>" · Reranker score: " {format!("{:.3}", score)}</small>
+            })}
+        </details>
+    }
+}
+
 #[component]
 fn App() -> impl IntoView {
     view! {
@@ -463,12 +548,37 @@ mod models {
     pub struct SearchQuery {
         pub query: String,
         pub top_k: Option<usize>,
+        pub explain: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub cursor: Option<String>,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct SearchResult {
+        pub id: u64,
         pub score: f32,
         pub review: Review,
+        #[serde(default)]
+        pub explanation: Option<Explanation>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Explanation {
+        pub mode: String,
+        pub components: Vec<ScoreComponent>,
+        pub matched_terms: Vec<String>,
+        #[serde(default)]
+        pub rerank_score: Option<f32>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct ScoreComponent {
+        pub signal: String,
+        pub raw: Option<f32>,
+        pub normalized: f32,
+        pub weight: f32,
+        pub rank: Option<usize>,
+        pub contribution: f32,
     }
 }
 